@@ -0,0 +1,262 @@
+//! Aggregation of values over time windows.
+//!
+//! Values are collected per key (the DevEUI) in time windows that are
+//! aligned to multiples of the window length. Together with the values, the
+//! most recent item of a window (e.g. the record of the last uplink) is kept.
+//! A window is closed once its end has passed (see
+//! [`Aggregator::close_ended`]) or by the first value that falls into a later
+//! window, at which point the aggregate of the closed window is returned.
+
+use std::{
+    collections::HashMap,
+    mem,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::config::AggregationFunction;
+
+/// The values of a closed time window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aggregate {
+    /// Start of the window
+    pub start: SystemTime,
+    /// Number of values within the window
+    pub count: usize,
+    pub mean: f32,
+    pub min: f32,
+    pub max: f32,
+    /// The most recent value within the window
+    pub last: f32,
+}
+
+impl Aggregate {
+    /// Return the aggregate selected by the function.
+    pub fn value(&self, function: AggregationFunction) -> f32 {
+        match function {
+            AggregationFunction::Mean => self.mean,
+            AggregationFunction::Min => self.min,
+            AggregationFunction::Max => self.max,
+            AggregationFunction::Last => self.last,
+        }
+    }
+}
+
+/// The currently open window of a key.
+#[derive(Debug)]
+struct Window<T> {
+    /// UNIX timestamp at which the window starts
+    start: u64,
+    /// Length of the window in seconds
+    length: u64,
+    count: usize,
+    sum: f64,
+    min: f32,
+    max: f32,
+    last: f32,
+    /// The most recent item within the window
+    item: T,
+}
+
+impl<T> Window<T> {
+    fn new(start: u64, length: u64, value: f32, item: T) -> Self {
+        Self {
+            start,
+            length,
+            count: 1,
+            sum: f64::from(value),
+            min: value,
+            max: value,
+            last: value,
+            item,
+        }
+    }
+
+    fn add(&mut self, value: f32, item: T) {
+        self.count += 1;
+        self.sum += f64::from(value);
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.last = value;
+        self.item = item;
+    }
+
+    /// UNIX timestamp at which the window ends
+    fn end(&self) -> u64 {
+        self.start + self.length
+    }
+
+    fn close(self) -> (Aggregate, T) {
+        let aggregate = Aggregate {
+            start: UNIX_EPOCH + Duration::from_secs(self.start),
+            count: self.count,
+            mean: (self.sum / self.count as f64) as f32,
+            min: self.min,
+            max: self.max,
+            last: self.last,
+        };
+        (aggregate, self.item)
+    }
+}
+
+/// Aggregates values per key over time windows.
+pub struct Aggregator<T> {
+    /// The open window per key
+    windows: Mutex<HashMap<String, Window<T>>>,
+}
+
+impl<T> Default for Aggregator<T> {
+    fn default() -> Self {
+        Self {
+            windows: Mutex::default(),
+        }
+    }
+}
+
+impl<T> Aggregator<T> {
+    /// Add a value (and the item it belongs to) to the window of a key.
+    ///
+    /// If the value falls into a later window than the open one, the open
+    /// window is closed and its aggregate is returned, together with its most
+    /// recent item. Values that fall into an earlier window (e.g. after the
+    /// clock was adjusted) are added to the open window.
+    pub fn add(
+        &self,
+        key: &str,
+        window: Duration,
+        value: f32,
+        item: T,
+        time: SystemTime,
+    ) -> Option<(Aggregate, T)> {
+        let length = window.as_secs().max(1);
+        let timestamp = timestamp(time);
+        let start = timestamp - timestamp % length;
+        let mut windows = self.windows.lock().unwrap();
+        match windows.get_mut(key) {
+            Some(open) if open.length == length && start <= open.start => {
+                open.add(value, item);
+                None
+            }
+            Some(open) => {
+                let closed = mem::replace(open, Window::new(start, length, value, item));
+                Some(closed.close())
+            }
+            None => {
+                windows.insert(key.to_string(), Window::new(start, length, value, item));
+                None
+            }
+        }
+    }
+
+    /// Close all windows that ended at or before the specified time.
+    pub fn close_ended(&self, now: SystemTime) -> Vec<(Aggregate, T)> {
+        let now = timestamp(now);
+        let mut windows = self.windows.lock().unwrap();
+        let ended = windows
+            .iter()
+            .filter(|(_, window)| window.end() <= now)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        ended
+            .into_iter()
+            .filter_map(|key| windows.remove(&key))
+            .map(Window::close)
+            .collect()
+    }
+
+    /// Close all windows, regardless of whether they ended.
+    pub fn close_all(&self) -> Vec<(Aggregate, T)> {
+        let mut windows = self.windows.lock().unwrap();
+        windows.drain().map(|(_, window)| window.close()).collect()
+    }
+}
+
+fn timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minute(m: u64) -> SystemTime {
+        // 2023-11-14 22:00:00 UTC
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000 - 800 + m * 60)
+    }
+
+    #[test]
+    fn test_windows() {
+        // The items are the minutes of the values
+        let aggregator = Aggregator::default();
+        let window = Duration::from_secs(30 * 60);
+        for (m, value) in [(2, 14.0), (7, 15.0), (12, 13.0), (17, 16.5)] {
+            assert_eq!(aggregator.add("AABB", window, value, m, minute(m)), None);
+        }
+        assert_eq!(aggregator.add("CCDD", window, 20.0, 20, minute(20)), None);
+
+        // The first value of the next window closes the first one
+        let (aggregate, item) = aggregator
+            .add("AABB", window, 17.0, 32, minute(32))
+            .unwrap();
+        assert_eq!(aggregate.start, minute(0));
+        assert_eq!(aggregate.count, 4);
+        assert_eq!(aggregate.value(AggregationFunction::Mean), 14.625);
+        assert_eq!(aggregate.value(AggregationFunction::Min), 13.0);
+        assert_eq!(aggregate.value(AggregationFunction::Max), 16.5);
+        assert_eq!(aggregate.value(AggregationFunction::Last), 16.5);
+        assert_eq!(item, 17);
+
+        // Windows without values are skipped
+        let (aggregate, _) = aggregator
+            .add("AABB", window, 18.0, 95, minute(95))
+            .unwrap();
+        assert_eq!(aggregate.start, minute(30));
+        assert_eq!(aggregate.count, 1);
+        assert_eq!(aggregate.mean, 17.0);
+
+        // Late values are added to the open window
+        assert_eq!(aggregator.add("AABB", window, 19.0, 50, minute(50)), None);
+        let (aggregate, item) = aggregator
+            .add("AABB", window, 18.0, 120, minute(120))
+            .unwrap();
+        assert_eq!(aggregate.start, minute(90));
+        assert_eq!(aggregate.count, 2);
+        assert_eq!(aggregate.last, 19.0);
+        assert_eq!(item, 50);
+    }
+
+    #[test]
+    fn test_close_ended() {
+        let aggregator = Aggregator::default();
+        let window = Duration::from_secs(30 * 60);
+        assert_eq!(aggregator.add("AABB", window, 14.0, 2, minute(2)), None);
+        assert_eq!(aggregator.add("AABB", window, 15.0, 7, minute(7)), None);
+        assert_eq!(aggregator.add("CCDD", window, 20.0, 40, minute(40)), None);
+
+        // Windows are closed once their end has passed
+        assert!(aggregator.close_ended(minute(29)).is_empty());
+        let closed = aggregator.close_ended(minute(30));
+        assert_eq!(closed.len(), 1);
+        let (aggregate, item) = closed[0];
+        assert_eq!(aggregate.start, minute(0));
+        assert_eq!(aggregate.count, 2);
+        assert_eq!(aggregate.last, 15.0);
+        assert_eq!(item, 7);
+        assert!(aggregator.close_ended(minute(31)).is_empty());
+
+        // A new window is opened by the next value
+        assert_eq!(aggregator.add("AABB", window, 16.0, 45, minute(45)), None);
+        let mut closed = aggregator.close_all();
+        closed.sort_by_key(|(_, item)| *item);
+        assert_eq!(
+            closed
+                .iter()
+                .map(|(a, item)| (a.count, *item))
+                .collect::<Vec<_>>(),
+            [(1, 40), (1, 45)]
+        );
+        assert!(aggregator.close_all().is_empty());
+    }
+}
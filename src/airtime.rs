@@ -0,0 +1,280 @@
+//! Airtime accounting according to the TTN fair use policy.
+//!
+//! The fair use policy of The Things Network limits the uplink airtime of
+//! every device to 30 seconds per 24 hours. The airtime of the uplinks of the
+//! last 24 hours is summed up per device, and an alert is raised when the
+//! usage reaches the warning threshold and when it exceeds the daily limit.
+//!
+//! Additionally, sensors whose recent uplinks all used a high spreading
+//! factor are flagged: they use up their budget quickly, which usually
+//! indicates poor coverage or a device that does not adapt its data rate.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    alerts::{Alert, AlertKind, Severity},
+    config::Airtime,
+    state::StateFile,
+};
+
+/// Name of the state file.
+const STATE_FILE: &str = "airtime.json";
+
+/// The rolling window of the fair use policy.
+const WINDOW_SECS: u64 = 24 * 3600;
+
+/// Default daily airtime limit per device (TTN fair use policy).
+const DEFAULT_DAILY_LIMIT_S: f64 = 30.0;
+
+/// Default share of the daily limit at which a warning is raised.
+const DEFAULT_WARNING_PERCENT: f64 = 80.0;
+
+/// Default spreading factor from which on a sensor is flagged.
+const DEFAULT_HIGH_SF: u16 = 11;
+
+/// Minimum number of consecutive uplinks with a high spreading factor before
+/// a sensor is flagged.
+const MIN_HIGH_SF_UPLINKS: usize = 3;
+
+/// The airtime usage level of a device.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+enum Level {
+    #[default]
+    Ok,
+    Warning,
+    Exceeded,
+}
+
+/// A single uplink of a device.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Uplink {
+    /// UNIX timestamp
+    time: u64,
+    airtime_ms: u32,
+    spreading_factor: Option<u16>,
+}
+
+/// The airtime state of a single device.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct DeviceState {
+    /// The current level (only changes when a threshold is crossed)
+    level: Level,
+    /// Whether the device is flagged for using high spreading factors
+    high_sf: bool,
+    /// Uplinks of the last 24 hours (oldest first)
+    uplinks: VecDeque<Uplink>,
+}
+
+/// The airtime usage of a device after an uplink.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct AirtimeStats {
+    /// Uplink airtime within the last 24 hours in milliseconds
+    pub used_24h_ms: u64,
+    /// Share of the daily limit used within the last 24 hours (1.0 means the
+    /// limit is reached)
+    pub fair_use_ratio: f64,
+    /// Whether the recent uplinks all used a high spreading factor
+    pub high_sf: bool,
+}
+
+/// Keeps track of the uplink airtime of all devices.
+pub struct AirtimeTracker {
+    /// Daily airtime limit per device in milliseconds
+    daily_limit_ms: u64,
+    /// Share of the daily limit at which a warning is raised
+    warning_ratio: f64,
+    /// Spreading factor from which on a device is flagged
+    high_sf: u16,
+    /// The airtime state per DevEUI
+    devices: StateFile<HashMap<String, DeviceState>>,
+}
+
+impl AirtimeTracker {
+    /// Create a new airtime tracker. If a state directory is specified, the
+    /// previous state is loaded from there.
+    pub fn load(config: Option<&Airtime>, state_dir: Option<&Path>) -> Result<Self> {
+        let devices = StateFile::load(state_dir, STATE_FILE, "airtime state")?;
+        let daily_limit_s = config
+            .and_then(|c| c.daily_limit_s)
+            .unwrap_or(DEFAULT_DAILY_LIMIT_S);
+        let warning_percent = config
+            .and_then(|c| c.warning_percent)
+            .unwrap_or(DEFAULT_WARNING_PERCENT);
+        Ok(Self {
+            daily_limit_ms: (daily_limit_s * 1000.0) as u64,
+            warning_ratio: warning_percent / 100.0,
+            high_sf: config.and_then(|c| c.high_sf).unwrap_or(DEFAULT_HIGH_SF),
+            devices,
+        })
+    }
+
+    /// Save pending changes to the state file.
+    pub fn flush(&self) {
+        self.devices.flush();
+    }
+
+    /// Register the airtime of an uplink.
+    ///
+    /// Returns the airtime usage of the device and the alerts raised because
+    /// a threshold was crossed or the device was flagged.
+    pub fn update(
+        &self,
+        dev_eui: &str,
+        sensor_id: u32,
+        airtime_ms: u32,
+        spreading_factor: Option<u16>,
+        now: SystemTime,
+    ) -> (AirtimeStats, Vec<Alert>) {
+        let timestamp = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut devices = self.devices.lock();
+        let device = devices.entry(dev_eui.to_string()).or_default();
+
+        // Sum up the airtime of the last 24 hours
+        device.uplinks.push_back(Uplink {
+            time: timestamp,
+            airtime_ms,
+            spreading_factor,
+        });
+        while let Some(uplink) = device.uplinks.front() {
+            if timestamp.saturating_sub(uplink.time) < WINDOW_SECS {
+                break;
+            }
+            device.uplinks.pop_front();
+        }
+        let used_24h_ms = device
+            .uplinks
+            .iter()
+            .map(|u| u64::from(u.airtime_ms))
+            .sum::<u64>();
+        let fair_use_ratio = used_24h_ms as f64 / self.daily_limit_ms as f64;
+
+        let alert = |kind, severity, message| Alert {
+            kind,
+            severity,
+            sensor_id,
+            dev_eui: dev_eui.to_string(),
+            message,
+        };
+        let mut alerts = vec![];
+
+        // Determine level
+        let level = if fair_use_ratio > 1.0 {
+            Level::Exceeded
+        } else if fair_use_ratio >= self.warning_ratio {
+            Level::Warning
+        } else {
+            Level::Ok
+        };
+        if level > device.level {
+            let usage = format!(
+                "Used {:.1} s of uplink airtime within the last 24 hours ({:.0}% of the daily limit of {:.0} s)",
+                used_24h_ms as f64 / 1000.0,
+                fair_use_ratio * 100.0,
+                self.daily_limit_ms as f64 / 1000.0,
+            );
+            alerts.push(match level {
+                Level::Exceeded => alert(AlertKind::AirtimeExceeded, Severity::Critical, usage),
+                _ => alert(AlertKind::AirtimeWarning, Severity::Warning, usage),
+            });
+        }
+        device.level = level;
+
+        // Flag devices stuck on a high spreading factor
+        let recent = device.uplinks.iter().rev().take(MIN_HIGH_SF_UPLINKS);
+        let high_sf = recent.len() >= MIN_HIGH_SF_UPLINKS
+            && recent
+                .clone()
+                .all(|u| u.spreading_factor.is_some_and(|sf| sf >= self.high_sf));
+        if high_sf && !device.high_sf {
+            alerts.push(alert(
+                AlertKind::HighSpreadingFactor,
+                Severity::Warning,
+                format!(
+                    "The last {} uplinks used SF{} or higher ({} ms airtime per uplink)",
+                    MIN_HIGH_SF_UPLINKS, self.high_sf, airtime_ms
+                ),
+            ));
+        }
+        device.high_sf = high_sf;
+
+        let stats = AirtimeStats {
+            used_24h_ms,
+            fair_use_ratio,
+            high_sf,
+        };
+        self.devices.changed(&devices);
+        (stats, alerts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn hour(h: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000 + h * 3600)
+    }
+
+    fn kinds(alerts: Vec<Alert>) -> Vec<AlertKind> {
+        alerts.into_iter().map(|a| a.kind).collect()
+    }
+
+    #[test]
+    fn test_budget() {
+        let tracker = AirtimeTracker::load(None, None).unwrap();
+        let update = |h, airtime_ms| tracker.update("AABB", 1, airtime_ms, Some(9), hour(h));
+
+        let (stats, alerts) = update(0, 12000);
+        assert_eq!(stats.used_24h_ms, 12000);
+        assert_eq!(stats.fair_use_ratio, 0.4);
+        assert!(alerts.is_empty());
+
+        // Warning at 80%, only raised once
+        assert_eq!(kinds(update(1, 12000).1), vec![AlertKind::AirtimeWarning]);
+        assert!(update(2, 1000).1.is_empty());
+
+        // Limit exceeded
+        let (stats, alerts) = update(3, 6000);
+        assert_eq!(stats.used_24h_ms, 31000);
+        assert_eq!(kinds(alerts), vec![AlertKind::AirtimeExceeded]);
+
+        // The first uplinks drop out of the window after 24 hours
+        let (stats, alerts) = update(25, 1000);
+        assert_eq!(stats.used_24h_ms, 8000);
+        assert!(alerts.is_empty());
+        assert_eq!(kinds(update(26, 20000).1), vec![AlertKind::AirtimeWarning]);
+    }
+
+    #[test]
+    fn test_high_sf() {
+        let config = Airtime {
+            daily_limit_s: None,
+            warning_percent: None,
+            high_sf: Some(11),
+        };
+        let tracker = AirtimeTracker::load(Some(&config), None).unwrap();
+        let update = |h, sf| tracker.update("AABB", 1, 1000, Some(sf), hour(h));
+
+        assert!(update(0, 12).1.is_empty());
+        assert!(update(1, 11).1.is_empty());
+        let (stats, alerts) = update(2, 12);
+        assert!(stats.high_sf);
+        assert_eq!(kinds(alerts), vec![AlertKind::HighSpreadingFactor]);
+        assert!(update(3, 12).1.is_empty());
+
+        // A lower spreading factor clears the flag
+        assert!(!update(4, 9).0.high_sf);
+        assert!(update(5, 12).1.is_empty());
+        assert!(update(6, 12).1.is_empty());
+        assert_eq!(kinds(update(7, 12).1), vec![AlertKind::HighSpreadingFactor]);
+    }
+}
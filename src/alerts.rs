@@ -0,0 +1,576 @@
+//! Alerts raised by the relay (e.g. when a sensor goes offline).
+//!
+//! Every alert is logged. Additionally, alerts are delivered to all
+//! configured notifiers (webhooks, chat webhooks, email), unless they are
+//! suppressed:
+//!
+//! - Deduplication: An alert of the same kind for the same sensor is only
+//!   delivered once within the deduplication interval.
+//! - Rate limiting: At most `max_per_hour` alerts are delivered per hour.
+//!
+//! The notifiers are called on a separate thread, fed through a bounded
+//! queue, so that a slow notifier does not delay the handling of uplinks.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{
+        mpsc::{self, SyncSender, TrySendError},
+        Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use lettre::{
+    message::Mailbox,
+    transport::smtp::{authentication::Credentials, SmtpTransport},
+    Message, Transport,
+};
+use log::{debug, error, info, warn};
+use serde_json::json;
+
+use crate::config::{self, SmtpSecurity};
+
+/// Default deduplication interval.
+const DEFAULT_DEDUP_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Default maximum number of delivered alerts per hour.
+const DEFAULT_MAX_PER_HOUR: usize = 20;
+
+/// Maximum number of alerts queued for delivery.
+const QUEUE_SIZE: usize = 100;
+
+/// The severity of an alert.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Informational, e.g. a problem was resolved
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+/// The kind of an alert.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AlertKind {
+    /// No uplink was received within the expected interval
+    SensorOffline,
+    /// An uplink was received from a sensor that was offline
+    SensorRecovered,
+    /// The battery voltage is below the warning threshold
+    BatteryLow,
+    /// The battery voltage is below the critical threshold
+    BatteryCritical,
+    /// The battery voltage is above the warning threshold again
+    BatteryRecovered,
+    /// A measurement could not be submitted to the specified output
+    OutputFailed(String),
+    /// The uplink airtime of the last 24 hours is close to the daily limit
+    AirtimeWarning,
+    /// The uplink airtime of the last 24 hours exceeds the daily limit
+    AirtimeExceeded,
+    /// The recent uplinks all used a high spreading factor
+    HighSpreadingFactor,
+}
+
+impl AlertKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AlertKind::SensorOffline => "sensor_offline",
+            AlertKind::SensorRecovered => "sensor_recovered",
+            AlertKind::BatteryLow => "battery_low",
+            AlertKind::BatteryCritical => "battery_critical",
+            AlertKind::BatteryRecovered => "battery_recovered",
+            AlertKind::OutputFailed(_) => "output_failed",
+            AlertKind::AirtimeWarning => "airtime_warning",
+            AlertKind::AirtimeExceeded => "airtime_exceeded",
+            AlertKind::HighSpreadingFactor => "high_spreading_factor",
+        }
+    }
+}
+
+/// An alert concerning a sensor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alert {
+    pub kind: AlertKind,
+    pub severity: Severity,
+    /// The Gfrörli API sensor ID
+    pub sensor_id: u32,
+    /// The DevEUI of the sensor
+    pub dev_eui: String,
+    /// A human readable description
+    pub message: String,
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}] Sensor {} ({}): {}",
+            self.kind.name(),
+            self.sensor_id,
+            self.dev_eui,
+            self.message
+        )
+    }
+}
+
+/// A channel through which alerts are delivered.
+pub trait Notifier: Send + Sync {
+    /// A human readable name, used in log messages.
+    fn name(&self) -> &str;
+
+    /// Deliver an alert.
+    fn notify(&self, alert: &Alert) -> Result<()>;
+}
+
+/// Posts alerts as JSON object to a webhook URL.
+struct WebhookNotifier {
+    url: String,
+    headers: HashMap<String, String>,
+    http_client: ureq::Agent,
+}
+
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    fn notify(&self, alert: &Alert) -> Result<()> {
+        let mut request = self.http_client.post(&self.url);
+        for (name, value) in &self.headers {
+            request = request.set(name, value);
+        }
+        request
+            .send_json(json!({
+                "kind": alert.kind.name(),
+                "severity": alert.severity.name(),
+                "sensor_id": alert.sensor_id,
+                "dev_eui": alert.dev_eui,
+                "message": alert.message,
+                "timestamp": SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            }))
+            .context("Webhook request failed")?;
+        Ok(())
+    }
+}
+
+/// Posts alerts as text message to a Slack or Matrix (hookshot) compatible
+/// incoming webhook.
+struct ChatWebhookNotifier {
+    name: &'static str,
+    url: String,
+    http_client: ureq::Agent,
+}
+
+impl Notifier for ChatWebhookNotifier {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn notify(&self, alert: &Alert) -> Result<()> {
+        let icon = match alert.severity {
+            Severity::Info => "✅",
+            Severity::Warning => "⚠️",
+            Severity::Critical => "🚨",
+        };
+        self.http_client
+            .post(&self.url)
+            .send_json(json!({ "text": format!("{} {}", icon, alert) }))
+            .context("Chat webhook request failed")?;
+        Ok(())
+    }
+}
+
+/// Sends alerts via email.
+struct EmailNotifier {
+    transport: SmtpTransport,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+}
+
+impl Notifier for EmailNotifier {
+    fn name(&self) -> &str {
+        "email"
+    }
+
+    fn notify(&self, alert: &Alert) -> Result<()> {
+        let mut builder = Message::builder().from(self.from.clone()).subject(format!(
+            "[ttn-relay] {}: Sensor {}",
+            alert.kind.name(),
+            alert.sensor_id
+        ));
+        for to in &self.to {
+            builder = builder.to(to.clone());
+        }
+        let email = builder
+            .body(alert.to_string())
+            .context("Could not build email")?;
+        self.transport
+            .send(&email)
+            .context("Could not send email")?;
+        Ok(())
+    }
+}
+
+/// Create a notifier from its config.
+fn create_notifier(
+    config: &config::Notifier,
+    http_client: &ureq::Agent,
+) -> Result<Box<dyn Notifier>> {
+    Ok(match config {
+        config::Notifier::Webhook { url, headers } => Box::new(WebhookNotifier {
+            url: url.clone(),
+            headers: headers.clone(),
+            http_client: http_client.clone(),
+        }),
+        config::Notifier::Slack { url } => Box::new(ChatWebhookNotifier {
+            name: "slack",
+            url: url.clone(),
+            http_client: http_client.clone(),
+        }),
+        config::Notifier::Matrix { url } => Box::new(ChatWebhookNotifier {
+            name: "matrix",
+            url: url.clone(),
+            http_client: http_client.clone(),
+        }),
+        config::Notifier::Email {
+            host,
+            port,
+            security,
+            user,
+            pass,
+            from,
+            to,
+        } => {
+            let mut builder = match security.unwrap_or(SmtpSecurity::Starttls) {
+                SmtpSecurity::Tls => SmtpTransport::relay(host),
+                SmtpSecurity::Starttls => SmtpTransport::starttls_relay(host),
+                SmtpSecurity::None => Ok(SmtpTransport::builder_dangerous(host)),
+            }
+            .with_context(|| format!("Invalid SMTP host: {}", host))?
+            .timeout(Some(Duration::from_secs(10)));
+            if let Some(port) = port {
+                builder = builder.port(*port);
+            }
+            if let (Some(user), Some(pass)) = (user, pass) {
+                builder = builder.credentials(Credentials::new(user.clone(), pass.clone()));
+            }
+            Box::new(EmailNotifier {
+                transport: builder.build(),
+                from: from
+                    .parse()
+                    .with_context(|| format!("Invalid sender address: {}", from))?,
+                to: to
+                    .iter()
+                    .map(|to| {
+                        to.parse()
+                            .with_context(|| format!("Invalid recipient address: {}", to))
+                    })
+                    .collect::<Result<_>>()?,
+            })
+        }
+    })
+}
+
+/// State for deduplication and rate limiting.
+#[derive(Default)]
+struct History {
+    /// The last delivery per (kind, DevEUI)
+    last_sent: HashMap<(AlertKind, String), Instant>,
+    /// All deliveries within the last hour
+    sent: VecDeque<Instant>,
+}
+
+/// The thread delivering alerts to the notifiers.
+struct Delivery {
+    sender: SyncSender<Alert>,
+    thread: JoinHandle<()>,
+}
+
+impl Delivery {
+    fn spawn(notifiers: Vec<Box<dyn Notifier>>) -> Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel::<Alert>(QUEUE_SIZE);
+        let thread = thread::Builder::new()
+            .name("alerts".to_string())
+            .spawn(move || {
+                for alert in receiver.iter() {
+                    for notifier in &notifiers {
+                        match notifier.notify(&alert) {
+                            Ok(()) => debug!("Delivered alert via {}", notifier.name()),
+                            Err(e) => {
+                                warn!("Could not deliver alert via {}: {:#}", notifier.name(), e)
+                            }
+                        }
+                    }
+                }
+                debug!("Alert delivery stopped");
+            })
+            .context("Could not start alert delivery thread")?;
+        Ok(Self { sender, thread })
+    }
+}
+
+/// Dispatches alerts.
+pub struct Alerts {
+    dedup_interval: Duration,
+    max_per_hour: usize,
+    history: Mutex<History>,
+    /// The delivery thread (if notifiers are configured and the delivery was
+    /// not stopped yet)
+    delivery: Mutex<Option<Delivery>>,
+}
+
+impl Alerts {
+    pub fn new(config: Option<&config::Alerts>, http_client: &ureq::Agent) -> Result<Self> {
+        let notifiers = config
+            .iter()
+            .flat_map(|c| &c.notifiers)
+            .map(|n| create_notifier(n, http_client))
+            .collect::<Result<Vec<_>>>()?;
+        let delivery = if notifiers.is_empty() {
+            None
+        } else {
+            Some(Delivery::spawn(notifiers)?)
+        };
+        Ok(Self {
+            dedup_interval: config
+                .and_then(|c| c.dedup_interval_s)
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_DEDUP_INTERVAL),
+            max_per_hour: config
+                .and_then(|c| c.max_per_hour)
+                .unwrap_or(DEFAULT_MAX_PER_HOUR),
+            history: Mutex::new(History::default()),
+            delivery: Mutex::new(delivery),
+        })
+    }
+
+    /// Check whether an alert should be delivered, and if so, record the
+    /// delivery.
+    fn should_deliver(&self, alert: &Alert, now: Instant) -> bool {
+        let mut history = self.history.lock().unwrap();
+        let key = (alert.kind.clone(), alert.dev_eui.clone());
+        if let Some(last) = history.last_sent.get(&key) {
+            if now.saturating_duration_since(*last) < self.dedup_interval {
+                debug!("Suppressing duplicate alert: {}", alert);
+                return false;
+            }
+        }
+        while let Some(t) = history.sent.front() {
+            if now.saturating_duration_since(*t) < Duration::from_secs(3600) {
+                break;
+            }
+            history.sent.pop_front();
+        }
+        if history.sent.len() >= self.max_per_hour {
+            warn!("Alert rate limit exceeded, not delivering alert: {}", alert);
+            return false;
+        }
+        history.sent.push_back(now);
+        history.last_sent.insert(key, now);
+        true
+    }
+
+    /// Raise an alert. The alert is logged and queued for delivery.
+    pub fn send(&self, alert: &Alert) {
+        match alert.severity {
+            Severity::Info => info!("Alert: {}", alert),
+            Severity::Warning | Severity::Critical => warn!("Alert: {}", alert),
+        }
+        let delivery = self.delivery.lock().unwrap();
+        let Some(ref delivery) = *delivery else {
+            return;
+        };
+        if !self.should_deliver(alert, Instant::now()) {
+            return;
+        }
+        match delivery.sender.try_send(alert.clone()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!("Alert queue is full, not delivering alert: {}", alert)
+            }
+            Err(TrySendError::Disconnected(_)) => error!("Alert delivery thread is not running"),
+        }
+    }
+
+    /// Deliver the queued alerts and stop the delivery thread. Alerts raised
+    /// afterwards are only logged.
+    pub fn shutdown(&self) {
+        let delivery = self.delivery.lock().unwrap().take();
+        if let Some(Delivery { sender, thread }) = delivery {
+            drop(sender);
+            if thread.join().is_err() {
+                error!("Alert delivery thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::test_support::{mock_http_server, request_body, TIMEOUT};
+
+    fn alert(kind: AlertKind, dev_eui: &str) -> Alert {
+        Alert {
+            kind,
+            severity: Severity::Warning,
+            sensor_id: 1,
+            dev_eui: dev_eui.to_string(),
+            message: "Something happened".to_string(),
+        }
+    }
+
+    fn alerts(config: &str) -> Alerts {
+        let config: config::Alerts = toml::from_str(config).unwrap();
+        Alerts::new(Some(&config), &ureq::agent()).unwrap()
+    }
+
+    #[test]
+    fn test_dedup_and_rate_limit() {
+        let alerts = alerts("dedup_interval_s = 600\nmax_per_hour = 4");
+        let t0 = Instant::now();
+        let min = |m: u64| t0 + Duration::from_secs(m * 60);
+        let offline = |dev_eui| alert(AlertKind::SensorOffline, dev_eui);
+        assert!(alerts.should_deliver(&offline("a"), min(0)));
+        assert!(!alerts.should_deliver(&offline("a"), min(5)));
+        assert!(alerts.should_deliver(&alert(AlertKind::SensorRecovered, "a"), min(5)));
+        assert!(alerts.should_deliver(&offline("b"), min(5)));
+        assert!(alerts.should_deliver(&offline("a"), min(20)));
+        // Rate limit exceeded
+        assert!(!alerts.should_deliver(&offline("c"), min(30)));
+        assert!(alerts.should_deliver(&offline("c"), min(61)));
+        // Different outputs are different kinds
+        let failed = |output: &str| alert(AlertKind::OutputFailed(output.to_string()), "a");
+        assert!(alerts.should_deliver(&failed("api"), min(70)));
+        assert!(!alerts.should_deliver(&failed("api"), min(71)));
+        assert!(alerts.should_deliver(&failed("influxdb"), min(71)));
+    }
+
+    #[test]
+    fn test_webhook_notifier() {
+        let (url, rx) = mock_http_server(&[200]);
+        let alerts = alerts(&format!(
+            "[[notifiers]]\ntype = \"webhook\"\nurl = \"{}\"",
+            url
+        ));
+        alerts.send(&alert(AlertKind::BatteryLow, "a"));
+        let request = rx.recv_timeout(TIMEOUT).unwrap();
+        let body: serde_json::Value = serde_json::from_str(request_body(&request)).unwrap();
+        assert_eq!(body["kind"], "battery_low");
+        assert_eq!(body["severity"], "warning");
+        assert_eq!(body["sensor_id"], 1);
+        assert_eq!(body["dev_eui"], "a");
+        assert_eq!(body["message"], "Something happened");
+    }
+
+    #[test]
+    fn test_chat_webhook_notifier() {
+        let (url, rx) = mock_http_server(&[200]);
+        let alerts = alerts(&format!(
+            "[[notifiers]]\ntype = \"slack\"\nurl = \"{}\"",
+            url
+        ));
+        alerts.send(&alert(AlertKind::SensorOffline, "a"));
+        let request = rx.recv_timeout(TIMEOUT).unwrap();
+        let body: serde_json::Value = serde_json::from_str(request_body(&request)).unwrap();
+        assert_eq!(
+            body["text"],
+            "⚠️ [sensor_offline] Sensor 1 (a): Something happened"
+        );
+    }
+
+    #[test]
+    fn test_email_notifier() {
+        // A minimal SMTP server that records the message data
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream.write_all(b"220 localhost ESMTP\r\n").unwrap();
+            let mut data = String::new();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+                        stream.write_all(b"250 OK\r\n").unwrap();
+                    } else {
+                        data.push_str(&line);
+                    }
+                    continue;
+                }
+                let response: &[u8] = match line.get(..4).map(str::to_uppercase).as_deref() {
+                    Some("EHLO") => b"250 localhost\r\n",
+                    Some("DATA") => {
+                        in_data = true;
+                        b"354 Go ahead\r\n"
+                    }
+                    Some("QUIT") => {
+                        stream.write_all(b"221 Bye\r\n").unwrap();
+                        break;
+                    }
+                    _ => b"250 OK\r\n",
+                };
+                stream.write_all(response).unwrap();
+            }
+            tx.send(data).unwrap();
+        });
+
+        let alerts = alerts(&format!(
+            "[[notifiers]]\n\
+             type = \"email\"\n\
+             host = \"127.0.0.1\"\n\
+             port = {}\n\
+             security = \"none\"\n\
+             from = \"relay@example.com\"\n\
+             to = [\"ops@example.com\"]",
+            port
+        ));
+        alerts.send(&alert(AlertKind::BatteryLow, "a"));
+        let data = rx.recv_timeout(TIMEOUT).unwrap();
+        assert!(data.contains("Subject: [ttn-relay] battery_low: Sensor 1"));
+        assert!(data.contains("To: ops@example.com"));
+        assert!(data.contains("[battery_low] Sensor 1 (a): Something happened"));
+    }
+
+    #[test]
+    fn test_slow_notifier() {
+        // A server that accepts connections but never responds
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let alerts = alerts(&format!(
+            "[[notifiers]]\ntype = \"webhook\"\nurl = \"http://{}/hook\"",
+            listener.local_addr().unwrap()
+        ));
+        let start = Instant::now();
+        alerts.send(&alert(AlertKind::BatteryLow, "a"));
+        alerts.send(&alert(AlertKind::BatteryLow, "b"));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
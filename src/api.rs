@@ -0,0 +1,549 @@
+//! Submission of water temperatures to the Gfrörli API.
+//!
+//! Two payload versions are supported: version 1 (the default) only
+//! contains the sensor ID and the water temperature, which is what older API
+//! servers accept. Version 2 additionally contains the sensor location, all
+//! readings of the measurement and the radio metadata of the uplink.
+
+use std::{
+    collections::BTreeMap,
+    mem,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+
+use crate::{
+    aggregation::{Aggregate, Aggregator},
+    config::{self, AggregationFunction},
+    output::{Failures, Output, Record},
+};
+
+/// The latest supported payload version.
+const MAX_PAYLOAD_VERSION: u8 = 2;
+
+/// Interval at which ended aggregation windows are submitted.
+const TICK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Payload version 1.
+#[derive(serde::Serialize)]
+struct ApiPayload {
+    sensor_id: u32,
+    temperature: f32,
+}
+
+/// Payload version 2.
+///
+/// With aggregation, the per-uplink fields (timestamp, location, readings
+/// and radio metadata) are those of the last measurement within the window.
+#[derive(serde::Serialize)]
+struct ApiPayloadV2<'a> {
+    payload_version: u8,
+    sensor_id: u32,
+    dev_eui: &'a str,
+    /// UNIX timestamp at which the uplink was processed
+    timestamp: u64,
+    /// The water temperature (aggregated, if configured for the sensor)
+    temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aggregation: Option<AggregationInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latitude: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    longitude: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    altitude: Option<f64>,
+    /// All readings of the measurement by field name, e.g. `water_temp`,
+    /// `enclosure_humi` or `voltage`
+    readings: BTreeMap<String, f32>,
+    /// The probe depths in m by field name (of the readings with a known
+    /// depth)
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    depths_m: BTreeMap<String, f32>,
+    radio: Radio<'a>,
+}
+
+/// The payload in one of the supported versions.
+///
+/// The payload is serialized directly (instead of via [`serde_json::Value`]),
+/// so that `f32` values are not widened, e.g. 13.14 is not sent as
+/// 13.140000343322754.
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum Payload<'a> {
+    V1(ApiPayload),
+    V2(Box<ApiPayloadV2<'a>>),
+}
+
+/// The time window over which the temperature was aggregated.
+#[derive(serde::Serialize)]
+struct AggregationInfo {
+    function: AggregationFunction,
+    /// UNIX timestamp at which the window started
+    window_start: u64,
+    window_min: u64,
+    /// Number of measurements within the window
+    count: usize,
+}
+
+/// The radio metadata of the uplink.
+#[derive(serde::Serialize)]
+struct Radio<'a> {
+    data_rate: Option<String>,
+    modulation: Option<&'static str>,
+    spreading_factor: Option<u16>,
+    bandwidth: Option<u64>,
+    coding_rate: Option<&'a str>,
+    bit_rate: Option<u32>,
+    /// Frequency in Hz
+    frequency: Option<u64>,
+    airtime_ms: u32,
+    fcnt: Option<u32>,
+    /// Number of receiving gateways
+    gateways: usize,
+    /// RSSI of the best receiving gateway
+    rssi: Option<f64>,
+    /// SNR of the best receiving gateway
+    snr: Option<f64>,
+}
+
+/// Sends the water temperature of every measurement (or one aggregated
+/// value per time window, if configured for the sensor) to the Gfrörli API.
+pub struct ApiOutput {
+    config: config::Api,
+    http_client: ureq::Agent,
+    /// Open aggregation windows, with the record of the last measurement
+    aggregator: Aggregator<Record>,
+    /// Closed windows whose submission failed when a later measurement
+    /// closed them, to be retried on the next tick
+    retry: Mutex<Vec<(Aggregate, Record)>>,
+}
+
+impl ApiOutput {
+    pub fn new(config: &config::Api, http_client: &ureq::Agent) -> Result<Self> {
+        match config.payload_version {
+            None | Some(1..=MAX_PAYLOAD_VERSION) => {}
+            Some(version) => bail!("Unsupported API payload version {}", version),
+        }
+        Ok(Self {
+            config: config.clone(),
+            http_client: http_client.clone(),
+            aggregator: Aggregator::default(),
+            retry: Mutex::default(),
+        })
+    }
+
+    /// Build the payload in the configured version.
+    fn payload<'a>(
+        &self,
+        record: &'a Record,
+        temperature: f32,
+        aggregate: Option<&Aggregate>,
+    ) -> Payload<'a> {
+        match self.config.payload_version.unwrap_or(1) {
+            1 => Payload::V1(ApiPayload {
+                sensor_id: record.sensor.sensor_id,
+                temperature,
+            }),
+            version => {
+                let meta = &record.meta;
+                let data_rate = meta.data_rate.as_ref();
+                let aggregation = record.sensor.api_aggregation.as_ref();
+                Payload::V2(Box::new(ApiPayloadV2 {
+                    payload_version: version,
+                    sensor_id: record.sensor.sensor_id,
+                    dev_eui: &record.dev_eui,
+                    timestamp: record
+                        .timestamp
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                    temperature,
+                    aggregation: aggregation.zip(aggregate).map(|(config, aggregate)| {
+                        AggregationInfo {
+                            function: config.function,
+                            window_start: aggregate
+                                .start
+                                .duration_since(UNIX_EPOCH)
+                                .unwrap_or_default()
+                                .as_secs(),
+                            window_min: config.window_min,
+                            count: aggregate.count,
+                        }
+                    }),
+                    latitude: record.location.map(|l| l.latitude),
+                    longitude: record.location.map(|l| l.longitude),
+                    altitude: record.location.and_then(|l| l.altitude),
+                    readings: record
+                        .measurement
+                        .readings
+                        .iter()
+                        .map(|reading| (reading.field_name(), reading.value))
+                        .collect(),
+                    depths_m: record
+                        .measurement
+                        .readings
+                        .iter()
+                        .filter_map(|reading| Some((reading.field_name(), reading.depth_m?)))
+                        .collect(),
+                    radio: Radio {
+                        data_rate: data_rate.map(ToString::to_string),
+                        modulation: data_rate.map(|dr| dr.modulation()),
+                        spreading_factor: meta.spreading_factor(),
+                        bandwidth: meta.bandwidth(),
+                        coding_rate: data_rate.and_then(|dr| dr.coding_rate()),
+                        bit_rate: data_rate.and_then(|dr| dr.bit_rate()),
+                        frequency: meta.frequency,
+                        airtime_ms: meta.airtime_ms,
+                        fcnt: meta.frames.as_ref().map(|frames| frames.fcnt),
+                        gateways: meta.receiving_gateways.len(),
+                        rssi: meta.max_rssi_gateway().map(|gw| gw.rssi),
+                        snr: meta.max_snr_gateway().map(|(_, snr)| snr),
+                    },
+                }))
+            }
+        }
+    }
+
+    /// Submit the aggregate of a closed window. The metadata is taken from
+    /// the record of the last measurement within the window.
+    fn submit_aggregate(&self, record: &Record, aggregate: &Aggregate) -> Result<()> {
+        let function = record
+            .sensor
+            .api_aggregation
+            .as_ref()
+            .map(|aggregation| aggregation.function)
+            .unwrap_or_default();
+        debug!(
+            "Aggregated {} temperatures of sensor {}",
+            aggregate.count, record.sensor.sensor_id
+        );
+        self.submit(record, aggregate.value(function), Some(aggregate))
+    }
+
+    /// Submit the aggregates of closed windows, after those to be retried.
+    fn submit_aggregates(&self, closed: Vec<(Aggregate, Record)>) -> Failures {
+        let retry = mem::take(&mut *self.retry.lock().unwrap());
+        retry
+            .into_iter()
+            .chain(closed)
+            .filter_map(|(aggregate, record)| {
+                let result = self.submit_aggregate(&record, &aggregate);
+                result.err().map(|e| (record, e))
+            })
+            .collect()
+    }
+
+    /// Send a temperature to the API.
+    fn submit(
+        &self,
+        record: &Record,
+        temperature: f32,
+        aggregate: Option<&Aggregate>,
+    ) -> Result<()> {
+        let payload = self.payload(record, temperature, aggregate);
+        let url = format!("{}/measurements", self.config.base_url);
+        let authorization = format!("Bearer {}", self.config.api_token);
+        info!("Sending temperature {:.2}°C to API...", temperature);
+        let response = self
+            .http_client
+            .post(&url)
+            .set("authorization", &authorization)
+            .send_json(&payload)
+            .context("API request failed")?;
+        if response.status() == 201 {
+            debug!("API request succeeded");
+            Ok(())
+        } else {
+            bail!(
+                "API request failed: HTTP {} ({})",
+                response.status(),
+                response.status_text()
+            );
+        }
+    }
+}
+
+impl Output for ApiOutput {
+    fn name(&self) -> &str {
+        "api"
+    }
+
+    fn send(&self, record: &Record) -> Result<()> {
+        let temperature = match record.measurement.water_temperature() {
+            Some(temperature) => temperature,
+            None => {
+                info!("Measurement does not contain a water temperature, not sending to API");
+                return Ok(());
+            }
+        };
+        let min_temperature = self.config.min_temperature.unwrap_or(0.0);
+        if temperature <= min_temperature {
+            warn!(
+                "Temperature is at or below {} °C, not sending to API",
+                min_temperature
+            );
+            return Ok(());
+        }
+        let Some(ref aggregation) = record.sensor.api_aggregation else {
+            return self.submit(record, temperature, None);
+        };
+        let window = Duration::from_secs(aggregation.window_min * 60);
+        let closed = self.aggregator.add(
+            &record.dev_eui,
+            window,
+            temperature,
+            record.clone(),
+            record.timestamp,
+        );
+        match closed {
+            // A measurement of a later window arrived before the tick. The
+            // error must not be reported for this measurement, so the window
+            // is retried (and reported as failed) on the next tick.
+            Some((aggregate, last)) => {
+                if let Err(e) = self.submit_aggregate(&last, &aggregate) {
+                    warn!(
+                        "Could not submit aggregation window of sensor {}, retrying: {:#}",
+                        last.sensor.sensor_id, e
+                    );
+                    self.retry.lock().unwrap().push((aggregate, last));
+                }
+                Ok(())
+            }
+            None => {
+                debug!("Temperature added to aggregation window, not sending to API");
+                Ok(())
+            }
+        }
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(TICK_INTERVAL)
+    }
+
+    fn tick(&self, now: SystemTime) -> Failures {
+        self.submit_aggregates(self.aggregator.close_ended(now))
+    }
+
+    /// Submit the open aggregation windows, so that the measurements are not
+    /// lost when the relay exits.
+    fn flush(&self) -> Failures {
+        self.submit_aggregates(self.aggregator.close_all())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{
+        payload::{Measurement, Position, Quantity, Reading},
+        test_support::{mock_http_server, request_body, TIMEOUT},
+        uplink::Location,
+    };
+
+    /// Serialize a payload the way it is sent.
+    fn json(payload: &Payload) -> Value {
+        serde_json::from_str(&serde_json::to_string(payload).unwrap()).unwrap()
+    }
+
+    fn output(payload_version: Option<u8>) -> Result<ApiOutput> {
+        output_with_url("http://localhost", payload_version)
+    }
+
+    fn output_with_url(base_url: &str, payload_version: Option<u8>) -> Result<ApiOutput> {
+        let config = config::Api {
+            base_url: base_url.to_string(),
+            api_token: "token".to_string(),
+            payload_version,
+            min_temperature: None,
+        };
+        ApiOutput::new(&config, &ureq::Agent::new())
+    }
+
+    /// Minutes after 2023-11-14 22:00:00 UTC.
+    fn minute_time(minute: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000 - 800 + minute * 60)
+    }
+
+    fn record() -> Record {
+        Record::example(
+            1,
+            Measurement {
+                readings: vec![
+                    Reading::new(Quantity::Temperature, Position::Water, 18.5),
+                    Reading::new(Quantity::Humidity, Position::Enclosure, 40.0),
+                    Reading::new(Quantity::BatteryVoltage, Position::Device, 3.5),
+                ],
+            },
+        )
+    }
+
+    #[test]
+    fn test_payload_versions() {
+        assert!(output(Some(0)).is_err());
+        assert!(output(Some(MAX_PAYLOAD_VERSION + 1)).is_err());
+
+        let mut record = record();
+        record.location = Some(Location {
+            latitude: 47.2,
+            longitude: 8.8,
+            altitude: None,
+        });
+        record.measurement.readings[0].depth_m = Some(0.5);
+
+        // Version 1 is unchanged for older API servers, even if the location
+        // and probe depth are known
+        let payload = json(&output(None).unwrap().payload(&record, 18.5, None));
+        assert_eq!(payload, json!({"sensor_id": 1, "temperature": 18.5}));
+
+        let payload = json(&output(Some(2)).unwrap().payload(&record, 18.5, None));
+        assert_eq!(payload["payload_version"], 2);
+        assert_eq!(payload["dev_eui"], "AABB");
+        assert_eq!(payload["temperature"], 18.5);
+        assert_eq!(payload.get("aggregation"), None);
+        assert_eq!(payload["latitude"], 47.2);
+        assert_eq!(payload["longitude"], 8.8);
+        assert_eq!(payload.get("altitude"), None);
+        assert_eq!(payload["depths_m"], json!({"water_temp": 0.5}));
+        assert_eq!(
+            payload["readings"],
+            json!({
+                "water_temp": 18.5,
+                "enclosure_humi": 40.0,
+                "voltage": 3.5,
+            })
+        );
+        assert_eq!(payload["radio"]["data_rate"], "SF7BW125");
+        assert_eq!(payload["radio"]["spreading_factor"], 7);
+        assert_eq!(payload["radio"]["coding_rate"], "4/5");
+        assert_eq!(payload["radio"]["frequency"], 868100000);
+        assert_eq!(payload["radio"]["gateways"], 2);
+        assert_eq!(payload["radio"]["rssi"], -95.0);
+    }
+
+    #[test]
+    fn test_payload_precision() {
+        let mut record = record();
+        record.measurement.readings[0].value = 13.14;
+        for version in [1, 2] {
+            let payload = output(Some(version)).unwrap().payload(&record, 13.14, None);
+            let body = serde_json::to_string(&payload).unwrap();
+            assert!(body.contains(r#""temperature":13.14"#), "{}", body);
+            if version == 2 {
+                assert!(body.contains(r#""water_temp":13.14"#), "{}", body);
+            }
+        }
+    }
+
+    #[test]
+    fn test_min_temperature() {
+        // The mock server accepts no requests, so sending would fail
+        let (url, requests) = mock_http_server(&[]);
+        let output = output_with_url(&url, None).unwrap();
+        let mut record = record();
+        for temperature in [0.0, -0.5] {
+            record.measurement.readings[0].value = temperature;
+            output.send(&record).unwrap();
+        }
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_aggregation() {
+        let (url, requests) = mock_http_server(&[201, 201]);
+        let output = output_with_url(&url, Some(2)).unwrap();
+        let aggregated = |temperature: f32, fcnt: u32, minute: u64| {
+            let mut record = Record::example(
+                1,
+                Measurement {
+                    readings: vec![Reading::new(
+                        Quantity::Temperature,
+                        Position::Water,
+                        temperature,
+                    )],
+                },
+            );
+            record.sensor = toml::from_str(
+                "sensor_type = \"gfroerli\"\nsensor_id = 1\n[api_aggregation]\nwindow_min = 30",
+            )
+            .unwrap();
+            record.meta.frames.as_mut().unwrap().fcnt = fcnt;
+            record.timestamp = minute_time(minute);
+            record
+        };
+        let body = || {
+            let request = requests.recv_timeout(TIMEOUT).unwrap();
+            serde_json::from_str::<Value>(request_body(&request)).unwrap()
+        };
+
+        output.send(&aggregated(13.0, 7, 2)).unwrap();
+        output.send(&aggregated(14.0, 8, 7)).unwrap();
+        assert!(output.tick(minute_time(29)).is_empty());
+
+        // The window is submitted once it has ended, with the metadata of its
+        // last measurement
+        assert!(output.tick(minute_time(30)).is_empty());
+        let payload = body();
+        assert_eq!(payload["temperature"], 13.5);
+        assert_eq!(payload["aggregation"]["count"], 2);
+        assert_eq!(payload["radio"]["fcnt"], 8);
+        assert_eq!(payload["readings"]["water_temp"], 14.0);
+
+        // The open window is submitted when the relay exits
+        output.send(&aggregated(15.0, 9, 40)).unwrap();
+        assert!(output.flush().is_empty());
+        let payload = body();
+        assert_eq!(payload["temperature"], 15.0);
+        assert_eq!(payload["radio"]["fcnt"], 9);
+    }
+
+    #[test]
+    fn test_aggregation_retry() {
+        let (url, requests) = mock_http_server(&[500, 500, 201]);
+        let output = output_with_url(&url, Some(2)).unwrap();
+        let aggregated = |fcnt: u32, minute: u64| {
+            let mut record = Record::example(
+                1,
+                Measurement {
+                    readings: vec![Reading::new(Quantity::Temperature, Position::Water, 13.0)],
+                },
+            );
+            record.sensor = toml::from_str(
+                "sensor_type = \"gfroerli\"\nsensor_id = 1\n[api_aggregation]\nwindow_min = 30",
+            )
+            .unwrap();
+            record.meta.frames.as_mut().unwrap().fcnt = fcnt;
+            record.timestamp = minute_time(minute);
+            record
+        };
+        let fcnt = || {
+            let request = requests.recv_timeout(TIMEOUT).unwrap();
+            serde_json::from_str::<Value>(request_body(&request)).unwrap()["radio"]["fcnt"].clone()
+        };
+
+        // The failed submission of the closed window is not charged to the
+        // measurement that closed it
+        output.send(&aggregated(7, 2)).unwrap();
+        output.send(&aggregated(8, 40)).unwrap();
+        assert_eq!(fcnt(), 7);
+
+        // The window is retried on the next tick and, if that fails as well,
+        // reported with its own record
+        let failures = output.tick(minute_time(50));
+        assert_eq!(fcnt(), 7);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(
+            failures[0].0.meta.frames.as_ref().map(|frames| frames.fcnt),
+            Some(7)
+        );
+        assert_eq!(failures[0].1.to_string(), "API request failed");
+
+        // The window is not retried again
+        assert!(output.flush().is_empty());
+        assert_eq!(fcnt(), 8);
+    }
+}
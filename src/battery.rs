@@ -0,0 +1,299 @@
+//! Battery monitoring.
+//!
+//! The battery voltage of every sensor (of a sensor type with configured
+//! thresholds) is compared against a warning and a critical threshold. An
+//! alert is raised whenever a sensor crosses one of the thresholds. If the
+//! voltage rises clearly above the warning threshold again (e.g. because the
+//! battery was replaced), a recovery alert is raised and the history is
+//! cleared.
+//!
+//! Additionally, the discharge trend is estimated using a linear regression
+//! over the readings of the last days, which is used to predict the number of
+//! days until the critical threshold is reached.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    alerts::{Alert, AlertKind, Severity},
+    config::{Battery, BatteryThresholds},
+    state::StateFile,
+};
+
+/// Name of the state file.
+const STATE_FILE: &str = "battery.json";
+
+/// Default time window for the trend estimation.
+const DEFAULT_TREND_WINDOW_DAYS: u64 = 14;
+
+/// The readings used for the trend estimation must span at least this many
+/// seconds, otherwise the estimation is too noisy.
+const MIN_TREND_SPAN_SECS: u64 = 24 * 3600;
+
+/// The voltage must rise this much above the warning threshold before the
+/// battery is considered recovered.
+const RECOVERY_HYSTERESIS_V: f32 = 0.1;
+
+/// The battery level of a sensor.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Level {
+    #[default]
+    Ok,
+    Warning,
+    Critical,
+}
+
+/// The battery state of a single sensor.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SensorState {
+    /// The current level (only changes when a threshold is crossed)
+    level: Level,
+    /// Recent readings as (UNIX timestamp, voltage)
+    history: VecDeque<(u64, f32)>,
+}
+
+/// The battery status of a sensor after a new reading.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatteryStatus {
+    pub level: Level,
+    /// The estimated discharge rate in volts per day (negative when
+    /// discharging)
+    pub trend_v_per_day: Option<f64>,
+    /// The estimated number of days until the critical threshold is reached
+    pub days_remaining: Option<f64>,
+}
+
+/// Monitors the battery voltage of all sensors.
+pub struct BatteryMonitor {
+    /// Thresholds per sensor type
+    thresholds: HashMap<String, BatteryThresholds>,
+    /// Time window for the trend estimation
+    trend_window: Duration,
+    /// The battery state per DevEUI
+    sensors: StateFile<HashMap<String, SensorState>>,
+}
+
+/// Estimate the slope (in volts per day) of the readings using a linear
+/// least squares fit.
+fn estimate_trend(history: &VecDeque<(u64, f32)>) -> Option<f64> {
+    let (first, last) = (history.front()?.0, history.back()?.0);
+    if history.len() < 3 || last.saturating_sub(first) < MIN_TREND_SPAN_SECS {
+        return None;
+    }
+    let n = history.len() as f64;
+    let points = history
+        .iter()
+        .map(|(t, v)| (t.saturating_sub(first) as f64 / 86400.0, f64::from(*v)));
+    let (sum_x, sum_y) = points
+        .clone()
+        .fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x, sy + y));
+    let (mean_x, mean_y) = (sum_x / n, sum_y / n);
+    let (cov, var) = points.fold((0.0, 0.0), |(cov, var), (x, y)| {
+        (
+            cov + (x - mean_x) * (y - mean_y),
+            var + (x - mean_x) * (x - mean_x),
+        )
+    });
+    Some(cov / var)
+}
+
+impl BatteryMonitor {
+    /// Create a new battery monitor. If a state directory is specified, the
+    /// previous state is loaded from there.
+    pub fn load(config: Option<&Battery>, state_dir: Option<&Path>) -> Result<Self> {
+        let sensors = StateFile::load(state_dir, STATE_FILE, "battery state")?;
+        Ok(Self {
+            thresholds: config.map(|c| c.thresholds.clone()).unwrap_or_default(),
+            trend_window: Duration::from_secs(
+                config
+                    .and_then(|c| c.trend_window_days)
+                    .unwrap_or(DEFAULT_TREND_WINDOW_DAYS)
+                    * 86400,
+            ),
+            sensors,
+        })
+    }
+
+    /// Save pending changes to the state file.
+    pub fn flush(&self) {
+        self.sensors.flush();
+    }
+
+    /// Register a battery reading of a sensor.
+    ///
+    /// Returns `None` if no thresholds are configured for this sensor type.
+    /// Otherwise, the battery status and an alert (if a threshold was
+    /// crossed) are returned.
+    pub fn update(
+        &self,
+        dev_eui: &str,
+        sensor_id: u32,
+        sensor_type: &str,
+        voltage: f32,
+        now: SystemTime,
+    ) -> Option<(BatteryStatus, Option<Alert>)> {
+        let thresholds = self.thresholds.get(sensor_type)?;
+        let mut sensors = self.sensors.lock();
+        let sensor = sensors.entry(dev_eui.to_string()).or_default();
+
+        // Determine level
+        let level = if voltage < thresholds.critical_v {
+            Level::Critical
+        } else if voltage < thresholds.warning_v {
+            Level::Warning
+        } else {
+            Level::Ok
+        };
+        let alert = |kind, severity, message| Alert {
+            kind,
+            severity,
+            sensor_id,
+            dev_eui: dev_eui.to_string(),
+            message,
+        };
+        let mut alert = if level > sensor.level {
+            sensor.level = level;
+            Some(match level {
+                Level::Critical => alert(
+                    AlertKind::BatteryCritical,
+                    Severity::Critical,
+                    format!(
+                        "Battery voltage {:.3} V is below the critical threshold of {:.3} V",
+                        voltage, thresholds.critical_v
+                    ),
+                ),
+                _ => alert(
+                    AlertKind::BatteryLow,
+                    Severity::Warning,
+                    format!(
+                        "Battery voltage {:.3} V is below the warning threshold of {:.3} V",
+                        voltage, thresholds.warning_v
+                    ),
+                ),
+            })
+        } else if sensor.level != Level::Ok
+            && voltage >= thresholds.warning_v + RECOVERY_HYSTERESIS_V
+        {
+            sensor.level = Level::Ok;
+            sensor.history.clear();
+            Some(alert(
+                AlertKind::BatteryRecovered,
+                Severity::Info,
+                format!("Battery voltage is back at {:.3} V", voltage),
+            ))
+        } else {
+            None
+        };
+
+        // Estimate trend
+        let timestamp = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        sensor.history.push_back((timestamp, voltage));
+        while let Some((t, _)) = sensor.history.front() {
+            if timestamp.saturating_sub(*t) <= self.trend_window.as_secs() {
+                break;
+            }
+            sensor.history.pop_front();
+        }
+        let trend_v_per_day = estimate_trend(&sensor.history);
+        let days_remaining = trend_v_per_day.map(|trend| {
+            let margin = f64::from(voltage - thresholds.critical_v).max(0.0);
+            if trend < 0.0 {
+                margin / -trend
+            } else {
+                f64::INFINITY
+            }
+        });
+        if let (Some(ref mut alert), Some(days)) = (&mut alert, days_remaining) {
+            if alert.kind == AlertKind::BatteryLow && days.is_finite() {
+                alert.message += &format!(" (about {:.0} days remaining)", days);
+            }
+        }
+
+        let status = BatteryStatus {
+            level: sensor.level,
+            trend_v_per_day,
+            days_remaining: days_remaining.filter(|d| d.is_finite()),
+        };
+        self.sensors.changed(&sensors);
+        Some((status, alert))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor() -> BatteryMonitor {
+        let config = Battery {
+            trend_window_days: Some(7),
+            thresholds: HashMap::from([(
+                "gfroerli".to_string(),
+                BatteryThresholds {
+                    warning_v: 3.4,
+                    critical_v: 3.2,
+                },
+            )]),
+        };
+        BatteryMonitor::load(Some(&config), None).unwrap()
+    }
+
+    fn day(d: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000 + d * 86400)
+    }
+
+    fn kind(result: Option<(BatteryStatus, Option<Alert>)>) -> Option<AlertKind> {
+        result.unwrap().1.map(|a| a.kind)
+    }
+
+    #[test]
+    fn test_thresholds() {
+        let monitor = monitor();
+        assert!(monitor.update("a", 1, "dragino", 2.0, day(0)).is_none());
+
+        assert_eq!(kind(monitor.update("a", 1, "gfroerli", 3.6, day(0))), None);
+        assert_eq!(
+            kind(monitor.update("a", 1, "gfroerli", 3.35, day(1))),
+            Some(AlertKind::BatteryLow)
+        );
+        // Only alert once, even if the voltage fluctuates
+        assert_eq!(kind(monitor.update("a", 1, "gfroerli", 3.42, day(2))), None);
+        assert_eq!(kind(monitor.update("a", 1, "gfroerli", 3.38, day(3))), None);
+        assert_eq!(
+            kind(monitor.update("a", 1, "gfroerli", 3.1, day(4))),
+            Some(AlertKind::BatteryCritical)
+        );
+        assert_eq!(
+            kind(monitor.update("a", 1, "gfroerli", 3.7, day(5))),
+            Some(AlertKind::BatteryRecovered)
+        );
+        let (status, _) = monitor.update("a", 1, "gfroerli", 3.7, day(6)).unwrap();
+        assert_eq!(status.level, Level::Ok);
+    }
+
+    #[test]
+    fn test_trend() {
+        let monitor = monitor();
+        // Not enough data yet
+        let (status, _) = monitor.update("a", 1, "gfroerli", 3.60, day(0)).unwrap();
+        assert_eq!(status.trend_v_per_day, None);
+
+        // Discharging with 10 mV per day
+        monitor.update("a", 1, "gfroerli", 3.59, day(1));
+        let (status, _) = monitor.update("a", 1, "gfroerli", 3.58, day(2)).unwrap();
+        let trend = status.trend_v_per_day.unwrap();
+        assert!((trend + 0.01).abs() < 1e-4, "{}", trend);
+        let days = status.days_remaining.unwrap();
+        assert!((days - 38.0).abs() < 0.1, "{}", days);
+
+        // Old readings are discarded
+        monitor.update("a", 1, "gfroerli", 3.58, day(20));
+        let (status, _) = monitor.update("a", 1, "gfroerli", 3.58, day(21)).unwrap();
+        assert_eq!(status.trend_v_per_day, None);
+    }
+}
@@ -0,0 +1,154 @@
+//! Per-sensor calibration of decoded readings.
+//!
+//! A calibration entry matches readings by quantity and (optionally) by
+//! position and channel. The corrected value is calculated as follows:
+//!
+//! 1. If a calibration table is configured, the raw value is mapped through
+//!    the table using linear interpolation between the table points (and
+//!    linear extrapolation beyond the first and last point).
+//! 2. The result is multiplied by `gain` (default 1) and `offset` (default 0)
+//!    is added.
+//!
+//! The original value is kept in [`Reading::raw_value`].
+
+use anyhow::{bail, Result};
+
+use crate::{config::Calibration, payload::Measurement};
+
+/// Validate the calibration config of a sensor.
+pub fn validate(calibrations: &[Calibration]) -> Result<()> {
+    for calibration in calibrations {
+        if let Some(ref table) = calibration.table {
+            if table.is_empty() {
+                bail!("Calibration table must not be empty");
+            }
+            if table.windows(2).any(|w| w[0].0 >= w[1].0) {
+                bail!("Calibration table must be sorted by strictly increasing raw values");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Map a value through a calibration table using linear interpolation.
+fn interpolate(table: &[(f32, f32)], value: f32) -> f32 {
+    match table {
+        [] => value,
+        [(raw, corrected)] => value + (corrected - raw),
+        _ => {
+            // Find the segment containing the value (or the first / last
+            // segment for extrapolation)
+            let i = table
+                .windows(2)
+                .position(|w| value <= w[1].0)
+                .unwrap_or(table.len() - 2);
+            let ((x0, y0), (x1, y1)) = (table[i], table[i + 1]);
+            y0 + (value - x0) * (y1 - y0) / (x1 - x0)
+        }
+    }
+}
+
+/// Apply a calibration entry to a value.
+fn correct(calibration: &Calibration, value: f32) -> f32 {
+    let value = match calibration.table {
+        Some(ref table) => interpolate(table, value),
+        None => value,
+    };
+    value * calibration.gain.unwrap_or(1.0) + calibration.offset.unwrap_or(0.0)
+}
+
+/// Apply the first matching calibration entry to every reading of the
+/// measurement.
+pub fn apply(calibrations: &[Calibration], measurement: &mut Measurement) {
+    for reading in &mut measurement.readings {
+        if let Some(calibration) = calibrations
+            .iter()
+            .find(|c| reading.matches(c.quantity, c.position, c.channel))
+        {
+            let raw_value = reading.raw_value.unwrap_or(reading.value);
+            reading.value = correct(calibration, raw_value);
+            reading.raw_value = Some(raw_value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::{Position, Quantity, Reading};
+
+    fn calibration(
+        offset: Option<f32>,
+        gain: Option<f32>,
+        table: Option<Vec<(f32, f32)>>,
+    ) -> Calibration {
+        Calibration {
+            quantity: Quantity::Temperature,
+            position: Some(Position::Water),
+            channel: None,
+            offset,
+            gain,
+            table,
+        }
+    }
+
+    #[test]
+    fn test_offset_and_gain() {
+        let c = calibration(Some(-0.3), None, None);
+        assert_eq!(correct(&c, 20.0), 19.7);
+        let c = calibration(Some(1.0), Some(2.0), None);
+        assert_eq!(correct(&c, 10.0), 21.0);
+    }
+
+    #[test]
+    fn test_table() {
+        let table = vec![(0.0, 0.5), (10.0, 10.0), (20.0, 19.0)];
+        let c = calibration(None, None, Some(table));
+        assert_eq!(correct(&c, 0.0), 0.5);
+        assert_eq!(correct(&c, 5.0), 5.25);
+        assert_eq!(correct(&c, 10.0), 10.0);
+        assert_eq!(correct(&c, 15.0), 14.5);
+        // Extrapolation
+        assert_eq!(correct(&c, -10.0), -9.0);
+        assert_eq!(correct(&c, 30.0), 28.0);
+
+        // A single point acts as offset
+        let c = calibration(None, None, Some(vec![(10.0, 10.5)]));
+        assert_eq!(correct(&c, 20.0), 20.5);
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut measurement = Measurement {
+            readings: vec![
+                Reading::new(Quantity::Temperature, Position::Water, 20.0).with_channel(1),
+                Reading::new(Quantity::Temperature, Position::Water, 18.0).with_channel(2),
+                Reading::new(Quantity::Temperature, Position::Enclosure, 25.0),
+            ],
+        };
+        let calibrations = vec![
+            Calibration {
+                channel: Some(2),
+                ..calibration(Some(0.5), None, None)
+            },
+            calibration(Some(-0.25), None, None),
+        ];
+        apply(&calibrations, &mut measurement);
+        let values = measurement
+            .readings
+            .iter()
+            .map(|r| (r.value, r.raw_value))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            vec![(19.75, Some(20.0)), (18.5, Some(18.0)), (25.0, None)]
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(&[calibration(None, None, Some(vec![(0.0, 0.0), (1.0, 1.0)]))]).is_ok());
+        assert!(validate(&[calibration(None, None, Some(vec![]))]).is_err());
+        assert!(validate(&[calibration(None, None, Some(vec![(1.0, 0.0), (1.0, 1.0)]))]).is_err());
+    }
+}
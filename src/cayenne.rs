@@ -0,0 +1,238 @@
+//! Decoder for the Cayenne Low Power Payload (LPP) format.
+//!
+//! An LPP payload is a sequence of data items, each consisting of a 1 byte
+//! channel, a 1 byte data type and a type specific number of data bytes.
+//! All multi-byte values are in big endian format.
+//!
+//! All readings are reported with [`Position::Device`] and the LPP channel.
+//! Use the per-channel sensor config to map channels to other positions
+//! (e.g. the water temperature probe).
+
+use anyhow::{bail, Result};
+
+use crate::payload::{Measurement, PayloadDecoder, Position, Quantity, Reading};
+
+/// Cayenne LPP data types (IPSO object ID - 3200).
+const DIGITAL_INPUT: u8 = 0;
+const DIGITAL_OUTPUT: u8 = 1;
+const ANALOG_INPUT: u8 = 2;
+const ANALOG_OUTPUT: u8 = 3;
+const ILLUMINANCE: u8 = 101;
+const PRESENCE: u8 = 102;
+const TEMPERATURE: u8 = 103;
+const HUMIDITY: u8 = 104;
+const ACCELEROMETER: u8 = 113;
+const BAROMETER: u8 = 115;
+const GYROMETER: u8 = 134;
+const GPS: u8 = 136;
+
+/// Cayenne Low Power Payload
+pub struct CayenneLppDecoder;
+
+impl PayloadDecoder for CayenneLppDecoder {
+    fn label(&self) -> &str {
+        "Cayenne LPP"
+    }
+
+    fn decode(&self, _frame_port: u16, payload: &[u8]) -> Result<Measurement> {
+        parse_payload_cayenne_lpp(payload)
+    }
+}
+
+/// Read a big endian unsigned integer.
+fn unsigned(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |acc, b| (acc << 8) | u32::from(*b))
+}
+
+/// Read a big endian two's complement signed integer.
+fn signed(bytes: &[u8]) -> i32 {
+    let bits = 8 * bytes.len() as u32;
+    let value = unsigned(bytes) as i32;
+    (value << (32 - bits)) >> (32 - bits)
+}
+
+/// Parse a Cayenne LPP payload.
+pub fn parse_payload_cayenne_lpp(payload: &[u8]) -> Result<Measurement> {
+    let mut readings = vec![];
+    let mut rest = payload;
+    while !rest.is_empty() {
+        if rest.len() < 2 {
+            bail!("Truncated Cayenne LPP data item header");
+        }
+        let (channel, data_type) = (rest[0], rest[1]);
+        let size = match data_type {
+            DIGITAL_INPUT | DIGITAL_OUTPUT | PRESENCE | HUMIDITY => 1,
+            ANALOG_INPUT | ANALOG_OUTPUT | ILLUMINANCE | TEMPERATURE | BAROMETER => 2,
+            ACCELEROMETER | GYROMETER => 6,
+            GPS => 9,
+            other => bail!(
+                "Unknown Cayenne LPP data type {} on channel {}",
+                other,
+                channel
+            ),
+        };
+        if rest.len() < 2 + size {
+            bail!(
+                "Truncated Cayenne LPP data item of type {} on channel {}",
+                data_type,
+                channel
+            );
+        }
+        let data = &rest[2..2 + size];
+        rest = &rest[2 + size..];
+
+        let mut push = |quantity, value: f32| {
+            readings.push(Reading::new(quantity, Position::Device, value).with_channel(channel));
+        };
+        match data_type {
+            DIGITAL_INPUT => push(Quantity::DigitalInput, data[0] as f32),
+            DIGITAL_OUTPUT => push(Quantity::DigitalOutput, data[0] as f32),
+            ANALOG_INPUT => push(Quantity::AnalogInput, signed(data) as f32 / 100.0),
+            ANALOG_OUTPUT => push(Quantity::AnalogOutput, signed(data) as f32 / 100.0),
+            ILLUMINANCE => push(Quantity::Illuminance, unsigned(data) as f32),
+            PRESENCE => push(Quantity::Presence, data[0] as f32),
+            TEMPERATURE => push(Quantity::Temperature, signed(data) as f32 / 10.0),
+            HUMIDITY => push(Quantity::Humidity, data[0] as f32 / 2.0),
+            BAROMETER => push(Quantity::Pressure, unsigned(data) as f32 / 10.0),
+            ACCELEROMETER => {
+                push(Quantity::AccelerationX, signed(&data[0..2]) as f32 / 1000.0);
+                push(Quantity::AccelerationY, signed(&data[2..4]) as f32 / 1000.0);
+                push(Quantity::AccelerationZ, signed(&data[4..6]) as f32 / 1000.0);
+            }
+            GYROMETER => {
+                push(
+                    Quantity::AngularVelocityX,
+                    signed(&data[0..2]) as f32 / 100.0,
+                );
+                push(
+                    Quantity::AngularVelocityY,
+                    signed(&data[2..4]) as f32 / 100.0,
+                );
+                push(
+                    Quantity::AngularVelocityZ,
+                    signed(&data[4..6]) as f32 / 100.0,
+                );
+            }
+            GPS => {
+                push(Quantity::Latitude, signed(&data[0..3]) as f32 / 10_000.0);
+                push(Quantity::Longitude, signed(&data[3..6]) as f32 / 10_000.0);
+                push(Quantity::Altitude, signed(&data[6..9]) as f32 / 100.0);
+            }
+            _ => unreachable!(),
+        }
+    }
+    if readings.is_empty() {
+        bail!("Cayenne LPP payload is empty");
+    }
+    Ok(Measurement { readings })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(measurement: &Measurement) -> Vec<(u8, Quantity, f32)> {
+        measurement
+            .readings
+            .iter()
+            .map(|r| (r.channel.unwrap(), r.quantity, r.value))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_cayenne_lpp_payload() {
+        // Examples taken from the Cayenne LPP documentation
+        let payload = [0x03, 0x67, 0x01, 0x10, 0x05, 0x67, 0x00, 0xff];
+        let measurement = parse_payload_cayenne_lpp(&payload).unwrap();
+        assert_eq!(
+            values(&measurement),
+            vec![
+                (3, Quantity::Temperature, 27.2),
+                (5, Quantity::Temperature, 25.5),
+            ]
+        );
+
+        let payload = [0x06, 0x71, 0x04, 0xd2, 0xfb, 0x2e, 0x00, 0x00];
+        let measurement = parse_payload_cayenne_lpp(&payload).unwrap();
+        assert_eq!(
+            values(&measurement),
+            vec![
+                (6, Quantity::AccelerationX, 1.234),
+                (6, Quantity::AccelerationY, -1.234),
+                (6, Quantity::AccelerationZ, 0.0),
+            ]
+        );
+
+        let payload = [
+            0x01, 0x88, 0x06, 0x76, 0x5f, 0xf2, 0x96, 0x0a, 0x00, 0x03, 0xe8,
+        ];
+        let measurement = parse_payload_cayenne_lpp(&payload).unwrap();
+        assert_eq!(
+            values(&measurement),
+            vec![
+                (1, Quantity::Latitude, 42.3519),
+                (1, Quantity::Longitude, -87.9094),
+                (1, Quantity::Altitude, 10.0),
+            ]
+        );
+        let location = measurement.location().unwrap();
+        assert!((location.latitude - 42.3519).abs() < 1e-4);
+        assert!((location.longitude + 87.9094).abs() < 1e-4);
+        assert_eq!(location.altitude, Some(10.0));
+        assert_eq!(Measurement::default().location(), None);
+    }
+
+    #[test]
+    fn test_parse_cayenne_lpp_all_types() {
+        let payload = [
+            0x00, 0x00, 0x01, // Digital input
+            0x01, 0x01, 0x00, // Digital output
+            0x02, 0x02, 0xff, 0x9c, // Analog input (-1.00)
+            0x03, 0x03, 0x01, 0x2c, // Analog output (3.00)
+            0x04, 0x65, 0x01, 0xf4, // Illuminance (500 lx)
+            0x05, 0x66, 0x01, // Presence
+            0x06, 0x68, 0x91, // Humidity (72.5 %)
+            0x07, 0x73, 0x27, 0x91, // Barometer (1012.9 hPa)
+            0x08, 0x86, 0x00, 0x64, 0xff, 0x38, 0x00, 0x00, // Gyrometer
+        ];
+        let measurement = parse_payload_cayenne_lpp(&payload).unwrap();
+        assert_eq!(
+            values(&measurement),
+            vec![
+                (0, Quantity::DigitalInput, 1.0),
+                (1, Quantity::DigitalOutput, 0.0),
+                (2, Quantity::AnalogInput, -1.0),
+                (3, Quantity::AnalogOutput, 3.0),
+                (4, Quantity::Illuminance, 500.0),
+                (5, Quantity::Presence, 1.0),
+                (6, Quantity::Humidity, 72.5),
+                (7, Quantity::Pressure, 1012.9),
+                (8, Quantity::AngularVelocityX, 1.0),
+                (8, Quantity::AngularVelocityY, -2.0),
+                (8, Quantity::AngularVelocityZ, 0.0),
+            ]
+        );
+        assert!(measurement
+            .readings
+            .iter()
+            .all(|r| r.position == Position::Device));
+    }
+
+    #[test]
+    fn test_parse_cayenne_lpp_invalid() {
+        let err = parse_payload_cayenne_lpp(&[]).unwrap_err();
+        assert_eq!(err.to_string(), "Cayenne LPP payload is empty");
+        let err = parse_payload_cayenne_lpp(&[0x01, 0x67, 0x01]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Truncated Cayenne LPP data item of type 103 on channel 1"
+        );
+        let err = parse_payload_cayenne_lpp(&[0x01, 0x42, 0x01]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown Cayenne LPP data type 66 on channel 1"
+        );
+        let err = parse_payload_cayenne_lpp(&[0x01, 0x00, 0x01, 0x02]).unwrap_err();
+        assert_eq!(err.to_string(), "Truncated Cayenne LPP data item header");
+    }
+}
@@ -0,0 +1,348 @@
+//! Gateway coverage statistics.
+//!
+//! For every sensor, the gateways that received its recent uplinks are
+//! recorded. From these, rolling statistics per (sensor, gateway) pair are
+//! computed over a time window: the reception ratio (the share of the
+//! sensor's uplinks that were received by the gateway), the median RSSI and
+//! SNR, and the time at which the gateway last heard the sensor.
+//!
+//! The statistics are persisted in the state directory, so that the
+//! `gateways` subcommand can report them while the relay is running.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Coverage, output::ReceivingGateway, state::StateFile};
+
+/// Name of the state file.
+const STATE_FILE: &str = "gateways.json";
+
+/// Default time window for the statistics.
+const DEFAULT_WINDOW_DAYS: u64 = 7;
+
+/// Maximum number of uplinks per sensor that are kept (to limit the size of
+/// the state file for sensors with a short interval).
+const MAX_UPLINKS: usize = 1000;
+
+/// The reception of an uplink by a gateway.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Reception {
+    gateway: String,
+    rssi: f64,
+    snr: Option<f64>,
+}
+
+/// An uplink and the gateways by which it was received.
+#[derive(Debug, Serialize, Deserialize)]
+struct Uplink {
+    /// UNIX timestamp
+    time: u64,
+    receptions: Vec<Reception>,
+}
+
+/// The coverage state of a single sensor.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SensorState {
+    sensor_id: u32,
+    /// Uplinks within the time window (oldest first)
+    uplinks: VecDeque<Uplink>,
+    /// UNIX timestamp at which each gateway last heard the sensor (also
+    /// contains gateways that did not hear any uplink within the window)
+    last_heard: BTreeMap<String, u64>,
+}
+
+/// Statistics of a (sensor, gateway) pair.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GatewayStats {
+    pub gateway: String,
+    /// Number of uplinks within the window received by this gateway
+    pub uplinks: usize,
+    /// Share of the uplinks within the window received by this gateway
+    pub reception_ratio: f64,
+    pub median_rssi: Option<f64>,
+    pub median_snr: Option<f64>,
+    /// UNIX timestamp at which the gateway last heard the sensor
+    pub last_heard: u64,
+}
+
+/// Statistics of all gateways that heard a sensor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorCoverage {
+    pub dev_eui: String,
+    pub sensor_id: u32,
+    /// Number of uplinks within the window
+    pub uplinks: usize,
+    /// Statistics per gateway, ordered by descending reception ratio
+    pub gateways: Vec<GatewayStats>,
+}
+
+impl SensorCoverage {
+    /// Number of gateways that heard the sensor within the window.
+    pub fn active_gateways(&self) -> usize {
+        self.gateways.iter().filter(|g| g.uplinks > 0).count()
+    }
+}
+
+/// Keeps track of the gateways receiving the uplinks of all sensors.
+pub struct CoverageTracker {
+    /// Time window for the statistics
+    window: Duration,
+    /// The coverage state per DevEUI
+    sensors: StateFile<HashMap<String, SensorState>>,
+}
+
+/// Return the median of the values (or `None` if there are no values).
+fn median(mut values: Vec<f64>) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    Some(if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    })
+}
+
+impl SensorState {
+    /// Compute the statistics of all gateways that heard the sensor.
+    fn coverage(&self, dev_eui: &str) -> SensorCoverage {
+        let gateways = self.last_heard.iter().map(|(gateway, last_heard)| {
+            let receptions = self
+                .uplinks
+                .iter()
+                .flat_map(|uplink| &uplink.receptions)
+                .filter(|r| &r.gateway == gateway)
+                .collect::<Vec<_>>();
+            GatewayStats {
+                gateway: gateway.clone(),
+                uplinks: receptions.len(),
+                reception_ratio: if self.uplinks.is_empty() {
+                    0.0
+                } else {
+                    receptions.len() as f64 / self.uplinks.len() as f64
+                },
+                median_rssi: median(receptions.iter().map(|r| r.rssi).collect()),
+                median_snr: median(receptions.iter().filter_map(|r| r.snr).collect()),
+                last_heard: *last_heard,
+            }
+        });
+        let mut gateways = gateways.collect::<Vec<_>>();
+        gateways.sort_by(|a, b| {
+            b.reception_ratio
+                .total_cmp(&a.reception_ratio)
+                .then_with(|| a.gateway.cmp(&b.gateway))
+        });
+        SensorCoverage {
+            dev_eui: dev_eui.to_string(),
+            sensor_id: self.sensor_id,
+            uplinks: self.uplinks.len(),
+            gateways,
+        }
+    }
+}
+
+impl CoverageTracker {
+    /// Create a new coverage tracker. If a state directory is specified, the
+    /// previous state is loaded from there.
+    pub fn load(config: Option<&Coverage>, state_dir: Option<&Path>) -> Result<Self> {
+        let sensors = StateFile::load(state_dir, STATE_FILE, "gateway statistics")?;
+        Ok(Self {
+            window: Duration::from_secs(
+                config
+                    .and_then(|c| c.window_days)
+                    .unwrap_or(DEFAULT_WINDOW_DAYS)
+                    * 86400,
+            ),
+            sensors,
+        })
+    }
+
+    /// Save pending changes to the state file.
+    pub fn flush(&self) {
+        self.sensors.flush();
+    }
+
+    /// Register the gateways that received an uplink of a sensor and return
+    /// the updated statistics of the sensor.
+    pub fn update(
+        &self,
+        dev_eui: &str,
+        sensor_id: u32,
+        gateways: &[ReceivingGateway],
+        now: SystemTime,
+    ) -> SensorCoverage {
+        let timestamp = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut sensors = self.sensors.lock();
+        let sensor = sensors.entry(dev_eui.to_string()).or_default();
+        sensor.sensor_id = sensor_id;
+
+        // A gateway may report an uplink once per antenna, only the best
+        // reception is counted
+        let mut receptions: Vec<Reception> = Vec::with_capacity(gateways.len());
+        for gateway in gateways {
+            match receptions.iter_mut().find(|r| r.gateway == gateway.name) {
+                Some(reception) if reception.rssi >= gateway.rssi => {}
+                Some(reception) => {
+                    reception.rssi = gateway.rssi;
+                    reception.snr = gateway.snr;
+                }
+                None => receptions.push(Reception {
+                    gateway: gateway.name.clone(),
+                    rssi: gateway.rssi,
+                    snr: gateway.snr,
+                }),
+            }
+        }
+        for reception in &receptions {
+            sensor
+                .last_heard
+                .insert(reception.gateway.clone(), timestamp);
+        }
+        sensor.uplinks.push_back(Uplink {
+            time: timestamp,
+            receptions,
+        });
+        while let Some(uplink) = sensor.uplinks.front() {
+            if sensor.uplinks.len() <= MAX_UPLINKS
+                && timestamp.saturating_sub(uplink.time) <= self.window.as_secs()
+            {
+                break;
+            }
+            sensor.uplinks.pop_front();
+        }
+
+        let coverage = sensor.coverage(dev_eui);
+        self.sensors.changed(&sensors);
+        coverage
+    }
+
+    /// Return the statistics of all sensors, ordered by sensor ID.
+    pub fn report(&self) -> Vec<SensorCoverage> {
+        let sensors = self.sensors.lock();
+        let mut report = sensors
+            .iter()
+            .map(|(dev_eui, sensor)| sensor.coverage(dev_eui))
+            .collect::<Vec<_>>();
+        report.sort_by_key(|coverage| coverage.sensor_id);
+        report
+    }
+
+    /// The time window of the statistics.
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gateway(name: &str, rssi: f64, snr: Option<f64>) -> ReceivingGateway {
+        ReceivingGateway {
+            name: name.to_string(),
+            eui: None,
+            rssi,
+            channel_rssi: rssi,
+            snr,
+            time: None,
+            timestamp: None,
+            antenna_index: 0,
+            location: None,
+        }
+    }
+
+    fn minute(m: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000 + m * 60)
+    }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(median(vec![]), None);
+        assert_eq!(median(vec![-3.0]), Some(-3.0));
+        assert_eq!(median(vec![-1.0, -5.0, -3.0]), Some(-3.0));
+        assert_eq!(median(vec![-1.0, -5.0, -3.0, -2.0]), Some(-2.5));
+    }
+
+    #[test]
+    fn test_update() {
+        let tracker = CoverageTracker::load(None, None).unwrap();
+        tracker.update("AABB", 1, &[gateway("gw-1", -110.0, Some(-3.0))], minute(0));
+        tracker.update(
+            "AABB",
+            1,
+            &[
+                gateway("gw-1", -100.0, Some(2.0)),
+                gateway("gw-2", -90.0, None),
+                gateway("gw-2", -95.0, Some(1.0)),
+            ],
+            minute(10),
+        );
+        let coverage = tracker.update(
+            "AABB",
+            1,
+            &[gateway("gw-1", -104.0, Some(-1.0))],
+            minute(20),
+        );
+        assert_eq!(coverage.uplinks, 3);
+        assert_eq!(coverage.active_gateways(), 2);
+        assert_eq!(
+            coverage.gateways,
+            vec![
+                GatewayStats {
+                    gateway: "gw-1".to_string(),
+                    uplinks: 3,
+                    reception_ratio: 1.0,
+                    median_rssi: Some(-104.0),
+                    median_snr: Some(-1.0),
+                    last_heard: 1_700_001_200,
+                },
+                GatewayStats {
+                    gateway: "gw-2".to_string(),
+                    uplinks: 1,
+                    reception_ratio: 1.0 / 3.0,
+                    median_rssi: Some(-90.0),
+                    median_snr: None,
+                    last_heard: 1_700_000_600,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_window() {
+        let config = Coverage {
+            window_days: Some(1),
+        };
+        let tracker = CoverageTracker::load(Some(&config), None).unwrap();
+        tracker.update("AABB", 1, &[gateway("gw-1", -110.0, None)], minute(0));
+        tracker.update("CCDD", 2, &[gateway("gw-1", -90.0, None)], minute(0));
+        let coverage = tracker.update(
+            "AABB",
+            1,
+            &[gateway("gw-2", -100.0, None)],
+            minute(24 * 60 + 1),
+        );
+
+        // The first uplink is outside the window, but the gateway is still
+        // listed
+        assert_eq!(coverage.uplinks, 1);
+        assert_eq!(coverage.active_gateways(), 1);
+        assert_eq!(coverage.gateways[0].gateway, "gw-2");
+        assert_eq!(coverage.gateways[1].gateway, "gw-1");
+        assert_eq!(coverage.gateways[1].reception_ratio, 0.0);
+        assert_eq!(coverage.gateways[1].median_rssi, None);
+
+        let report = tracker.report();
+        assert_eq!(report.len(), 2);
+        assert_eq!(report[0], coverage);
+        assert_eq!(report[1].sensor_id, 2);
+    }
+}
@@ -0,0 +1,241 @@
+//! Tracking of uplink frame counters (FCnt) per device.
+//!
+//! The last frame counter of every device is used to detect lost frames
+//! (gaps in the counter) and duplicates (the same frame delivered twice, e.g.
+//! after an MQTT reconnect with a persistent session). An uplink is only a
+//! duplicate if it repeats the last frame counter with the same payload
+//! within [`DUPLICATE_WINDOW`]. If the frame counter decreases, jumps by more
+//! than [`MAX_FCNT_GAP`] or repeats with a different payload, we assume that
+//! the device was reset or re-joined the network and start counting anew,
+//! while keeping the totals.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::state::StateFile;
+
+/// Name of the state file.
+const STATE_FILE: &str = "frame_counters.json";
+
+/// Larger gaps between two frame counters are treated as a counter reset
+/// (same value as `MAX_FCNT_GAP` in the LoRaWAN 1.0 specification).
+const MAX_FCNT_GAP: u32 = 16384;
+
+/// An uplink repeating the last frame counter and payload is only treated as
+/// a duplicate within this time after the last uplink.
+const DUPLICATE_WINDOW: Duration = Duration::from_secs(3600);
+
+/// Frame counter state of a single device.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+struct DeviceState {
+    /// The last received frame counter
+    last_fcnt: u32,
+    /// The payload of the last uplink
+    #[serde(default)]
+    last_payload: Vec<u8>,
+    /// UNIX timestamp of the last uplink
+    #[serde(default)]
+    last_time: u64,
+    /// Total number of received frames
+    received: u64,
+    /// Total number of lost frames
+    lost: u64,
+}
+
+/// Frame statistics of an uplink.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameStats {
+    /// The frame counter of the uplink
+    pub fcnt: u32,
+    /// Number of frames lost since the previous uplink
+    pub lost_frames: u32,
+    /// Whether the frame counter was reset since the previous uplink
+    pub reset: bool,
+    /// Total number of received frames
+    pub received_total: u64,
+    /// Total number of lost frames
+    pub lost_total: u64,
+}
+
+impl FrameStats {
+    /// The packet delivery ratio (between 0 and 1) since tracking started.
+    pub fn packet_delivery_ratio(&self) -> f64 {
+        self.received_total as f64 / (self.received_total + self.lost_total) as f64
+    }
+}
+
+/// Keeps track of the frame counters of all devices.
+pub struct FrameCounterTracker {
+    /// The frame counter state per DevEUI
+    devices: StateFile<HashMap<String, DeviceState>>,
+}
+
+impl FrameCounterTracker {
+    /// Create a new tracker. If a state directory is specified, the previous
+    /// state is loaded from there.
+    pub fn load(state_dir: Option<&Path>) -> Result<Self> {
+        let devices = StateFile::load(state_dir, STATE_FILE, "frame counters")?;
+        Ok(Self { devices })
+    }
+
+    /// Save pending changes to the state file.
+    pub fn flush(&self) {
+        self.devices.flush();
+    }
+
+    /// Register an uplink with the specified frame counter and payload.
+    ///
+    /// Returns `None` if the uplink is a duplicate.
+    pub fn update(
+        &self,
+        dev_eui: &str,
+        fcnt: u32,
+        payload: &[u8],
+        now: SystemTime,
+    ) -> Option<FrameStats> {
+        let time = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut devices = self.devices.lock();
+        let stats = match devices.get_mut(dev_eui) {
+            Some(device) => {
+                let repeated = fcnt == device.last_fcnt;
+                if repeated
+                    && payload == device.last_payload
+                    && time.saturating_sub(device.last_time) < DUPLICATE_WINDOW.as_secs()
+                {
+                    return None;
+                }
+                let gap = fcnt.wrapping_sub(device.last_fcnt);
+                let reset = repeated || fcnt < device.last_fcnt || gap > MAX_FCNT_GAP;
+                let lost_frames = if reset { 0 } else { gap - 1 };
+                device.last_fcnt = fcnt;
+                device.last_payload = payload.to_vec();
+                device.last_time = time;
+                device.received += 1;
+                device.lost += u64::from(lost_frames);
+                FrameStats {
+                    fcnt,
+                    lost_frames,
+                    reset,
+                    received_total: device.received,
+                    lost_total: device.lost,
+                }
+            }
+            None => {
+                devices.insert(
+                    dev_eui.to_string(),
+                    DeviceState {
+                        last_fcnt: fcnt,
+                        last_payload: payload.to_vec(),
+                        last_time: time,
+                        received: 1,
+                        lost: 0,
+                    },
+                );
+                FrameStats {
+                    fcnt,
+                    lost_frames: 0,
+                    reset: false,
+                    received_total: 1,
+                    lost_total: 0,
+                }
+            }
+        };
+        self.devices.changed(&devices);
+        Some(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lost(stats: Option<FrameStats>) -> Option<(u32, bool)> {
+        stats.map(|s| (s.lost_frames, s.reset))
+    }
+
+    fn minute(m: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000 + m * 60)
+    }
+
+    /// Register an uplink with a fixed payload, one minute after the
+    /// previous one.
+    fn update(tracker: &FrameCounterTracker, dev_eui: &str, fcnt: u32) -> Option<FrameStats> {
+        tracker.update(dev_eui, fcnt, &[0x01], minute(u64::from(fcnt)))
+    }
+
+    #[test]
+    fn test_lost_frames_and_duplicates() {
+        let tracker = FrameCounterTracker::load(None).unwrap();
+        assert_eq!(lost(update(&tracker, "a", 10)), Some((0, false)));
+        assert_eq!(lost(update(&tracker, "a", 11)), Some((0, false)));
+        assert_eq!(lost(update(&tracker, "a", 11)), None);
+        assert_eq!(lost(update(&tracker, "a", 14)), Some((2, false)));
+
+        // Devices are tracked independently
+        assert_eq!(lost(update(&tracker, "b", 14)), Some((0, false)));
+
+        let stats = update(&tracker, "a", 15).unwrap();
+        assert_eq!(stats.received_total, 4);
+        assert_eq!(stats.lost_total, 2);
+        assert!((stats.packet_delivery_ratio() - 4.0 / 6.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_counter_reset() {
+        let tracker = FrameCounterTracker::load(None).unwrap();
+        update(&tracker, "a", 1000);
+        assert_eq!(lost(update(&tracker, "a", 0)), Some((0, true)));
+        assert_eq!(lost(update(&tracker, "a", 2)), Some((1, false)));
+        assert_eq!(lost(update(&tracker, "a", 50_000)), Some((0, true)));
+        let stats = update(&tracker, "a", 50_001).unwrap();
+        assert_eq!(stats.received_total, 5);
+        assert_eq!(stats.lost_total, 1);
+    }
+
+    #[test]
+    fn test_repeated_fcnt() {
+        let tracker = FrameCounterTracker::load(None).unwrap();
+        tracker.update("a", 0, &[0x01], minute(0));
+
+        // The same frame counter and payload shortly after: duplicate
+        assert_eq!(lost(tracker.update("a", 0, &[0x01], minute(5))), None);
+
+        // A reset device that happens to send the same frame counter with
+        // a different payload, or much later
+        assert_eq!(
+            lost(tracker.update("a", 0, &[0x02], minute(10))),
+            Some((0, true))
+        );
+        assert_eq!(
+            lost(tracker.update("a", 0, &[0x02], minute(80))),
+            Some((0, true))
+        );
+        assert_eq!(
+            lost(tracker.update("a", 1, &[0x02], minute(85))),
+            Some((0, false))
+        );
+    }
+
+    #[test]
+    fn test_persistence() {
+        let dir = std::env::temp_dir().join(format!("ttn-relay-fcnt-{}", std::process::id()));
+        let tracker = FrameCounterTracker::load(Some(&dir)).unwrap();
+        update(&tracker, "a", 1);
+        update(&tracker, "a", 3);
+        // Changes are saved when flushed
+        tracker.flush();
+
+        let tracker = FrameCounterTracker::load(Some(&dir)).unwrap();
+        assert_eq!(lost(update(&tracker, "a", 3)), None);
+        let stats = update(&tracker, "a", 4).unwrap();
+        assert_eq!(stats.received_total, 3);
+        assert_eq!(stats.lost_total, 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -2,40 +2,68 @@ use std::collections::HashMap;
 
 use anyhow::{Context, Result};
 use base64::prelude::{Engine, BASE64_STANDARD};
-use log::debug;
+use log::{debug, info};
 use ureq::Agent;
 
-use crate::config;
+use crate::{
+    config,
+    output::{Output, Record},
+};
 
-pub enum InfluxDbConfig<'a> {
-    V1(&'a config::InfluxDb),
-    V2(&'a config::InfluxDb2),
+pub enum InfluxDbConfig {
+    V1(config::InfluxDb),
+    V2(config::InfluxDb2),
 }
 
-pub fn submit_measurement(
-    agent: Agent,
-    config: InfluxDbConfig,
-    tags: &HashMap<&'static str, String>,
-    fields: &HashMap<&'static str, String>,
-) -> Result<()> {
+/// Name of the series with one point per uplink and receiving gateway.
+const RECEPTION_MEASUREMENT: &str = "reception";
+
+/// A single point of a series.
+struct Point {
+    /// The measurement name (default: the configured measurement name)
+    measurement: Option<&'static str>,
+    tags: HashMap<&'static str, String>,
+    fields: HashMap<String, String>,
+}
+
+/// Escape a tag value for the line protocol.
+fn escape_tag(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+/// Write points to InfluxDB in a single request.
+fn submit_points(agent: &Agent, config: &InfluxDbConfig, points: &[Point]) -> Result<()> {
     // Prepare payloads
-    let mut payloads = vec![];
-    let tags_string = tags
-        .iter()
-        .map(|(k, v)| format!("{}={}", k, v))
-        .collect::<Vec<String>>()
-        .join(",");
-    let fields_string = fields
-        .iter()
-        .map(|(k, v)| format!("{}={}", k, v))
-        .collect::<Vec<String>>()
-        .join(",");
     let default_measurement = "temperature";
     let measurement = match config {
         InfluxDbConfig::V1(c) => c.measurement.as_deref().unwrap_or(default_measurement),
         InfluxDbConfig::V2(c) => c.measurement.as_deref().unwrap_or(default_measurement),
     };
-    payloads.push(format!("{},{} {}", measurement, tags_string, fields_string));
+    let mut payloads = vec![];
+    for point in points {
+        let tags_string = point
+            .tags
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<String>>()
+            .join(",");
+        let fields_string = point
+            .fields
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<String>>()
+            .join(",");
+        payloads.push(format!(
+            "{},{} {}",
+            point.measurement.unwrap_or(measurement),
+            tags_string,
+            fields_string
+        ));
+    }
     let payload = payloads.join("\n");
     debug!("Sending payload: {}", payload);
 
@@ -55,7 +83,10 @@ pub fn submit_measurement(
     // Create request
     let url = match config {
         InfluxDbConfig::V1(c) => format!("{}/write?db={}", c.base_url, c.db),
-        InfluxDbConfig::V2(c) => format!("{}/api/v2/write?org={}&bucket={}", c.base_url, c.org, c.bucket),
+        InfluxDbConfig::V2(c) => format!(
+            "{}/api/v2/write?org={}&bucket={}",
+            c.base_url, c.org, c.bucket
+        ),
     };
 
     // Send request to server
@@ -67,3 +98,323 @@ pub fn submit_measurement(
 
     Ok(())
 }
+
+/// Build the tags and value fields of a record.
+///
+/// Records with rejected readings are tagged with `rejected=true`.
+fn point(record: &Record) -> Point {
+    // Note:
+    // - Tags can be used for filtering and grouping.
+    // - Value fields can be visualized directly.
+    let mut tags = HashMap::new();
+    let mut fields = HashMap::new();
+    let meta = &record.meta;
+
+    // Sensor info
+    tags.insert("sensor_id", record.sensor.sensor_id.to_string());
+    tags.insert("sensor_dev_eui", record.dev_eui.clone());
+    tags.insert("sensor_type", record.sensor.sensor_type.clone());
+    if record.rejected {
+        tags.insert("rejected", "true".to_string());
+    }
+
+    // Radio settings
+    if let Some(ref data_rate) = meta.data_rate {
+        tags.insert("data_rate", escape_tag(&data_rate.to_string()));
+        tags.insert("modulation", data_rate.modulation().to_string());
+        if let Some(coding_rate) = data_rate.coding_rate() {
+            fields.insert("coding_rate".into(), format!("\"{}\"", coding_rate));
+        }
+        if let Some(bit_rate) = data_rate.bit_rate() {
+            fields.insert("bit_rate".into(), format!("{}i", bit_rate));
+        }
+    }
+    if let Some(sf) = meta.spreading_factor() {
+        tags.insert("sf", sf.to_string());
+        fields.insert("sf".into(), format!("{}i", sf));
+    }
+    if let Some(bw) = meta.bandwidth() {
+        tags.insert("bw", bw.to_string());
+        fields.insert("bw".into(), format!("{}i", bw));
+    }
+    if let Some(frequency) = meta.frequency {
+        fields.insert("frequency".into(), format!("{}i", frequency));
+    }
+    if let Some(timestamp) = meta.timestamp {
+        fields.insert("timestamp".into(), format!("{}i", timestamp));
+    }
+    fields.insert("airtime_ms".into(), format!("{}i", meta.airtime_ms));
+    fields.insert(
+        "airtime_24h_ms".into(),
+        format!("{}i", meta.airtime.used_24h_ms),
+    );
+    fields.insert(
+        "fair_use_ratio".into(),
+        format!("{:.4}", meta.airtime.fair_use_ratio),
+    );
+
+    // Link quality
+    if let Some(margin) = meta.link.margin_db {
+        fields.insert("link_margin_db".into(), format!("{:.1}", margin));
+    }
+    if let Some(sf) = meta.link.recommended_sf {
+        fields.insert("recommended_sf".into(), format!("{}i", sf));
+        fields.insert("adr_stuck".into(), meta.link.adr_stuck.to_string());
+    }
+
+    // Frame counter and loss statistics
+    if let Some(ref frames) = meta.frames {
+        fields.insert("fcnt".into(), format!("{}i", frames.fcnt));
+        fields.insert("lost_frames".into(), format!("{}i", frames.lost_frames));
+        fields.insert(
+            "lost_frames_total".into(),
+            format!("{}i", frames.lost_total),
+        );
+        fields.insert(
+            "pdr".into(),
+            format!("{:.4}", frames.packet_delivery_ratio()),
+        );
+    }
+
+    // Measurements
+    for reading in &record.measurement.readings {
+        if let Some(raw_value) = reading.influx_raw_value() {
+            fields.insert(format!("{}_raw", reading.field_name()), raw_value);
+        }
+        if let Some(depth_m) = reading.depth_m {
+            fields.insert(
+                format!("{}_depth_m", reading.field_name()),
+                depth_m.to_string(),
+            );
+        }
+        fields.insert(reading.field_name(), reading.influx_value());
+    }
+
+    // Battery trend
+    if let Some(ref battery) = record.battery {
+        if let Some(trend) = battery.trend_v_per_day {
+            fields.insert("battery_trend_v_per_day".into(), format!("{:.5}", trend));
+        }
+        if let Some(days) = battery.days_remaining {
+            fields.insert("battery_days_remaining".into(), format!("{:.1}", days));
+        }
+    }
+
+    // Location
+    if let Some(location) = record.location {
+        fields.insert("latitude".into(), format!("{:.6}", location.latitude));
+        fields.insert("longitude".into(), format!("{:.6}", location.longitude));
+        if let Some(altitude) = location.altitude {
+            fields.insert("altitude".into(), format!("{:.1}", altitude));
+        }
+    }
+
+    // Gateway(s)
+    fields.insert(
+        "receiving_gateway_count".into(),
+        format!("{}i", meta.receiving_gateways.len()),
+    );
+    if let Some(gw) = meta.max_rssi_gateway() {
+        tags.insert("max_rssi_gateway", format!("\"{}\"", gw.name));
+        fields.insert("max_rssi".into(), gw.rssi.to_string());
+    }
+    if let Some((gw, snr)) = meta.max_snr_gateway() {
+        tags.insert("max_snr_gateway", format!("\"{}\"", gw.name));
+        fields.insert("max_snr".into(), snr.to_string());
+    }
+
+    Point {
+        measurement: None,
+        tags,
+        fields,
+    }
+}
+
+/// Build one point per receiving gateway of a record.
+fn reception_points(record: &Record) -> Vec<Point> {
+    record
+        .meta
+        .receiving_gateways
+        .iter()
+        .map(|gateway| {
+            let mut tags = HashMap::new();
+            let mut fields = HashMap::new();
+            tags.insert("sensor_id", record.sensor.sensor_id.to_string());
+            tags.insert("sensor_dev_eui", record.dev_eui.clone());
+            tags.insert("gateway_id", escape_tag(&gateway.name));
+            if let Some(ref eui) = gateway.eui {
+                tags.insert("gateway_eui", escape_tag(eui));
+            }
+            tags.insert("antenna_index", gateway.antenna_index.to_string());
+            if let Some(sf) = record.meta.spreading_factor() {
+                tags.insert("sf", sf.to_string());
+            }
+            if let Some(ref frames) = record.meta.frames {
+                fields.insert("fcnt".into(), format!("{}i", frames.fcnt));
+            }
+            fields.insert("rssi".into(), gateway.rssi.to_string());
+            fields.insert("channel_rssi".into(), gateway.channel_rssi.to_string());
+            if let Some(snr) = gateway.snr {
+                fields.insert("snr".into(), snr.to_string());
+            }
+            if let Some(timestamp) = gateway.timestamp {
+                fields.insert("timestamp".into(), format!("{}i", timestamp));
+            }
+            if let Some(ref time) = gateway.time {
+                fields.insert("gateway_time".into(), format!("\"{}\"", time));
+            }
+            if let Some(location) = gateway.location {
+                fields.insert("latitude".into(), format!("{:.6}", location.latitude));
+                fields.insert("longitude".into(), format!("{:.6}", location.longitude));
+                if let Some(altitude) = location.altitude {
+                    fields.insert("altitude".into(), format!("{:.1}", altitude));
+                }
+            }
+            Point {
+                measurement: Some(RECEPTION_MEASUREMENT),
+                tags,
+                fields,
+            }
+        })
+        .collect()
+}
+
+/// Writes every measurement (and optionally rejected readings) to InfluxDB.
+pub struct InfluxDbOutput {
+    config: InfluxDbConfig,
+    /// Whether records with rejected readings are written
+    write_rejected: bool,
+    http_client: Agent,
+}
+
+impl InfluxDbOutput {
+    /// Create an output from the config. InfluxDB 2 has precedence over
+    /// InfluxDB 1. Returns `None` if InfluxDB is not configured.
+    pub fn new(config: &config::Config, http_client: &Agent) -> Option<Self> {
+        let influxdb_config = match (&config.influxdb2, &config.influxdb) {
+            (Some(v2), _) => InfluxDbConfig::V2(v2.clone()),
+            (None, Some(v1)) => InfluxDbConfig::V1(v1.clone()),
+            (None, None) => return None,
+        };
+        Some(Self {
+            config: influxdb_config,
+            write_rejected: config
+                .validation
+                .write_rejected_to_influxdb
+                .unwrap_or(false),
+            http_client: http_client.clone(),
+        })
+    }
+}
+
+impl Output for InfluxDbOutput {
+    fn name(&self) -> &str {
+        "influxdb"
+    }
+
+    fn accepts(&self, record: &Record) -> bool {
+        !record.rejected || self.write_rejected
+    }
+
+    fn send(&self, record: &Record) -> Result<()> {
+        info!("Logging measurement to InfluxDB...");
+        let mut points = vec![point(record)];
+        if !record.rejected {
+            points.extend(reception_points(record));
+        }
+        submit_points(&self.http_client, &self.config, &points)
+            .context("InfluxDB request failed")?;
+        debug!("InfluxDB request succeeded");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        payload::{Measurement, Position, Quantity, Reading},
+        uplink::Location,
+    };
+
+    #[test]
+    fn test_point() {
+        let mut record = Record::example(
+            42,
+            Measurement {
+                readings: vec![
+                    Reading::new(Quantity::Temperature, Position::Water, 16.5),
+                    Reading::new(Quantity::Temperature, Position::Water, 12.5).with_channel(2),
+                ],
+            },
+        );
+        record.measurement.readings[1].depth_m = Some(2.5);
+        record.rejected = true;
+        let Point { tags, fields, .. } = point(&record);
+        assert_eq!(tags["sensor_id"], "42");
+        assert_eq!(tags["rejected"], "true");
+        assert_eq!(tags["sf"], "7");
+        assert_eq!(tags["data_rate"], "SF7BW125");
+        assert_eq!(tags["modulation"], "lora");
+        assert_eq!(fields["coding_rate"], "\"4/5\"");
+        assert_eq!(fields["frequency"], "868100000i");
+        assert_eq!(fields["timestamp"], "1234500i");
+        assert!(!fields.contains_key("bit_rate"));
+        assert_eq!(tags["max_rssi_gateway"], "\"gw-2\"");
+        assert_eq!(tags["max_snr_gateway"], "\"gw-1\"");
+        assert_eq!(fields["fcnt"], "7i");
+        assert_eq!(fields["lost_frames"], "1i");
+        assert_eq!(fields["pdr"], "0.8750");
+        assert_eq!(fields["airtime_24h_ms"], "6000i");
+        assert_eq!(fields["fair_use_ratio"], "0.2000");
+        assert_eq!(fields["link_margin_db"], "4.0");
+        assert_eq!(fields["recommended_sf"], "7i");
+        assert_eq!(fields["adr_stuck"], "false");
+        assert_eq!(fields["receiving_gateway_count"], "2i");
+        assert_eq!(fields["max_rssi"], "-95");
+        assert_eq!(fields["max_snr"], "-3.5");
+        assert!(fields.contains_key("water_temp"));
+        assert!(!fields.contains_key("water_temp_depth_m"));
+        assert_eq!(fields["water_temp_2_depth_m"], "2.5");
+        assert!(!fields.contains_key("battery_days_remaining"));
+        assert!(!fields.contains_key("latitude"));
+
+        record.location = Some(Location {
+            latitude: 47.2267,
+            longitude: 8.8184,
+            altitude: None,
+        });
+        let Point { fields, .. } = point(&record);
+        assert_eq!(fields["latitude"], "47.226700");
+        assert_eq!(fields["longitude"], "8.818400");
+        assert!(!fields.contains_key("altitude"));
+    }
+
+    #[test]
+    fn test_reception_points() {
+        let mut record = Record::example(42, Measurement::default());
+        record.meta.receiving_gateways[1].name = "gw 2".to_string();
+        let points = reception_points(&record);
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0].measurement, Some("reception"));
+
+        let Point { tags, fields, .. } = &points[0];
+        assert_eq!(tags["gateway_id"], "gw-1");
+        assert_eq!(tags["gateway_eui"], "B827EBFFFE000001");
+        assert_eq!(tags["antenna_index"], "0");
+        assert_eq!(fields["rssi"], "-110");
+        assert_eq!(fields["channel_rssi"], "-111");
+        assert_eq!(fields["snr"], "-3.5");
+        assert_eq!(fields["timestamp"], "1234567i");
+        assert_eq!(fields["gateway_time"], "\"2023-11-14T22:13:20.123Z\"");
+        assert_eq!(fields["latitude"], "47.200000");
+        assert_eq!(fields["altitude"], "410.0");
+
+        let Point { tags, fields, .. } = &points[1];
+        assert_eq!(tags["gateway_id"], "gw\\ 2");
+        assert!(!tags.contains_key("gateway_eui"));
+        assert_eq!(tags["antenna_index"], "1");
+        assert!(!fields.contains_key("snr"));
+        assert!(!fields.contains_key("latitude"));
+    }
+}
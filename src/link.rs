@@ -0,0 +1,241 @@
+//! Link quality analysis and adaptive data rate (ADR) recommendations.
+//!
+//! The link margin of an uplink is the SNR of the best receiving gateway
+//! minus the SNR that is required to demodulate the uplink at its spreading
+//! factor. From the best SNR of the recent uplinks of a device, the lowest
+//! spreading factor that would still leave the configured safety margin is
+//! recommended, similar to the ADR algorithm of the network server (every
+//! step to a lower spreading factor requires 2.5 dB more SNR).
+//!
+//! If a device keeps using a higher spreading factor than recommended over
+//! the whole window, ADR is apparently not working for it (e.g. because it
+//! is disabled on the device), and the device wastes airtime and battery.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    path::Path,
+};
+
+use anyhow::Result;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Adr, state::StateFile};
+
+/// Name of the state file.
+const STATE_FILE: &str = "link_quality.json";
+
+/// Number of recent uplinks per device used for the recommendation (the
+/// same number as used by the ADR algorithm of the network server).
+const WINDOW: usize = 20;
+
+/// Minimum number of uplinks before a recommendation is made.
+const MIN_UPLINKS: usize = 10;
+
+/// Default safety margin in dB (the default installation margin of TTN).
+const DEFAULT_MARGIN_DB: f64 = 15.0;
+
+/// Return the SNR in dB required to demodulate an uplink with the specified
+/// spreading factor.
+pub fn required_snr(spreading_factor: u16) -> Option<f64> {
+    match spreading_factor {
+        7 => Some(-7.5),
+        8 => Some(-10.0),
+        9 => Some(-12.5),
+        10 => Some(-15.0),
+        11 => Some(-17.5),
+        12 => Some(-20.0),
+        _ => None,
+    }
+}
+
+/// A single uplink of a device.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Uplink {
+    spreading_factor: u16,
+    /// SNR of the best receiving gateway in dB
+    snr: f64,
+}
+
+/// The link state of a single device.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct DeviceState {
+    /// The recent uplinks (oldest first)
+    uplinks: VecDeque<Uplink>,
+    /// Whether ADR was considered to be not working after the last uplink
+    adr_stuck: bool,
+}
+
+/// The link quality of an uplink and the recommendation for the device.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LinkQuality {
+    /// The link margin of the uplink in dB (SNR of the best gateway minus the
+    /// SNR required for the spreading factor)
+    pub margin_db: Option<f64>,
+    /// The spreading factor recommended based on the recent uplinks
+    pub recommended_sf: Option<u16>,
+    /// Whether the device used a higher spreading factor than recommended
+    /// during the whole window (i.e. ADR does not seem to work)
+    pub adr_stuck: bool,
+}
+
+/// Analyzes the link quality of all devices.
+pub struct LinkAnalyzer {
+    /// Safety margin in dB that must remain at the recommended spreading
+    /// factor
+    margin_db: f64,
+    /// The link state per DevEUI
+    devices: StateFile<HashMap<String, DeviceState>>,
+}
+
+impl LinkAnalyzer {
+    /// Create a new link analyzer. If a state directory is specified, the
+    /// previous state is loaded from there.
+    pub fn load(config: Option<&Adr>, state_dir: Option<&Path>) -> Result<Self> {
+        let devices = StateFile::load(state_dir, STATE_FILE, "link quality state")?;
+        Ok(Self {
+            margin_db: config
+                .and_then(|c| c.margin_db)
+                .unwrap_or(DEFAULT_MARGIN_DB),
+            devices,
+        })
+    }
+
+    /// Save pending changes to the state file.
+    pub fn flush(&self) {
+        self.devices.flush();
+    }
+
+    /// Register an uplink of a device with its spreading factor and the SNR
+    /// of the best receiving gateway.
+    ///
+    /// Uplinks without spreading factor (i.e. non-LoRa uplinks) or SNR are
+    /// not taken into account.
+    pub fn update(
+        &self,
+        dev_eui: &str,
+        spreading_factor: Option<u16>,
+        max_snr: Option<f64>,
+    ) -> LinkQuality {
+        let (Some(spreading_factor), Some(snr)) = (spreading_factor, max_snr) else {
+            return LinkQuality::default();
+        };
+        let Some(required) = required_snr(spreading_factor) else {
+            return LinkQuality::default();
+        };
+        let mut devices = self.devices.lock();
+        let device = devices.entry(dev_eui.to_string()).or_default();
+        device.uplinks.push_back(Uplink {
+            spreading_factor,
+            snr,
+        });
+        while device.uplinks.len() > WINDOW {
+            device.uplinks.pop_front();
+        }
+
+        // Recommend the lowest spreading factor that leaves enough margin
+        // at the best SNR within the window, like the ADR algorithm
+        let recommended_sf = (device.uplinks.len() >= MIN_UPLINKS).then(|| {
+            let best = device
+                .uplinks
+                .iter()
+                .map(|u| u.snr)
+                .fold(f64::NEG_INFINITY, f64::max);
+            (7..=12)
+                .find(|&sf| required_snr(sf).is_some_and(|r| best - r >= self.margin_db))
+                .unwrap_or(12)
+        });
+        let adr_stuck = recommended_sf.is_some_and(|recommended| {
+            device
+                .uplinks
+                .iter()
+                .all(|u| u.spreading_factor > recommended)
+        });
+        if adr_stuck && !device.adr_stuck {
+            warn!(
+                "Device {} keeps using SF{} although SF{} would suffice, ADR does not seem to work",
+                dev_eui,
+                spreading_factor,
+                recommended_sf.unwrap_or(spreading_factor)
+            );
+        }
+        device.adr_stuck = adr_stuck;
+
+        self.devices.changed(&devices);
+        LinkQuality {
+            margin_db: Some(snr - required),
+            recommended_sf,
+            adr_stuck,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_margin() {
+        let analyzer = LinkAnalyzer::load(None, None).unwrap();
+        let link = analyzer.update("AABB", Some(12), Some(-5.0));
+        assert_eq!(link.margin_db, Some(15.0));
+        assert_eq!(link.recommended_sf, None);
+        assert!(!link.adr_stuck);
+
+        assert_eq!(
+            analyzer.update("AABB", None, Some(-5.0)),
+            LinkQuality::default()
+        );
+        assert_eq!(
+            analyzer.update("AABB", Some(12), None),
+            LinkQuality::default()
+        );
+    }
+
+    #[test]
+    fn test_recommendation() {
+        let analyzer = LinkAnalyzer::load(None, None).unwrap();
+
+        // SF12 with 23 dB margin: SF9 leaves a margin of 15.5 dB
+        for _ in 0..MIN_UPLINKS - 1 {
+            analyzer.update("AABB", Some(12), Some(2.0));
+        }
+        let link = analyzer.update("AABB", Some(12), Some(3.0));
+        assert_eq!(link.margin_db, Some(23.0));
+        assert_eq!(link.recommended_sf, Some(9));
+        assert!(link.adr_stuck);
+
+        // ADR kicks in
+        let link = analyzer.update("AABB", Some(9), Some(-6.0));
+        assert_eq!(link.margin_db, Some(6.5));
+        assert_eq!(link.recommended_sf, Some(9));
+        assert!(!link.adr_stuck);
+
+        // A poor link needs a higher spreading factor
+        let analyzer = LinkAnalyzer::load(
+            Some(&Adr {
+                margin_db: Some(10.0),
+            }),
+            None,
+        )
+        .unwrap();
+        for _ in 0..MIN_UPLINKS {
+            analyzer.update("CCDD", Some(7), Some(-2.0));
+        }
+        let link = analyzer.update("CCDD", Some(7), Some(-4.0));
+        assert_eq!(link.margin_db, Some(3.5));
+        assert_eq!(link.recommended_sf, Some(9));
+        assert!(!link.adr_stuck);
+
+        // No spreading factor leaves enough margin
+        for _ in 0..WINDOW {
+            analyzer.update("CCDD", Some(12), Some(-15.0));
+        }
+        assert_eq!(
+            analyzer
+                .update("CCDD", Some(12), Some(-15.0))
+                .recommended_sf,
+            Some(12)
+        );
+    }
+}
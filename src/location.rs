@@ -0,0 +1,283 @@
+//! Tracking of the current location of every sensor.
+//!
+//! Locations are taken from three sources, in increasing order of priority:
+//!
+//! - The location registered for the device in TTN (`user` location in the
+//!   uplink message)
+//! - Locations determined by TTN, i.e. location solver events and positions
+//!   decoded from the payload by TTN (other uplink `locations`)
+//! - Positions decoded from the payload by the relay (latitude and longitude
+//!   readings, e.g. of a GPS tracker)
+//!
+//! A location from a lower priority source only replaces the current
+//! location if the current location is outdated. This way, a sensor with a
+//! GPS receiver is not moved back to its registered location on the next
+//! uplink.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{state::StateFile, uplink::Location};
+
+/// Name of the state file.
+const STATE_FILE: &str = "locations.json";
+
+/// A location from a lower priority source replaces the current location
+/// after this time.
+const MAX_AGE: Duration = Duration::from_secs(7 * 86400);
+
+/// A location change larger than this is logged.
+const MIN_MOVE_M: f64 = 100.0;
+
+/// The source of a location, ordered by priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum LocationSource {
+    /// The location registered in TTN
+    Registry,
+    /// A location determined by TTN (location solver or payload formatter)
+    Network,
+    /// A position decoded from the payload
+    Payload,
+}
+
+/// The current location of a sensor.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SensorLocation {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: Option<f64>,
+    pub source: LocationSource,
+    /// UNIX timestamp at which the location was last reported
+    pub time: u64,
+}
+
+impl SensorLocation {
+    pub fn location(&self) -> Location {
+        Location {
+            latitude: self.latitude,
+            longitude: self.longitude,
+            altitude: self.altitude,
+        }
+    }
+}
+
+/// Return the distance between two locations in meters (haversine
+/// formula, ignoring the altitude).
+pub fn distance_m(a: &Location, b: &Location) -> f64 {
+    const EARTH_RADIUS_M: f64 = 6_371_000.0;
+    let (lat_a, lat_b) = (a.latitude.to_radians(), b.latitude.to_radians());
+    let d_lat = lat_b - lat_a;
+    let d_lon = (b.longitude - a.longitude).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * h.sqrt().asin()
+}
+
+#[derive(Deserialize)]
+struct EndDeviceIds {
+    dev_eui: String,
+}
+
+#[derive(Deserialize)]
+struct LocationSolved {
+    #[serde(default)]
+    service: String,
+    location: Location,
+}
+
+#[derive(Deserialize)]
+struct LocationMessage {
+    end_device_ids: EndDeviceIds,
+    location_solved: LocationSolved,
+}
+
+/// A TTN location solved event (published on the
+/// `v3/{application}/devices/{device}/location/solved` topic).
+#[derive(Debug, PartialEq)]
+pub struct LocationEvent {
+    pub dev_eui: String,
+    /// The service that determined the location
+    pub service: String,
+    pub location: Location,
+}
+
+impl LocationEvent {
+    pub fn parse(payload: &[u8]) -> Result<Self> {
+        let message = serde_json::from_slice::<LocationMessage>(payload)
+            .context("Could not deserialize location event")?;
+        Ok(Self {
+            dev_eui: message.end_device_ids.dev_eui,
+            service: message.location_solved.service,
+            location: message.location_solved.location,
+        })
+    }
+}
+
+/// Keeps track of the current location of all sensors.
+pub struct LocationTracker {
+    /// The location per DevEUI
+    sensors: StateFile<HashMap<String, SensorLocation>>,
+}
+
+impl LocationTracker {
+    /// Create a new location tracker. If a state directory is specified,
+    /// the previous state is loaded from there.
+    pub fn load(state_dir: Option<&Path>) -> Result<Self> {
+        let sensors = StateFile::load(state_dir, STATE_FILE, "sensor locations")?;
+        Ok(Self { sensors })
+    }
+
+    /// Save pending changes to the state file.
+    pub fn flush(&self) {
+        self.sensors.flush();
+    }
+
+    /// Register a reported location of a sensor.
+    ///
+    /// Returns whether the location was accepted as the current location.
+    pub fn update(
+        &self,
+        dev_eui: &str,
+        location: Location,
+        source: LocationSource,
+        now: SystemTime,
+    ) -> bool {
+        // GPS receivers without a fix often report 0, 0
+        if !location.latitude.is_finite()
+            || !location.longitude.is_finite()
+            || location.latitude.abs() > 90.0
+            || location.longitude.abs() > 180.0
+            || (location.latitude == 0.0 && location.longitude == 0.0)
+        {
+            warn!(
+                "Ignoring invalid location of sensor {}: {}, {}",
+                dev_eui, location.latitude, location.longitude
+            );
+            return false;
+        }
+        let time = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut sensors = self.sensors.lock();
+        if let Some(current) = sensors.get(dev_eui) {
+            let outdated = time.saturating_sub(current.time) > MAX_AGE.as_secs();
+            if source < current.source && !outdated {
+                return false;
+            }
+            let distance = distance_m(&current.location(), &location);
+            if distance > MIN_MOVE_M {
+                info!(
+                    "Sensor {} moved by {:.1} km ({:?} location)",
+                    dev_eui,
+                    distance / 1000.0,
+                    source
+                );
+            }
+        }
+        sensors.insert(
+            dev_eui.to_string(),
+            SensorLocation {
+                latitude: location.latitude,
+                longitude: location.longitude,
+                altitude: location.altitude,
+                source,
+                time,
+            },
+        );
+        self.sensors.changed(&sensors);
+        true
+    }
+
+    /// The current location of a sensor.
+    pub fn get(&self, dev_eui: &str) -> Option<SensorLocation> {
+        self.sensors.lock().get(dev_eui).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(latitude: f64, longitude: f64) -> Location {
+        Location {
+            latitude,
+            longitude,
+            altitude: None,
+        }
+    }
+
+    fn day(d: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000 + d * 86400)
+    }
+
+    #[test]
+    fn test_parse_event() {
+        let event = LocationEvent::parse(
+            br#"{
+                "end_device_ids": {"device_id": "buoy-1", "dev_eui": "AABB"},
+                "received_at": "2024-05-01T12:00:00Z",
+                "location_solved": {
+                    "service": "lora-cloud-geolocation",
+                    "location": {
+                        "latitude": 47.2267,
+                        "longitude": 8.8184,
+                        "accuracy": 120,
+                        "source": "SOURCE_LORA_RSSI_GEOLOCATION"
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(event.dev_eui, "AABB");
+        assert_eq!(event.service, "lora-cloud-geolocation");
+        assert_eq!(event.location, location(47.2267, 8.8184));
+        assert!(LocationEvent::parse(b"{}").is_err());
+    }
+
+    #[test]
+    fn test_distance() {
+        // Rapperswil to Zurich
+        let distance = distance_m(&location(47.2267, 8.8184), &location(47.3769, 8.5417));
+        assert!((distance - 27_000.0).abs() < 1000.0, "{}", distance);
+        assert_eq!(distance_m(&location(47.0, 8.0), &location(47.0, 8.0)), 0.0);
+    }
+
+    #[test]
+    fn test_priority() {
+        let tracker = LocationTracker::load(None).unwrap();
+        let registry = location(47.2267, 8.8184);
+        let gps = location(47.2301, 8.8102);
+        assert_eq!(tracker.get("AABB"), None);
+
+        assert!(tracker.update("AABB", registry, LocationSource::Registry, day(0)));
+        assert!(tracker.update("AABB", gps, LocationSource::Payload, day(1)));
+
+        // The registered location does not replace the GPS position...
+        assert!(!tracker.update("AABB", registry, LocationSource::Registry, day(2)));
+        let current = tracker.get("AABB").unwrap();
+        assert_eq!(current.location(), gps);
+        assert_eq!(current.source, LocationSource::Payload);
+
+        // ...unless the GPS position is outdated
+        assert!(tracker.update("AABB", registry, LocationSource::Registry, day(9)));
+        assert_eq!(tracker.get("AABB").unwrap().location(), registry);
+
+        assert!(!tracker.update(
+            "AABB",
+            location(91.0, 8.0),
+            LocationSource::Payload,
+            day(10)
+        ));
+        assert!(!tracker.update(
+            "AABB",
+            location(f64::NAN, 8.0),
+            LocationSource::Payload,
+            day(10)
+        ));
+        assert!(!tracker.update("AABB", location(0.0, 0.0), LocationSource::Payload, day(10)));
+    }
+}
@@ -1,35 +1,150 @@
-use std::{collections::HashMap, path::PathBuf, time::Duration};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{bail, Context, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use drogue_ttn::v3 as ttn;
 use env_logger::Env;
 use log::{debug, error, info, warn};
 use paho_mqtt as mqtt;
 use serde_json as json;
 
+mod aggregation;
+mod airtime;
+mod alerts;
+mod api;
+mod battery;
+mod calibration;
+mod cayenne;
 mod config;
+mod coverage;
+mod framecounter;
 mod influxdb;
+mod link;
+mod location;
+mod metrics;
+mod output;
 mod payload;
+mod postgres;
+mod republish;
+mod script;
+mod sqlite;
+mod state;
+#[cfg(test)]
+mod test_support;
+mod uplink;
+mod validation;
+mod watchdog;
+mod webhook;
 
-use config::{Config, Sensor, SensorType};
-use influxdb::InfluxDbConfig;
+use airtime::AirtimeTracker;
+use alerts::{Alert, AlertKind, Alerts, Severity};
+use api::ApiOutput;
+use battery::BatteryMonitor;
+use config::{Config, Sensor};
+use coverage::CoverageTracker;
+use framecounter::FrameCounterTracker;
+use influxdb::InfluxDbOutput;
+use link::LinkAnalyzer;
+use location::{LocationEvent, LocationSource, LocationTracker};
+use metrics::Metrics;
+use output::{MeasurementMeta, Output, ReceivingGateway, Record, Worker};
+use payload::DecoderRegistry;
+use postgres::PostgresOutput;
+use republish::Republisher;
+use sqlite::SqliteStore;
+use uplink::{DataRate, UplinkDetails};
+use validation::Validator;
+use watchdog::Watchdog;
+use webhook::Webhook;
 
 #[derive(Debug, Parser)]
 struct Cli {
     /// Path to the config file
     #[clap(short, long, default_value = "config.toml")]
     config: PathBuf,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run the relay (default)
+    Run,
+    /// List all available payload decoders
+    Decoders,
+    /// Decode a raw payload and print the resulting measurement
+    Decode {
+        /// The sensor type (i.e. decoder name), e.g. "gfroerli"
+        #[clap(short, long)]
+        sensor_type: String,
+        /// The FPort on which the payload was received
+        #[clap(short, long, default_value_t = 1)]
+        fport: u16,
+        /// The payload as hex string, e.g. "0b45010500000000000000"
+        payload: String,
+    },
+    /// Query the local SQLite measurement store
+    Query {
+        /// Only show measurements of this sensor ID
+        #[clap(short, long)]
+        sensor_id: Option<u32>,
+        /// Number of recent measurements per sensor to list
+        #[clap(short, long, default_value_t = 10)]
+        limit: usize,
+        /// Start of the time range in UTC, e.g. "2024-06-01" or
+        /// "2024-06-01 12:00"
+        #[clap(long)]
+        since: Option<String>,
+        /// End of the time range in UTC (exclusive)
+        #[clap(long)]
+        until: Option<String>,
+        /// Export all measurements in the time range to a CSV file ("-" for
+        /// stdout) instead of listing the recent ones
+        #[clap(long)]
+        csv: Option<PathBuf>,
+    },
+    /// Report the gateway coverage statistics of all sensors (requires a
+    /// state directory)
+    Gateways,
 }
 
 /// Main application object.
 struct App {
     /// App configuration
     config: Config,
+    /// Payload decoders
+    decoders: DecoderRegistry,
+    /// Plausibility validation
+    validator: Validator,
+    /// Frame counter tracking
+    frame_counters: FrameCounterTracker,
+    /// Offline sensor detection
+    watchdog: Arc<Watchdog>,
+    /// Battery monitoring
+    battery_monitor: BatteryMonitor,
+    /// Gateway coverage statistics
+    coverage: CoverageTracker,
+    /// Airtime accounting
+    airtime: AirtimeTracker,
+    /// Link quality analysis
+    link_analyzer: LinkAnalyzer,
+    /// Current sensor locations
+    locations: LocationTracker,
+    /// Alert dispatching
+    alerts: Arc<Alerts>,
+    /// Enabled outputs (API, InfluxDB, republishing, webhooks), each running
+    /// on its own thread
+    outputs: Vec<Worker>,
+    /// Metrics
+    metrics: Arc<Metrics>,
     /// MQTT client
     mqtt_client: mqtt::Client,
-    /// HTTP client
-    http_client: ureq::Agent,
 }
 
 #[derive(Debug)]
@@ -41,31 +156,30 @@ struct MeasurementMessage<'a> {
     raw_payload: &'a [u8],
 }
 
-#[derive(Debug)]
-struct MeasurementMeta {
-    airtime_ms: u32,
-    spreading_factor: Option<u16>,
-    bandwidth: Option<u64>,
-    receiving_gateways: Vec<ReceivingGateway>,
-}
-
-#[derive(Debug)]
-struct ReceivingGateway {
-    name: String,
-    rssi: f64,
-    snr: Option<f64>,
-}
-
-#[derive(serde::Serialize)]
-struct ApiPayload {
-    sensor_id: u32,
-    temperature: f32,
-}
+/// Default maximum number of queued records per output.
+const DEFAULT_OUTPUT_QUEUE_SIZE: usize = 100;
 
-static SUBSCRIPTIONS: [&str; 2] = ["v3/+/devices/+/activations", "v3/+/devices/+/up"];
+static SUBSCRIPTIONS: [&str; 3] = [
+    "v3/+/devices/+/activations",
+    "v3/+/devices/+/up",
+    "v3/+/devices/+/location/solved",
+];
 
 impl App {
-    fn new(config: Config) -> Result<Self> {
+    fn new(config: Config, decoders: DecoderRegistry) -> Result<Self> {
+        // Ensure that a decoder exists for every sensor
+        for (dev_eui, sensor) in &config.sensors {
+            if !decoders.contains(&sensor.sensor_type) {
+                bail!(
+                    "Unknown sensor type for sensor {}: {}",
+                    dev_eui,
+                    sensor.sensor_type
+                );
+            }
+            calibration::validate(&sensor.calibration)
+                .with_context(|| format!("Invalid calibration for sensor {}", dev_eui))?;
+        }
+
         // MQTT client
         let mut mqtt_client = mqtt::Client::new(
             mqtt::CreateOptionsBuilder::new()
@@ -81,10 +195,79 @@ impl App {
             .timeout_write(Duration::from_secs(5))
             .build();
 
+        // Metrics
+        let metrics = Arc::new(Metrics::new());
+        if let Some(ref metrics_config) = config.metrics {
+            metrics.clone().serve(&metrics_config.listen)?;
+        }
+
+        // Frame counters
+        let frame_counters =
+            FrameCounterTracker::load(config.state.as_ref().map(|s| s.dir.as_path()))?;
+
+        // Offline sensor detection
+        let watchdog = Arc::new(Watchdog::new(&config, SystemTime::now()));
+
+        // Alerts
+        let alerts = Arc::new(Alerts::new(config.alerts.as_ref(), &http_client)?);
+
+        // Outputs
+        let on_error: output::ErrorHandler = {
+            let (alerts, metrics) = (alerts.clone(), metrics.clone());
+            Arc::new(move |record, output, error| {
+                output_failed(&alerts, &metrics, record, output, error)
+            })
+        };
+        let queue_size = config
+            .output_queue_size
+            .unwrap_or(DEFAULT_OUTPUT_QUEUE_SIZE);
+        let outputs = create_outputs(&config, &http_client)?
+            .into_iter()
+            .map(|output| Worker::spawn(output, queue_size, on_error.clone()))
+            .collect::<Result<_>>()?;
+
+        // Battery monitoring
+        let battery_monitor = BatteryMonitor::load(
+            config.battery.as_ref(),
+            config.state.as_ref().map(|s| s.dir.as_path()),
+        )?;
+
+        // Gateway coverage statistics
+        let coverage = CoverageTracker::load(
+            config.coverage.as_ref(),
+            config.state.as_ref().map(|s| s.dir.as_path()),
+        )?;
+
+        // Airtime accounting
+        let airtime = AirtimeTracker::load(
+            config.airtime.as_ref(),
+            config.state.as_ref().map(|s| s.dir.as_path()),
+        )?;
+
+        // Link quality analysis
+        let link_analyzer = LinkAnalyzer::load(
+            config.adr.as_ref(),
+            config.state.as_ref().map(|s| s.dir.as_path()),
+        )?;
+
+        // Sensor locations
+        let locations = LocationTracker::load(config.state.as_ref().map(|s| s.dir.as_path()))?;
+
         Ok(Self {
             config,
+            decoders,
+            validator: Validator::new(),
+            frame_counters,
+            watchdog,
+            battery_monitor,
+            coverage,
+            airtime,
+            link_analyzer,
+            locations,
+            alerts,
+            outputs,
+            metrics,
             mqtt_client,
-            http_client,
         })
     }
 
@@ -109,9 +292,17 @@ impl App {
                 "Connected to: '{}' with MQTT version {}",
                 conn_rsp.server_uri, conn_rsp.mqtt_version
             );
-            if !conn_rsp.session_present {
-                subscribe(&self.mqtt_client)?;
-            }
+            // Subscribe even if the broker resumed a previous session, which
+            // may lack topics that were added since (subscribing again to a
+            // topic of the session just replaces the subscription)
+            subscribe(&self.mqtt_client)?;
+        }
+
+        // Start offline sensor detection
+        if self.watchdog.is_active() {
+            self.watchdog
+                .clone()
+                .spawn(self.alerts.clone(), self.metrics.clone());
         }
 
         // Just loop on incoming messages.
@@ -119,7 +310,11 @@ impl App {
         info!("Waiting for messages...");
         for msg in rx.iter() {
             if let Some(msg) = msg {
-                if let Err(e) = self.handle_uplink(msg) {
+                if msg.topic().ends_with("/location/solved") {
+                    if let Err(e) = self.handle_location_event(&msg) {
+                        error!("Failed to handle location event: {}", e);
+                    }
+                } else if let Err(e) = self.handle_uplink(msg) {
                     error!("Failed to handle uplink: {}", e);
                 }
             } else {
@@ -135,6 +330,17 @@ impl App {
             self.mqtt_client.unsubscribe_many(&SUBSCRIPTIONS).unwrap();
             self.mqtt_client.disconnect(None).unwrap();
         }
+        info!("Waiting for outputs to finish...");
+        for worker in self.outputs {
+            worker.shutdown();
+        }
+        self.alerts.shutdown();
+        self.frame_counters.flush();
+        self.battery_monitor.flush();
+        self.coverage.flush();
+        self.airtime.flush();
+        self.link_analyzer.flush();
+        self.locations.flush();
         info!("Exiting");
 
         Ok(())
@@ -144,6 +350,7 @@ impl App {
     ///
     /// - Log metadata
     /// - Look up sensor
+    /// - Track frame counter and drop duplicates
     /// - If sensor was found, create a `MeasurementMessage` and call processing function
     fn handle_uplink(&self, msg: mqtt::Message) -> Result<()> {
         // Right now we're only interested in uplinks
@@ -153,6 +360,7 @@ impl App {
         }
         info!("Uplink received:");
         debug!("  Topic: {}", msg.topic());
+        self.metrics.inc("ttn_relay_uplinks_total", &[]);
 
         // Decode payload and print some information
         let ttn_msg = match json::from_slice::<ttn::Message>(msg.payload()) {
@@ -183,19 +391,24 @@ impl App {
             "  Airtime: {} ms",
             uplink.consumed_airtime.num_milliseconds()
         );
-        let (spreading_factor, bandwidth) =
-            if let Some(ttn::DataRate::Lora(dr)) = uplink.settings.data_rate {
-                debug!("  SF: {}", dr.spreading_factor);
-                debug!("  Bandwidth: {} Hz", dr.bandwidth);
-                (Some(dr.spreading_factor), Some(dr.bandwidth))
-            } else {
-                warn!("Non-LoRa data rate");
-                (None, None)
-            };
+        let details = UplinkDetails::parse(msg.payload());
+        match details.data_rate {
+            Some(ref data_rate) => debug!("  Data rate: {}", data_rate),
+            None => warn!("Unknown data rate"),
+        }
+        if let Some(frequency) = details.frequency {
+            debug!("  Frequency: {} Hz", frequency);
+        }
+        let spreading_factor = details
+            .data_rate
+            .as_ref()
+            .and_then(DataRate::spreading_factor);
         debug!("  Payload: {:?}", uplink.frame_payload);
         debug!("  Receiving gateways: {}", uplink.rx_metadata.len());
+        let mut rx_metadata = details.rx_metadata.into_iter();
         let mut gateways = Vec::with_capacity(uplink.rx_metadata.len());
         for (i, gateway) in uplink.rx_metadata.iter().enumerate() {
+            let details = rx_metadata.next().unwrap_or_default();
             let name = gateway
                 .gateway_ids
                 .get("gateway_id")
@@ -212,10 +425,22 @@ impl App {
             } else {
                 debug!("       SNR: ?");
             }
+            if let Some(location) = details.location {
+                debug!(
+                    "       Location: {}, {}",
+                    location.latitude, location.longitude
+                );
+            }
             gateways.push(ReceivingGateway {
                 name,
+                eui: gateway.gateway_ids.get("eui").map(String::to_string),
                 rssi: gateway.rssi,
+                channel_rssi: gateway.channel_rssi,
                 snr: gateway.snr,
+                time: details.time,
+                timestamp: details.timestamp,
+                antenna_index: details.antenna_index,
+                location: details.location,
             });
         }
 
@@ -231,15 +456,173 @@ impl App {
             }
         };
 
+        // Update last-seen time
+        if let Some(alert) = self.watchdog.seen(&dev_eui, SystemTime::now()) {
+            self.alerts.send(&alert);
+        }
+
+        // Update location (as registered or determined by TTN)
+        for (service, location) in details.locations {
+            let source = if service == "user" {
+                LocationSource::Registry
+            } else {
+                LocationSource::Network
+            };
+            self.locations
+                .update(&dev_eui, location, source, SystemTime::now());
+        }
+
+        // Track frame counter
+        let sensor_id = sensor.sensor_id.to_string();
+        let frames = match uplink.frame_counter {
+            Some(fcnt) => match self.frame_counters.update(
+                &dev_eui,
+                fcnt,
+                &uplink.frame_payload,
+                SystemTime::now(),
+            ) {
+                Some(frames) => Some(frames),
+                None => {
+                    info!(
+                        "Duplicate uplink with FCnt {} from sensor {}, ignoring",
+                        fcnt, sensor.sensor_id
+                    );
+                    self.metrics.inc(
+                        "ttn_relay_duplicate_uplinks_total",
+                        &[("sensor_id", &sensor_id)],
+                    );
+                    return Ok(());
+                }
+            },
+            None => {
+                warn!(
+                    "Uplink from sensor {} has no frame counter, not tracking it",
+                    sensor.sensor_id
+                );
+                None
+            }
+        };
+        if let Some(ref frames) = frames {
+            if frames.reset {
+                info!(
+                    "Frame counter of sensor {} was reset (FCnt {})",
+                    sensor.sensor_id, frames.fcnt
+                );
+            } else if frames.lost_frames > 0 {
+                warn!(
+                    "Lost {} frame(s) of sensor {} before FCnt {}",
+                    frames.lost_frames, sensor.sensor_id, frames.fcnt
+                );
+            }
+            self.metrics.add(
+                "ttn_relay_lost_frames_total",
+                &[("sensor_id", &sensor_id)],
+                f64::from(frames.lost_frames),
+            );
+            self.metrics.set(
+                "ttn_relay_packet_delivery_ratio",
+                &[("sensor_id", &sensor_id)],
+                frames.packet_delivery_ratio(),
+            );
+        }
+
+        // Update gateway coverage statistics
+        let coverage =
+            self.coverage
+                .update(&dev_eui, sensor.sensor_id, &gateways, SystemTime::now());
+        for gateway in &coverage.gateways {
+            let labels = [
+                ("sensor_id", sensor_id.as_str()),
+                ("gateway", &gateway.gateway),
+            ];
+            self.metrics.set(
+                "ttn_relay_gateway_reception_ratio",
+                &labels,
+                gateway.reception_ratio,
+            );
+            if let Some(rssi) = gateway.median_rssi {
+                self.metrics
+                    .set("ttn_relay_gateway_median_rssi", &labels, rssi);
+            }
+            if let Some(snr) = gateway.median_snr {
+                self.metrics
+                    .set("ttn_relay_gateway_median_snr", &labels, snr);
+            }
+            self.metrics.set(
+                "ttn_relay_gateway_last_heard_timestamp_seconds",
+                &labels,
+                gateway.last_heard as f64,
+            );
+        }
+
+        // Account airtime
+        let airtime_ms = uplink.consumed_airtime.num_milliseconds() as u32;
+        let (airtime, alerts) = self.airtime.update(
+            &dev_eui,
+            sensor.sensor_id,
+            airtime_ms,
+            spreading_factor,
+            SystemTime::now(),
+        );
+        for alert in alerts {
+            self.alerts.send(&alert);
+        }
+        self.metrics.add(
+            "ttn_relay_airtime_seconds_total",
+            &[("sensor_id", &sensor_id)],
+            f64::from(airtime_ms) / 1000.0,
+        );
+        self.metrics.set(
+            "ttn_relay_airtime_24h_seconds",
+            &[("sensor_id", &sensor_id)],
+            airtime.used_24h_ms as f64 / 1000.0,
+        );
+        self.metrics.set(
+            "ttn_relay_fair_use_ratio",
+            &[("sensor_id", &sensor_id)],
+            airtime.fair_use_ratio,
+        );
+        self.metrics.set(
+            "ttn_relay_high_spreading_factor",
+            &[("sensor_id", &sensor_id)],
+            if airtime.high_sf { 1.0 } else { 0.0 },
+        );
+
+        // Analyze link quality
+        let max_snr = gateways.iter().filter_map(|gw| gw.snr).reduce(f64::max);
+        let link = self
+            .link_analyzer
+            .update(&dev_eui, spreading_factor, max_snr);
+        if let Some(margin) = link.margin_db {
+            debug!("  Link margin: {:.1} dB", margin);
+            self.metrics.set(
+                "ttn_relay_link_margin_db",
+                &[("sensor_id", &sensor_id)],
+                margin,
+            );
+        }
+        if let Some(sf) = link.recommended_sf {
+            debug!("  Recommended SF: {}", sf);
+            self.metrics.set(
+                "ttn_relay_recommended_spreading_factor",
+                &[("sensor_id", &sensor_id)],
+                f64::from(sf),
+            );
+        }
+
         // Collect relevant information
         let measurement_message = MeasurementMessage {
             dev_eui: &dev_eui,
             sensor,
             meta: MeasurementMeta {
-                airtime_ms: uplink.consumed_airtime.num_milliseconds() as u32,
-                spreading_factor,
-                bandwidth,
+                airtime_ms,
+                airtime,
+                link,
+                data_rate: details.data_rate,
+                frequency: details.frequency,
+                timestamp: details.timestamp,
                 receiving_gateways: gateways,
+                frames,
             },
             frame_port: uplink.frame_port,
             raw_payload: &uplink.frame_payload,
@@ -248,6 +631,7 @@ impl App {
         // Process measurement
         if let Err(e) = self.process_measurement(measurement_message) {
             error!("Error while processing measurement: {}", e);
+            self.metrics.inc("ttn_relay_processing_errors_total", &[]);
         }
 
         Ok(())
@@ -256,180 +640,194 @@ impl App {
     /// Process a measurement targeted at a specific sensor.
     fn process_measurement(&self, measurement_message: MeasurementMessage) -> Result<()> {
         // Parse payload
-        let parsed_data = match measurement_message.sensor.sensor_type {
-            // Gfroerli
-            SensorType::Gfroerli if measurement_message.frame_port == 1 => {
-                payload::parse_payload_gfroerli_v1(measurement_message.raw_payload)
-                    .context("Failed to parse Gfroerli V1 payload")?
-            }
-            SensorType::Gfroerli if measurement_message.frame_port == 2 => {
-                payload::parse_payload_gfroerli_v2(measurement_message.raw_payload)
-                    .context("Failed to parse Gfroerli V2 payload")?
-            }
-            SensorType::Gfroerli => bail!(
-                "Unknown FPort for a Gfroerli sensor: {}",
-                measurement_message.frame_port
-            ),
-
-            // Dragino
-            SensorType::Dragino => payload::parse_payload_dragino(measurement_message.raw_payload)
-                .context("Failed to parse Dragino payload")?,
-        };
-        info!("Measurement: {:?}", parsed_data);
+        let mut parsed_data = self.decoders.decode(
+            &measurement_message.sensor.sensor_type,
+            measurement_message.frame_port,
+            measurement_message.raw_payload,
+        )?;
 
-        if measurement_message.sensor.send_to_api.unwrap_or(true) {
-            // Send to Gfrörli API
-            if let Err(e) = self.send_to_api(
-                measurement_message.sensor.sensor_id,
-                parsed_data.temperature_water,
-            ) {
-                warn!("Could not submit measurement to API: {:#}", e);
-            }
+        // Apply channel config (position, depth and primary channel)
+        parsed_data.apply_channels(&measurement_message.sensor.channels);
 
-            // Send to InfluxDB
-            if let Err(e) = self.send_to_influxdb(&measurement_message, &parsed_data) {
-                warn!("Could not submit measurement to InfluxDB: {:#}", e);
-            }
-        } else {
-            info!(
-                "API data submission was disabled for sensor {}",
-                measurement_message.sensor.sensor_id
+        // Apply calibration
+        calibration::apply(&measurement_message.sensor.calibration, &mut parsed_data);
+        info!("Measurement:");
+        for reading in &parsed_data.readings {
+            info!("  {}", reading);
+        }
+
+        // Reject implausible readings
+        let sensor_id = measurement_message.sensor.sensor_id.to_string();
+        let rejections = self.validator.validate(
+            measurement_message.dev_eui,
+            self.config
+                .validation
+                .rules
+                .iter()
+                .chain(&measurement_message.sensor.validation),
+            &mut parsed_data,
+            Instant::now(),
+        );
+        if let Some(location) = parsed_data.location() {
+            self.locations.update(
+                measurement_message.dev_eui,
+                location,
+                LocationSource::Payload,
+                SystemTime::now(),
+            );
+        }
+        let location = self
+            .locations
+            .get(measurement_message.dev_eui)
+            .map(|l| l.location());
+        for rejection in &rejections {
+            warn!("Rejected {}: {}", rejection.reading, rejection.reason);
+            self.metrics.inc(
+                "ttn_relay_rejected_readings_total",
+                &[
+                    ("sensor_id", &sensor_id),
+                    ("field", &rejection.reading.field_name()),
+                ],
             );
         }
+        if !rejections.is_empty() {
+            // Only outputs that accept rejected readings receive this record
+            self.deliver(Record {
+                dev_eui: measurement_message.dev_eui.to_string(),
+                sensor: measurement_message.sensor.clone(),
+                meta: measurement_message.meta.clone(),
+                measurement: payload::Measurement {
+                    readings: rejections.into_iter().map(|r| r.reading).collect(),
+                },
+                battery: None,
+                location,
+                rejected: true,
+                timestamp: SystemTime::now(),
+            });
+        }
+
+        // Monitor battery
+        let battery = parsed_data.battery_voltage().and_then(|voltage| {
+            let (status, alert) = self.battery_monitor.update(
+                measurement_message.dev_eui,
+                measurement_message.sensor.sensor_id,
+                &measurement_message.sensor.sensor_type,
+                voltage,
+                SystemTime::now(),
+            )?;
+            if let Some(alert) = alert {
+                self.alerts.send(&alert);
+            }
+            if let Some(days) = status.days_remaining {
+                self.metrics.set(
+                    "ttn_relay_battery_days_remaining",
+                    &[("sensor_id", &sensor_id)],
+                    days,
+                );
+            }
+            Some(status)
+        });
+
+        self.metrics
+            .inc("ttn_relay_measurements_total", &[("sensor_id", &sensor_id)]);
+        self.metrics.set(
+            "ttn_relay_last_measurement_timestamp_seconds",
+            &[("sensor_id", &sensor_id)],
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+        );
+
+        // Deliver to outputs
+        self.deliver(Record {
+            dev_eui: measurement_message.dev_eui.to_string(),
+            sensor: measurement_message.sensor.clone(),
+            meta: measurement_message.meta,
+            measurement: parsed_data,
+            battery,
+            location,
+            rejected: false,
+            timestamp: SystemTime::now(),
+        });
 
         info!("Processing done!");
         Ok(())
     }
 
-    /// Send a measurement to the Gfrörli API server.
-    fn send_to_api(&self, sensor_id: u32, temperature: f32) -> Result<()> {
-        if temperature <= 0.0 {
-            warn!("Temperature is at or below °C, not sending to API");
-            return Ok(());
-        }
-
-        let url = format!("{}/measurements", self.config.api.base_url);
-        let authorization = format!("Bearer {}", self.config.api.api_token);
-        info!("Sending temperature {:.2}°C to API...", temperature);
-        let response = self
-            .http_client
-            .post(&url)
-            .set("authorization", &authorization)
-            .send_json(&ApiPayload {
-                sensor_id,
-                temperature,
-            })
-            .context("API request failed")?;
-        if response.status() == 201 {
-            debug!("API request succeeded");
-            Ok(())
-        } else {
-            bail!(
-                "API request failed: HTTP {} ({})",
-                response.status(),
-                response.status_text()
+    /// Handle a TTN location solved event.
+    fn handle_location_event(&self, msg: &mqtt::Message) -> Result<()> {
+        let event = LocationEvent::parse(msg.payload())?;
+        info!(
+            "Location of {} solved by {}: {}, {}",
+            event.dev_eui, event.service, event.location.latitude, event.location.longitude
+        );
+        if !self.config.sensors.contains_key(&event.dev_eui) {
+            warn!(
+                "Sensor with DevEUI {} not found in config, ignoring location",
+                event.dev_eui
             );
+            return Ok(());
         }
+        self.locations.update(
+            &event.dev_eui,
+            event.location,
+            LocationSource::Network,
+            SystemTime::now(),
+        );
+        Ok(())
     }
 
-    /// Send a measurement to InfluxDB.
-    fn send_to_influxdb(
-        &self,
-        measurement_message: &MeasurementMessage,
-        measurement: &payload::Measurement,
-    ) -> Result<()> {
-        let config: Option<InfluxDbConfig> = if let Some(ref v2) = self.config.influxdb2 {
-            Some(InfluxDbConfig::V2(v2))
-        } else if let Some(ref v1) = self.config.influxdb {
-            Some(InfluxDbConfig::V1(v1))
-        } else {
-            None
-        };
-        if let Some(config) = config {
-            info!("Logging measurement to InfluxDB...");
-
-            // Note:
-            // - Tags can be used for filtering and grouping.
-            // - Value fields can be visualized directly.
-            let mut tags = HashMap::new();
-            let mut fields = HashMap::new();
-
-            // Sensor info
-            tags.insert(
-                "sensor_id",
-                measurement_message.sensor.sensor_id.to_string(),
-            );
-            tags.insert("sensor_dev_eui", measurement_message.dev_eui.to_string());
-            tags.insert(
-                "sensor_type",
-                measurement_message.sensor.sensor_type.to_string(),
-            );
-
-            // Spreading factor and bandwidth
-            if let Some(sf) = measurement_message.meta.spreading_factor {
-                tags.insert("sf", sf.to_string());
-                fields.insert("sf", format!("{}i", sf));
-            }
-            if let Some(bw) = measurement_message.meta.bandwidth {
-                tags.insert("bw", bw.to_string());
-                fields.insert("bw", format!("{}i", bw));
+    /// Queue a record for delivery to all outputs that accept it and are
+    /// enabled for the sensor.
+    fn deliver(&self, record: Record) {
+        let record = Arc::new(record);
+        for output in &self.outputs {
+            if !output.accepts(&record) {
+                continue;
             }
-            fields.insert(
-                "airtime_ms",
-                format!("{}i", measurement_message.meta.airtime_ms),
-            );
-
-            // Measurements
-            fields.insert(
-                "water_temp",
-                format!("{:.2}", measurement.temperature_water),
-            );
-            if let Some(temp) = measurement.temperature_enclosure {
-                fields.insert("enclosure_temp", format!("{:.2}", temp));
-            }
-            if let Some(humi) = measurement.humidity_enclosure {
-                fields.insert("enclosure_humi", format!("{:.2}", humi));
+            if !output::is_enabled(&record.sensor, output.name()) {
+                debug!(
+                    "Output {} is disabled for sensor {}",
+                    output.name(),
+                    record.sensor.sensor_id
+                );
+                continue;
             }
-            fields.insert(
-                "voltage",
-                format!("{:.3}", (measurement.battery_millivolts as f32) / 1000.0),
-            );
-
-            // Gateway(s)
-            fields.insert(
-                "receiving_gateway_count",
-                format!("{}i", measurement_message.meta.receiving_gateways.len()),
-            );
-            if !measurement_message.meta.receiving_gateways.is_empty() {
-                if let Some(gw) = measurement_message
-                    .meta
-                    .receiving_gateways
-                    .iter()
-                    .max_by(|a, b| a.rssi.total_cmp(&b.rssi))
-                {
-                    tags.insert("max_rssi_gateway", format!("\"{}\"", gw.name));
-                    fields.insert("max_rssi", gw.rssi.to_string());
-                }
-                if let Some((gw, snr)) = measurement_message
-                    .meta
-                    .receiving_gateways
-                    .iter()
-                    .filter_map(|gw| gw.snr.map(|snr| (gw, snr)))
-                    .max_by(|a, b| a.1.total_cmp(&b.1))
-                {
-                    tags.insert("max_snr_gateway", format!("\"{}\"", gw.name));
-                    fields.insert("max_snr", snr.to_string());
-                }
+            if !output.submit(record.clone()) {
+                warn!(
+                    "Queue of output {} is full, dropping measurement of sensor {}",
+                    output.name(),
+                    record.sensor.sensor_id
+                );
+                self.metrics.inc(
+                    "ttn_relay_output_dropped_total",
+                    &[("output", output.name())],
+                );
             }
-
-            influxdb::submit_measurement(self.http_client.clone(), config, &tags, &fields)
-                .context("InfluxDB request failed")?;
-            debug!("InfluxDB request succeeded");
         }
-        Ok(())
     }
 }
 
+/// Log and raise an alert for a failed measurement submission.
+fn output_failed(
+    alerts: &Alerts,
+    metrics: &Metrics,
+    record: &Record,
+    output: &str,
+    error: &anyhow::Error,
+) {
+    warn!("Could not submit measurement to {}: {:#}", output, error);
+    metrics.inc("ttn_relay_output_errors_total", &[("output", output)]);
+    alerts.send(&Alert {
+        kind: AlertKind::OutputFailed(output.to_string()),
+        severity: Severity::Warning,
+        sensor_id: record.sensor.sensor_id,
+        dev_eui: record.dev_eui.clone(),
+        message: format!("Could not submit measurement to {}: {:#}", output, error),
+    });
+}
+
 fn main() -> Result<()> {
     env_logger::Builder::from_env(Env::default().default_filter_or("warn,ttn_relay=debug")).init();
 
@@ -437,35 +835,329 @@ fn main() -> Result<()> {
 
     // Parse args
     let cli = Cli::parse();
+    match cli.command {
+        Some(Command::Decoders) => {
+            // The config is optional here, it only provides scripted decoders
+            let config = read_optional_config(&cli.config)?;
+            let decoders = load_decoders(config.as_ref(), &cli.config)?;
+            return print_decoders(&decoders);
+        }
+        Some(Command::Decode {
+            sensor_type,
+            fport,
+            payload,
+        }) => {
+            let config = read_optional_config(&cli.config)?;
+            let decoders = load_decoders(config.as_ref(), &cli.config)?;
+            return decode_payload(&decoders, &sensor_type, fport, &payload);
+        }
+        Some(Command::Query {
+            sensor_id,
+            limit,
+            since,
+            until,
+            csv,
+        }) => {
+            let config = Config::from_file(&cli.config)?;
+            let query = sqlite::Query {
+                sensor_id,
+                since,
+                until,
+            };
+            return query_store(&config, &query, limit, csv.as_deref());
+        }
+        Some(Command::Gateways) => {
+            let config = Config::from_file(&cli.config)?;
+            return print_gateways(&config);
+        }
+        Some(Command::Run) | None => {}
+    }
 
     // Read config
     debug!("Reading config from {:?}", &cli.config);
     let config = Config::from_file(&cli.config)?;
+    let decoders = load_decoders(Some(&config), &cli.config)?;
     info!("Configured sensors:");
     for (dev_eui, sensor) in &config.sensors {
         info!(
-            "  {} → {} ({:?})",
+            "  {} → {} ({})",
             dev_eui, sensor.sensor_id, sensor.sensor_type
         )
     }
 
     // Instantiate App
-    let app = App::new(config)?;
+    let app = App::new(config, decoders)?;
     app.run()
 }
 
-/// Subscribe to activations and uplinks.
+/// Create all configured outputs.
+///
+/// If the config contains a list of enabled outputs, only those are created.
+/// Unknown output names (globally or in the per-sensor lists) are rejected.
+fn create_outputs(config: &Config, http_client: &ureq::Agent) -> Result<Vec<Box<dyn Output>>> {
+    // Determine the names of all configured outputs
+    let mut available = vec!["api".to_string()];
+    if config.influxdb2.is_some() || config.influxdb.is_some() {
+        available.push("influxdb".to_string());
+    }
+    if config.republish.is_some() {
+        available.push("mqtt".to_string());
+    }
+    if config.sqlite.is_some() {
+        available.push("sqlite".to_string());
+    }
+    if config.postgres.is_some() {
+        available.push("postgres".to_string());
+    }
+    for webhook in &config.webhooks {
+        available.push(format!("webhook:{}", webhook.name));
+    }
+    let mut seen = HashSet::new();
+    for name in &available {
+        if !seen.insert(name) {
+            bail!("Output {} is configured more than once", name);
+        }
+    }
+    let check = |names: &[String], context: &str| -> Result<()> {
+        for name in names {
+            if !available.contains(name) {
+                bail!("Unknown output in {}: {}", context, name);
+            }
+        }
+        Ok(())
+    };
+    check(config.outputs.as_deref().unwrap_or_default(), "outputs")?;
+    for (dev_eui, sensor) in &config.sensors {
+        let context = format!("config of sensor {}", dev_eui);
+        check(
+            sensor.include_outputs.as_deref().unwrap_or_default(),
+            &context,
+        )?;
+        check(&sensor.exclude_outputs, &context)?;
+    }
+    let enabled = |name: &str| {
+        config
+            .outputs
+            .as_ref()
+            .is_none_or(|outputs| outputs.iter().any(|o| o == name))
+    };
+
+    // Create the enabled outputs
+    let mut outputs: Vec<Box<dyn Output>> = Vec::new();
+    if enabled("api") {
+        outputs.push(Box::new(ApiOutput::new(&config.api, http_client)?));
+    }
+    if enabled("influxdb") {
+        if let Some(output) = InfluxDbOutput::new(config, http_client) {
+            outputs.push(Box::new(output));
+        }
+    }
+    if let Some(ref republish) = config.republish {
+        if enabled("mqtt") {
+            outputs.push(Box::new(Republisher::connect(republish)?));
+        }
+    }
+    if let Some(ref sqlite) = config.sqlite {
+        if enabled("sqlite") {
+            outputs.push(Box::new(SqliteStore::open(&sqlite.path)?));
+        }
+    }
+    if let Some(ref postgres) = config.postgres {
+        if enabled("postgres") {
+            outputs.push(Box::new(PostgresOutput::new(postgres)?));
+        }
+    }
+    for webhook in &config.webhooks {
+        if enabled(&format!("webhook:{}", webhook.name)) {
+            outputs.push(Box::new(Webhook::new(webhook, http_client)));
+        }
+    }
+    info!(
+        "Enabled outputs: {}",
+        outputs
+            .iter()
+            .map(|o| o.name())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    Ok(outputs)
+}
+
+/// Read the config file if it exists.
+fn read_optional_config(config_path: &Path) -> Result<Option<Config>> {
+    if config_path.exists() {
+        Config::from_file(config_path).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Create a registry with the built-in decoders and the scripted decoders
+/// defined in the config.
+fn load_decoders(config: Option<&Config>, config_path: &Path) -> Result<DecoderRegistry> {
+    let mut decoders = DecoderRegistry::with_builtin_decoders();
+    let base_dir = config_path.parent().unwrap_or_else(|| Path::new("."));
+    for (name, decoder_config) in config.iter().flat_map(|c| &c.decoders) {
+        if decoders.contains(name) {
+            bail!(
+                "Scripted decoder {} conflicts with a built-in decoder",
+                name
+            );
+        }
+        let decoder = script::ScriptDecoder::load(name, decoder_config, base_dir)?;
+        debug!("Loaded scripted decoder {}", name);
+        decoders.register(name, None, decoder);
+    }
+    Ok(decoders)
+}
+
+/// Print all registered payload decoders.
+fn print_decoders(decoders: &DecoderRegistry) -> Result<()> {
+    for (name, fport, label) in decoders.entries() {
+        match fport {
+            Some(fport) => println!("{:<12} FPort {:<5} {}", name, fport, label),
+            None => println!("{:<12} any FPort  {}", name, label),
+        }
+    }
+    Ok(())
+}
+
+/// Decode a hex encoded payload and print the resulting measurement.
+fn decode_payload(
+    decoders: &DecoderRegistry,
+    sensor_type: &str,
+    fport: u16,
+    payload: &str,
+) -> Result<()> {
+    let raw_payload = parse_hex(payload).context("Invalid hex payload")?;
+    let measurement = decoders.decode(sensor_type, fport, &raw_payload)?;
+    for reading in &measurement.readings {
+        println!("{}", reading);
+    }
+    Ok(())
+}
+
+/// List recent measurements from the SQLite store, or export them as CSV.
+fn query_store(
+    config: &Config,
+    query: &sqlite::Query,
+    limit: usize,
+    csv: Option<&Path>,
+) -> Result<()> {
+    let Some(ref sqlite_config) = config.sqlite else {
+        bail!("No SQLite store configured");
+    };
+    let store = SqliteStore::open(&sqlite_config.path)?;
+    match csv {
+        Some(path) if path == Path::new("-") => {
+            store.export_csv(query, &mut std::io::stdout().lock())?;
+        }
+        Some(path) => {
+            let mut file = std::io::BufWriter::new(
+                std::fs::File::create(path)
+                    .with_context(|| format!("Could not create {:?}", path))?,
+            );
+            let count = store.export_csv(query, &mut file)?;
+            println!("Exported {} measurements to {:?}", count, path);
+        }
+        None => {
+            for measurement in store.recent(query, limit)? {
+                let readings = measurement
+                    .readings
+                    .iter()
+                    .map(|(field, value, unit)| format!("{}={}{}", field, value, unit))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                println!(
+                    "{}  sensor {:<5} {:<12} FCnt {:<6} SF{:<3} {}{}",
+                    measurement.time,
+                    measurement.sensor_id,
+                    measurement.sensor_type,
+                    measurement
+                        .fcnt
+                        .map_or("?".to_string(), |fcnt| fcnt.to_string()),
+                    measurement
+                        .spreading_factor
+                        .map_or("?".to_string(), |sf| sf.to_string()),
+                    readings,
+                    if measurement.rejected {
+                        " (rejected)"
+                    } else {
+                        ""
+                    },
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Print the gateway coverage statistics persisted by the relay.
+fn print_gateways(config: &Config) -> Result<()> {
+    let Some(ref state) = config.state else {
+        bail!("Gateway statistics are only available with a state directory");
+    };
+    let coverage = CoverageTracker::load(config.coverage.as_ref(), Some(&state.dir))?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let window_days = coverage.window().as_secs() / 86400;
+    for sensor in coverage.report() {
+        println!(
+            "Sensor {} ({}): {} uplinks in the last {} days",
+            sensor.sensor_id, sensor.dev_eui, sensor.uplinks, window_days
+        );
+        for gateway in &sensor.gateways {
+            let format =
+                |value: Option<f64>| value.map_or("?".to_string(), |v| format!("{:.1}", v));
+            println!(
+                "  {:<24} {:>6.1}%  RSSI {:>6}  SNR {:>5}  last heard {} ago",
+                gateway.gateway,
+                gateway.reception_ratio * 100.0,
+                format(gateway.median_rssi),
+                format(gateway.median_snr),
+                watchdog::format_duration(Duration::from_secs(
+                    now.saturating_sub(gateway.last_heard)
+                )),
+            );
+        }
+        if sensor.active_gateways() == 1 {
+            println!("  Warning: only heard by a single gateway");
+        }
+    }
+    Ok(())
+}
+
+/// Parse a hex string (whitespace is ignored).
+fn parse_hex(hex: &str) -> Result<Vec<u8>> {
+    let digits = hex
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<Vec<char>>();
+    if digits.len() % 2 != 0 {
+        bail!("Odd number of hex digits");
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let byte = pair.iter().collect::<String>();
+            u8::from_str_radix(&byte, 16).with_context(|| format!("Invalid hex byte: {}", byte))
+        })
+        .collect()
+}
+
+/// Subscribe to activations, uplinks and location events.
 fn subscribe(client: &mqtt::Client) -> Result<()> {
-    let qos = [1, 1];
+    let qos = [1; SUBSCRIPTIONS.len()];
 
     // Register subscriptions on the server
     debug!("Subscribing to topics, with requested QoS: {:?}", qos);
 
     let qosv = client
         .subscribe_many(&SUBSCRIPTIONS, &qos)
-        .map_err(|e| {
+        .inspect_err(|_| {
             client.disconnect(None).unwrap();
-            e
         })
         .context("Error subscribing to topics")?;
     debug!("QoS granted: {}", qosv.reason_code());
@@ -0,0 +1,212 @@
+//! Simple in-process metrics, exposed in the Prometheus text format.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+
+/// Timeout for reading the request and writing the response.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+/// A metric name together with its labels.
+type Key = (&'static str, Vec<(&'static str, String)>);
+
+/// A collection of counters and gauges.
+#[derive(Default)]
+pub struct Metrics {
+    values: Mutex<BTreeMap<Key, (Kind, f64)>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn key(name: &'static str, labels: &[(&'static str, &str)]) -> Key {
+        (
+            name,
+            labels.iter().map(|(k, v)| (*k, v.to_string())).collect(),
+        )
+    }
+
+    /// Increment a counter by one.
+    pub fn inc(&self, name: &'static str, labels: &[(&'static str, &str)]) {
+        self.add(name, labels, 1.0);
+    }
+
+    /// Increment a counter by the specified value.
+    pub fn add(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        let mut values = self.values.lock().unwrap();
+        values
+            .entry(Self::key(name, labels))
+            .or_insert((Kind::Counter, 0.0))
+            .1 += value;
+    }
+
+    /// Set a gauge to the specified value.
+    pub fn set(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        let mut values = self.values.lock().unwrap();
+        values.insert(Self::key(name, labels), (Kind::Gauge, value));
+    }
+
+    /// Return the current value of a metric (if it exists).
+    #[cfg(test)]
+    pub fn get(&self, name: &'static str, labels: &[(&'static str, &str)]) -> Option<f64> {
+        let values = self.values.lock().unwrap();
+        values.get(&Self::key(name, labels)).map(|(_, v)| *v)
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let values = self.values.lock().unwrap();
+        let mut output = String::new();
+        let mut previous_name = None;
+        for ((name, labels), (kind, value)) in values.iter() {
+            if previous_name != Some(name) {
+                let kind = match kind {
+                    Kind::Counter => "counter",
+                    Kind::Gauge => "gauge",
+                };
+                writeln!(output, "# TYPE {} {}", name, kind).unwrap();
+                previous_name = Some(name);
+            }
+            output.push_str(name);
+            if !labels.is_empty() {
+                let labels = labels
+                    .iter()
+                    .map(|(k, v)| {
+                        format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\""))
+                    })
+                    .collect::<Vec<_>>()
+                    .join(",");
+                write!(output, "{{{}}}", labels).unwrap();
+            }
+            writeln!(output, " {}", value).unwrap();
+        }
+        output
+    }
+
+    /// Serve the metrics via HTTP on the specified address in a background
+    /// thread.
+    pub fn serve(self: Arc<Self>, listen: &str) -> Result<()> {
+        let listener = TcpListener::bind(listen)
+            .with_context(|| format!("Could not bind metrics listener to {}", listen))?;
+        info!("Serving metrics on http://{}/metrics", listen);
+        self.spawn_server(listener);
+        Ok(())
+    }
+
+    /// Accept connections in a background thread. Every connection is
+    /// handled on its own thread, so that a slow client does not block the
+    /// others.
+    fn spawn_server(self: Arc<Self>, listener: TcpListener) {
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("Metrics connection failed: {}", e);
+                        continue;
+                    }
+                };
+                let metrics = self.clone();
+                thread::spawn(move || metrics.respond(stream));
+            }
+        });
+    }
+
+    /// Answer a single HTTP request with the metrics.
+    fn respond(&self, mut stream: TcpStream) {
+        if let Err(e) = stream
+            .set_read_timeout(Some(CONNECTION_TIMEOUT))
+            .and_then(|_| stream.set_write_timeout(Some(CONNECTION_TIMEOUT)))
+        {
+            warn!("Could not set metrics connection timeout: {}", e);
+            return;
+        }
+        // We serve the metrics for every request, so the request itself can
+        // be ignored.
+        let mut buf = [0; 1024];
+        let _ = stream.read(&mut buf);
+        let body = self.render();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        if let Err(e) = stream.write_all(response.as_bytes()) {
+            debug!("Could not write metrics response: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.inc("uplinks_total", &[]);
+        metrics.inc("uplinks_total", &[]);
+        metrics.inc(
+            "rejected_total",
+            &[("sensor_id", "1"), ("quantity", "temperature")],
+        );
+        metrics.set("battery_volts", &[("sensor_id", "1")], 3.3);
+        metrics.set("battery_volts", &[("sensor_id", "2")], 2.9);
+        assert_eq!(
+            metrics.render(),
+            "# TYPE battery_volts gauge\n\
+             battery_volts{sensor_id=\"1\"} 3.3\n\
+             battery_volts{sensor_id=\"2\"} 2.9\n\
+             # TYPE rejected_total counter\n\
+             rejected_total{sensor_id=\"1\",quantity=\"temperature\"} 1\n\
+             # TYPE uplinks_total counter\n\
+             uplinks_total 2\n"
+        );
+        assert_eq!(metrics.get("uplinks_total", &[]), Some(2.0));
+    }
+
+    #[test]
+    fn test_idle_client() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.inc("ttn_relay_uplinks_total", &[]);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        metrics.spawn_server(listener);
+
+        // An idle client does not block other clients
+        let _idle = TcpStream::connect(addr).unwrap();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        client.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(
+            response.ends_with("ttn_relay_uplinks_total 1\n"),
+            "{}",
+            response
+        );
+    }
+}
@@ -0,0 +1,582 @@
+//! Outputs to which processed measurements are delivered.
+//!
+//! Every sink (the Gfrörli API, InfluxDB, the local MQTT broker and the HTTP
+//! webhooks) implements the [`Output`] trait. Outputs can be enabled globally
+//! (`outputs` in the config) and per sensor (`include_outputs` and
+//! `exclude_outputs`), and every output fails independently of the others.
+//!
+//! Every output runs on its own [`Worker`] thread with a bounded queue, so
+//! that a slow output stalls neither the reception of uplinks nor the other
+//! outputs. If the queue of an output is full, new records for this output
+//! are dropped. Outputs that hold back records (e.g. to aggregate them over
+//! a time window) are ticked periodically on their worker thread.
+
+use std::{
+    sync::{
+        mpsc::{self, RecvTimeoutError, SyncSender, TrySendError},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, error};
+
+use crate::{
+    airtime::AirtimeStats,
+    battery::BatteryStatus,
+    config::Sensor,
+    framecounter::FrameStats,
+    link::LinkQuality,
+    payload::Measurement,
+    uplink::{DataRate, Location},
+};
+
+/// Radio and frame metadata of an uplink.
+#[derive(Debug, Clone)]
+pub struct MeasurementMeta {
+    pub airtime_ms: u32,
+    /// Airtime usage of the device within the last 24 hours
+    pub airtime: AirtimeStats,
+    /// Link margin and spreading factor recommendation
+    pub link: LinkQuality,
+    pub data_rate: Option<DataRate>,
+    /// Frequency in Hz
+    pub frequency: Option<u64>,
+    /// Concentrator timestamp of the first receiving gateway in microseconds
+    pub timestamp: Option<u32>,
+    pub receiving_gateways: Vec<ReceivingGateway>,
+    /// Frame counter statistics (`None` if the uplink has no frame counter)
+    pub frames: Option<FrameStats>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReceivingGateway {
+    /// The gateway ID (or EUI, if the ID is unknown)
+    pub name: String,
+    pub eui: Option<String>,
+    pub rssi: f64,
+    pub channel_rssi: f64,
+    pub snr: Option<f64>,
+    /// Reception time according to the gateway (RFC 3339)
+    pub time: Option<String>,
+    /// Concentrator timestamp in microseconds
+    pub timestamp: Option<u32>,
+    pub antenna_index: u32,
+    pub location: Option<Location>,
+}
+
+impl MeasurementMeta {
+    /// The spreading factor (LoRa only).
+    pub fn spreading_factor(&self) -> Option<u16> {
+        self.data_rate.as_ref().and_then(DataRate::spreading_factor)
+    }
+
+    /// The bandwidth in Hz (LoRa only).
+    pub fn bandwidth(&self) -> Option<u64> {
+        self.data_rate.as_ref().and_then(DataRate::bandwidth)
+    }
+
+    /// The gateway with the highest RSSI.
+    pub fn max_rssi_gateway(&self) -> Option<&ReceivingGateway> {
+        self.receiving_gateways
+            .iter()
+            .max_by(|a, b| a.rssi.total_cmp(&b.rssi))
+    }
+
+    /// The gateway with the highest SNR, together with the SNR.
+    pub fn max_snr_gateway(&self) -> Option<(&ReceivingGateway, f64)> {
+        self.receiving_gateways
+            .iter()
+            .filter_map(|gw| gw.snr.map(|snr| (gw, snr)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+    }
+}
+
+/// A processed measurement, ready to be delivered to the outputs.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub dev_eui: String,
+    pub sensor: Sensor,
+    pub meta: MeasurementMeta,
+    pub measurement: Measurement,
+    /// The battery status (if battery monitoring is configured)
+    pub battery: Option<BatteryStatus>,
+    /// The current location of the sensor (if known)
+    pub location: Option<Location>,
+    /// Whether the record contains readings that were rejected by the
+    /// validation
+    pub rejected: bool,
+    /// Time at which the uplink was processed
+    pub timestamp: SystemTime,
+}
+
+/// A sink for processed measurements.
+pub trait Output: Send + Sync {
+    /// The name of the output, as used in the `outputs`, `include_outputs`
+    /// and `exclude_outputs` config lists.
+    fn name(&self) -> &str;
+
+    /// Return whether this output wants to receive the record. By default,
+    /// records with rejected readings are ignored.
+    fn accepts(&self, record: &Record) -> bool {
+        !record.rejected
+    }
+
+    /// Deliver a record.
+    fn send(&self, record: &Record) -> Result<()>;
+
+    /// The maximum number of queued records that are delivered at once
+    /// using [`Output::send_batch`] (default 1, i.e. no batching).
+    fn max_batch_size(&self) -> usize {
+        1
+    }
+
+    /// Deliver multiple records at once. By default, the records are sent
+    /// one by one.
+    fn send_batch(&self, records: &[Arc<Record>]) -> Result<()> {
+        records.iter().try_for_each(|record| self.send(record))
+    }
+
+    /// The interval at which [`Output::tick`] is called (default: never).
+    fn tick_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Deliver held back records that are due at the specified time.
+    fn tick(&self, _now: SystemTime) -> Failures {
+        Vec::new()
+    }
+
+    /// Deliver all held back records. Called when the worker stops.
+    fn flush(&self) -> Failures {
+        Vec::new()
+    }
+}
+
+/// Records that could not be delivered by [`Output::tick`] or
+/// [`Output::flush`], with the reason.
+pub type Failures = Vec<(Record, anyhow::Error)>;
+
+/// Return whether the output with the specified name is enabled for a sensor.
+pub fn is_enabled(sensor: &Sensor, output: &str) -> bool {
+    if output == "api" && sensor.send_to_api == Some(false) {
+        return false;
+    }
+    if let Some(ref include) = sensor.include_outputs {
+        if !include.iter().any(|name| name == output) {
+            return false;
+        }
+    }
+    !sensor.exclude_outputs.iter().any(|name| name == output)
+}
+
+/// Called on a worker thread when an output fails to deliver a record.
+pub type ErrorHandler = Arc<dyn Fn(&Record, &str, &anyhow::Error) + Send + Sync>;
+
+/// An output running on its own thread, fed through a bounded queue.
+pub struct Worker {
+    output: Arc<dyn Output>,
+    sender: SyncSender<Arc<Record>>,
+    thread: JoinHandle<()>,
+}
+
+impl Worker {
+    /// Start a worker thread for an output. At most `queue_size` records are
+    /// queued.
+    pub fn spawn(
+        output: Box<dyn Output>,
+        queue_size: usize,
+        on_error: ErrorHandler,
+    ) -> Result<Self> {
+        let output: Arc<dyn Output> = Arc::from(output);
+        let (sender, receiver) = mpsc::sync_channel::<Arc<Record>>(queue_size);
+        let thread = thread::Builder::new()
+            .name(format!("output-{}", output.name()))
+            .spawn({
+                let output = output.clone();
+                move || {
+                    let max_batch_size = output.max_batch_size().max(1);
+                    let tick_interval = output.tick_interval();
+                    let mut next_tick = tick_interval.map(|interval| Instant::now() + interval);
+                    let report = |failures: Failures| {
+                        for (record, e) in failures {
+                            on_error(&record, output.name(), &e);
+                        }
+                    };
+                    loop {
+                        if let (Some(interval), Some(tick)) = (tick_interval, next_tick) {
+                            if tick <= Instant::now() {
+                                report(output.tick(SystemTime::now()));
+                                next_tick = Some(Instant::now() + interval);
+                            }
+                        }
+                        let record = match next_tick {
+                            Some(tick) => match receiver
+                                .recv_timeout(tick.saturating_duration_since(Instant::now()))
+                            {
+                                Ok(record) => record,
+                                Err(RecvTimeoutError::Timeout) => continue,
+                                Err(RecvTimeoutError::Disconnected) => break,
+                            },
+                            None => match receiver.recv() {
+                                Ok(record) => record,
+                                Err(_) => break,
+                            },
+                        };
+                        if max_batch_size == 1 {
+                            if let Err(e) = output.send(&record) {
+                                on_error(&record, output.name(), &e);
+                            }
+                            continue;
+                        }
+
+                        // Deliver all records that are already queued at once
+                        let mut batch = vec![record];
+                        batch.extend(receiver.try_iter().take(max_batch_size - 1));
+                        if let Err(e) = output.send_batch(&batch) {
+                            for record in &batch {
+                                on_error(record, output.name(), &e);
+                            }
+                        }
+                    }
+                    report(output.flush());
+                    debug!("Output {} stopped", output.name());
+                }
+            })
+            .context("Could not start output thread")?;
+        Ok(Self {
+            output,
+            sender,
+            thread,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        self.output.name()
+    }
+
+    /// Return whether the output wants to receive the record.
+    pub fn accepts(&self, record: &Record) -> bool {
+        self.output.accepts(record)
+    }
+
+    /// Queue a record for delivery. Returns `false` if the queue is full and
+    /// the record was dropped.
+    pub fn submit(&self, record: Arc<Record>) -> bool {
+        match self.sender.try_send(record) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => false,
+            Err(TrySendError::Disconnected(_)) => {
+                error!("Output thread {} is not running", self.name());
+                false
+            }
+        }
+    }
+
+    /// Deliver the queued and held back records and stop the worker thread.
+    pub fn shutdown(self) {
+        let Self { sender, thread, .. } = self;
+        drop(sender);
+        if thread.join().is_err() {
+            error!("Output thread panicked");
+        }
+    }
+}
+
+#[cfg(test)]
+impl Record {
+    /// Create a record for tests.
+    pub fn example(sensor_id: u32, measurement: Measurement) -> Self {
+        Self {
+            dev_eui: "AABB".to_string(),
+            sensor: toml::from_str(&format!(
+                "sensor_type = \"gfroerli\"\nsensor_id = {}",
+                sensor_id
+            ))
+            .unwrap(),
+            meta: MeasurementMeta {
+                airtime_ms: 61,
+                airtime: AirtimeStats {
+                    used_24h_ms: 6000,
+                    fair_use_ratio: 0.2,
+                    high_sf: false,
+                },
+                link: LinkQuality {
+                    margin_db: Some(4.0),
+                    recommended_sf: Some(7),
+                    adr_stuck: false,
+                },
+                data_rate: Some(DataRate::Lora {
+                    spreading_factor: 7,
+                    bandwidth: 125000,
+                    coding_rate: Some("4/5".to_string()),
+                }),
+                frequency: Some(868100000),
+                timestamp: Some(1234500),
+                receiving_gateways: vec![
+                    ReceivingGateway {
+                        name: "gw-1".to_string(),
+                        eui: Some("B827EBFFFE000001".to_string()),
+                        rssi: -110.0,
+                        channel_rssi: -111.0,
+                        snr: Some(-3.5),
+                        time: Some("2023-11-14T22:13:20.123Z".to_string()),
+                        timestamp: Some(1234567),
+                        antenna_index: 0,
+                        location: Some(Location {
+                            latitude: 47.2,
+                            longitude: 8.8,
+                            altitude: Some(410.0),
+                        }),
+                    },
+                    ReceivingGateway {
+                        name: "gw-2".to_string(),
+                        eui: None,
+                        rssi: -95.0,
+                        channel_rssi: -95.0,
+                        snr: None,
+                        time: None,
+                        timestamp: None,
+                        antenna_index: 1,
+                        location: None,
+                    },
+                ],
+                frames: Some(FrameStats {
+                    fcnt: 7,
+                    lost_frames: 1,
+                    reset: false,
+                    received_total: 7,
+                    lost_total: 1,
+                }),
+            },
+            measurement,
+            battery: None,
+            location: None,
+            rejected: false,
+            timestamp: SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    fn sensor(config: &str) -> Sensor {
+        toml::from_str(&format!(
+            "sensor_type = \"gfroerli\"\nsensor_id = 1\n{}",
+            config
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_is_enabled() {
+        let all = sensor("");
+        assert!(is_enabled(&all, "api"));
+        assert!(is_enabled(&all, "webhook:test"));
+
+        let no_api = sensor("send_to_api = false");
+        assert!(!is_enabled(&no_api, "api"));
+        assert!(is_enabled(&no_api, "influxdb"));
+
+        let include = sensor("include_outputs = [\"influxdb\", \"mqtt\"]");
+        assert!(!is_enabled(&include, "api"));
+        assert!(is_enabled(&include, "mqtt"));
+
+        let exclude = sensor("exclude_outputs = [\"mqtt\"]");
+        assert!(is_enabled(&exclude, "api"));
+        assert!(!is_enabled(&exclude, "mqtt"));
+    }
+
+    /// An output that signals the start of every delivery (with the sensor
+    /// IDs of the records) and blocks until it is released.
+    struct SlowOutput {
+        max_batch_size: usize,
+        started: Mutex<mpsc::Sender<Vec<u32>>>,
+        release: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl SlowOutput {
+        fn spawn(
+            max_batch_size: usize,
+            queue_size: usize,
+        ) -> (
+            Worker,
+            mpsc::Receiver<Vec<u32>>,
+            mpsc::Sender<()>,
+            mpsc::Receiver<String>,
+        ) {
+            let (started_tx, started) = mpsc::channel();
+            let (release, release_rx) = mpsc::channel();
+            let (error_tx, errors) = mpsc::channel();
+            let error_tx = Mutex::new(error_tx);
+            let output = SlowOutput {
+                max_batch_size,
+                started: Mutex::new(started_tx),
+                release: Mutex::new(release_rx),
+            };
+            let worker = Worker::spawn(
+                Box::new(output),
+                queue_size,
+                Arc::new(
+                    move |record: &Record, output: &str, error: &anyhow::Error| {
+                        let message = format!("{} {} {}", output, record.sensor.sensor_id, error);
+                        error_tx.lock().unwrap().send(message).unwrap();
+                    },
+                ),
+            )
+            .unwrap();
+            (worker, started, release, errors)
+        }
+
+        fn deliver(&self, sensor_ids: Vec<u32>) -> Result<()> {
+            let unlucky = sensor_ids.contains(&13);
+            self.started.lock().unwrap().send(sensor_ids).unwrap();
+            self.release.lock().unwrap().recv().unwrap();
+            anyhow::ensure!(!unlucky, "unlucky");
+            Ok(())
+        }
+    }
+
+    impl Output for SlowOutput {
+        fn name(&self) -> &str {
+            "slow"
+        }
+
+        fn send(&self, record: &Record) -> Result<()> {
+            self.deliver(vec![record.sensor.sensor_id])
+        }
+
+        fn max_batch_size(&self) -> usize {
+            self.max_batch_size
+        }
+
+        fn send_batch(&self, records: &[Arc<Record>]) -> Result<()> {
+            self.deliver(records.iter().map(|r| r.sensor.sensor_id).collect())
+        }
+    }
+
+    fn record(sensor_id: u32) -> Arc<Record> {
+        Arc::new(Record::example(sensor_id, Measurement::default()))
+    }
+
+    #[test]
+    fn test_worker() {
+        let (worker, started, release, errors) = SlowOutput::spawn(1, 1);
+
+        // While the first record is being sent, the second one is queued and
+        // the third one is dropped
+        assert!(worker.submit(record(13)));
+        assert_eq!(started.recv().unwrap(), [13]);
+        assert!(worker.submit(record(2)));
+        assert!(!worker.submit(record(3)));
+        release.send(()).unwrap();
+        assert_eq!(errors.recv().unwrap(), "slow 13 unlucky");
+
+        // Queued records are delivered on shutdown
+        release.send(()).unwrap();
+        worker.shutdown();
+        assert_eq!(started.try_iter().collect::<Vec<_>>(), [[2]]);
+        assert!(errors.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_worker_batches() {
+        let (worker, started, release, errors) = SlowOutput::spawn(3, 10);
+        assert!(worker.submit(record(1)));
+        assert_eq!(started.recv().unwrap(), [1]);
+        for sensor_id in [2, 13, 4, 5] {
+            assert!(worker.submit(record(sensor_id)));
+        }
+        for _ in 0..3 {
+            release.send(()).unwrap();
+        }
+        worker.shutdown();
+        assert_eq!(
+            started.try_iter().collect::<Vec<_>>(),
+            [vec![2, 13, 4], vec![5]]
+        );
+        // Every record of the failed batch is reported
+        assert_eq!(
+            errors.try_iter().collect::<Vec<_>>(),
+            ["slow 2 unlucky", "slow 13 unlucky", "slow 4 unlucky"]
+        );
+    }
+
+    /// An output that holds back records and signals every tick.
+    struct HoldingOutput {
+        held: Mutex<Vec<Record>>,
+        ticks: Mutex<mpsc::Sender<usize>>,
+    }
+
+    impl Output for HoldingOutput {
+        fn name(&self) -> &str {
+            "holding"
+        }
+
+        fn send(&self, record: &Record) -> Result<()> {
+            self.held.lock().unwrap().push(record.clone());
+            Ok(())
+        }
+
+        fn tick_interval(&self) -> Option<Duration> {
+            Some(Duration::from_millis(10))
+        }
+
+        fn tick(&self, _now: SystemTime) -> Failures {
+            let held = self.held.lock().unwrap().len();
+            self.ticks.lock().unwrap().send(held).unwrap();
+            Vec::new()
+        }
+
+        fn flush(&self) -> Failures {
+            let held = std::mem::take(&mut *self.held.lock().unwrap());
+            held.into_iter()
+                .map(|record| (record, anyhow::anyhow!("flushed")))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_worker_ticks() {
+        let (ticks_tx, ticks) = mpsc::channel();
+        let (error_tx, errors) = mpsc::channel();
+        let error_tx = Mutex::new(error_tx);
+        let output = HoldingOutput {
+            held: Mutex::default(),
+            ticks: Mutex::new(ticks_tx),
+        };
+        let worker = Worker::spawn(
+            Box::new(output),
+            10,
+            Arc::new(move |record: &Record, _: &str, error: &anyhow::Error| {
+                let message = format!("{} {}", record.sensor.sensor_id, error);
+                error_tx.lock().unwrap().send(message).unwrap();
+            }),
+        )
+        .unwrap();
+
+        // The output is ticked while no records arrive
+        let timeout = Duration::from_secs(5);
+        assert_eq!(ticks.recv_timeout(timeout).unwrap(), 0);
+        assert!(worker.submit(record(1)));
+        while ticks.recv_timeout(timeout).unwrap() == 0 {}
+
+        // Held back records are flushed on shutdown
+        worker.shutdown();
+        assert_eq!(errors.try_iter().collect::<Vec<_>>(), ["1 flushed"]);
+    }
+
+    #[test]
+    fn test_max_gateways() {
+        let record = Record::example(1, Measurement::default());
+        assert_eq!(record.meta.max_rssi_gateway().unwrap().name, "gw-2");
+        let (gw, snr) = record.meta.max_snr_gateway().unwrap();
+        assert_eq!((gw.name.as_str(), snr), ("gw-1", -3.5));
+    }
+}
@@ -1,28 +1,540 @@
-use anyhow::{bail, Result};
+use std::{collections::HashMap, fmt};
 
-#[derive(Debug)]
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::{cayenne::CayenneLppDecoder, config::Channel, uplink::Location};
+
+/// A physical quantity (or device state) reported by a sensor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Quantity {
+    /// Temperature in °C
+    Temperature,
+    /// Relative humidity in %RH
+    Humidity,
+    /// Battery voltage in V
+    BatteryVoltage,
+    /// Barometric pressure in hPa
+    Pressure,
+    /// Electrical conductivity in µS/cm
+    Conductivity,
+    /// Illuminance in lux
+    Illuminance,
+    /// Analog input, unitless
+    AnalogInput,
+    /// Analog output, unitless
+    AnalogOutput,
+    /// Digital input level (0 or 1)
+    DigitalInput,
+    /// Digital output level (0 or 1)
+    DigitalOutput,
+    /// Alarm flag (0 or 1)
+    Alarm,
+    /// Work mode of the device
+    WorkMode,
+    /// Presence (0 or 1)
+    Presence,
+    /// Acceleration along the X axis in g
+    AccelerationX,
+    /// Acceleration along the Y axis in g
+    AccelerationY,
+    /// Acceleration along the Z axis in g
+    AccelerationZ,
+    /// Angular velocity around the X axis in °/s
+    AngularVelocityX,
+    /// Angular velocity around the Y axis in °/s
+    AngularVelocityY,
+    /// Angular velocity around the Z axis in °/s
+    AngularVelocityZ,
+    /// Latitude in degrees (WGS84)
+    Latitude,
+    /// Longitude in degrees (WGS84)
+    Longitude,
+    /// Altitude in meters
+    Altitude,
+}
+
+impl Quantity {
+    /// The unit in which values of this quantity are reported.
+    pub fn unit(&self) -> Unit {
+        match self {
+            Quantity::Temperature => Unit::Celsius,
+            Quantity::Humidity => Unit::Percent,
+            Quantity::BatteryVoltage => Unit::Volt,
+            Quantity::Pressure => Unit::Hectopascal,
+            Quantity::Conductivity => Unit::MicrosiemensPerCentimeter,
+            Quantity::Illuminance => Unit::Lux,
+            Quantity::AnalogInput
+            | Quantity::AnalogOutput
+            | Quantity::DigitalInput
+            | Quantity::DigitalOutput
+            | Quantity::Alarm
+            | Quantity::WorkMode
+            | Quantity::Presence => Unit::None,
+            Quantity::AccelerationX | Quantity::AccelerationY | Quantity::AccelerationZ => {
+                Unit::Gravity
+            }
+            Quantity::AngularVelocityX
+            | Quantity::AngularVelocityY
+            | Quantity::AngularVelocityZ => Unit::DegreesPerSecond,
+            Quantity::Latitude | Quantity::Longitude => Unit::Degrees,
+            Quantity::Altitude => Unit::Meter,
+        }
+    }
+
+    /// The short name used to build InfluxDB field names.
+    fn field_name(&self) -> &'static str {
+        match self {
+            Quantity::Temperature => "temp",
+            Quantity::Humidity => "humi",
+            Quantity::BatteryVoltage => "voltage",
+            Quantity::Pressure => "pressure",
+            Quantity::Conductivity => "conductivity",
+            Quantity::Illuminance => "illuminance",
+            Quantity::AnalogInput => "analog_in",
+            Quantity::AnalogOutput => "analog_out",
+            Quantity::DigitalInput => "digital_input",
+            Quantity::DigitalOutput => "digital_output",
+            Quantity::Alarm => "alarm",
+            Quantity::WorkMode => "work_mode",
+            Quantity::Presence => "presence",
+            Quantity::AccelerationX => "acceleration_x",
+            Quantity::AccelerationY => "acceleration_y",
+            Quantity::AccelerationZ => "acceleration_z",
+            Quantity::AngularVelocityX => "angular_velocity_x",
+            Quantity::AngularVelocityY => "angular_velocity_y",
+            Quantity::AngularVelocityZ => "angular_velocity_z",
+            Quantity::Latitude => "latitude",
+            Quantity::Longitude => "longitude",
+            Quantity::Altitude => "altitude",
+        }
+    }
+}
+
+/// The unit of a reading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Celsius,
+    Percent,
+    Volt,
+    Hectopascal,
+    MicrosiemensPerCentimeter,
+    Lux,
+    Gravity,
+    DegreesPerSecond,
+    Degrees,
+    Meter,
+    None,
+}
+
+impl Unit {
+    /// The unit symbol (empty for unitless values).
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Unit::Celsius => "°C",
+            Unit::Percent => "%",
+            Unit::Volt => "V",
+            Unit::Hectopascal => "hPa",
+            Unit::MicrosiemensPerCentimeter => "µS/cm",
+            Unit::Lux => "lx",
+            Unit::Gravity => "g",
+            Unit::DegreesPerSecond => "°/s",
+            Unit::Degrees => "°",
+            Unit::Meter => "m",
+            Unit::None => "",
+        }
+    }
+}
+
+/// Where a reading was taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Position {
+    /// In the water
+    Water,
+    /// Inside the sensor enclosure
+    Enclosure,
+    /// In the air outside of the enclosure
+    Air,
+    /// The device itself (e.g. battery or status flags)
+    Device,
+}
+
+impl Position {
+    /// The prefix used to build InfluxDB field names.
+    fn field_prefix(&self) -> &'static str {
+        match self {
+            Position::Water => "water_",
+            Position::Enclosure => "enclosure_",
+            Position::Air => "air_",
+            Position::Device => "",
+        }
+    }
+}
+
+/// A single value reported by a sensor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reading {
+    /// The measured quantity.
+    pub quantity: Quantity,
+    /// The unit of the value.
+    pub unit: Unit,
+    /// Where the reading was taken.
+    pub position: Position,
+    /// The channel, for sensors with multiple probes of the same kind.
+    pub channel: Option<u8>,
+    /// The depth below the water surface in meters (if known).
+    pub depth_m: Option<f32>,
+    /// The value.
+    pub value: f32,
+    /// The uncorrected value, if the value was calibrated.
+    pub raw_value: Option<f32>,
+    /// Whether this is the primary reading of its quantity and position, if
+    /// set by the channel config (default: see [`Reading::is_primary`]).
+    pub primary: Option<bool>,
+}
+
+impl Reading {
+    /// Create a new reading, using the default unit of the quantity.
+    pub fn new(quantity: Quantity, position: Position, value: f32) -> Self {
+        Self {
+            quantity,
+            unit: quantity.unit(),
+            position,
+            channel: None,
+            depth_m: None,
+            value,
+            raw_value: None,
+            primary: None,
+        }
+    }
+
+    /// Set the channel of this reading.
+    pub fn with_channel(mut self, channel: u8) -> Self {
+        self.channel = Some(channel);
+        self
+    }
+
+    /// Whether this reading belongs to the primary channel (i.e. no channel
+    /// or channel 1, unless overridden by the channel config).
+    pub fn is_primary(&self) -> bool {
+        self.primary
+            .unwrap_or(matches!(self.channel, None | Some(1)))
+    }
+
+    /// Whether this reading matches the specified quantity, and (if
+    /// specified) position and channel.
+    pub fn matches(
+        &self,
+        quantity: Quantity,
+        position: Option<Position>,
+        channel: Option<u8>,
+    ) -> bool {
+        self.quantity == quantity
+            && position.is_none_or(|p| p == self.position)
+            && channel.is_none_or(|c| Some(c) == self.channel)
+    }
+
+    /// The InfluxDB field name, e.g. `water_temp` or `water_temp_2`.
+    ///
+    /// Readings of the primary channel have no channel suffix.
+    pub fn field_name(&self) -> String {
+        let name = format!(
+            "{}{}",
+            self.position.field_prefix(),
+            self.quantity.field_name()
+        );
+        match self.channel {
+            Some(channel) if !self.is_primary() => format!("{}_{}", name, channel),
+            _ => name,
+        }
+    }
+
+    /// The value formatted for the InfluxDB line protocol.
+    pub fn influx_value(&self) -> String {
+        self.format_influx(self.value)
+    }
+
+    /// The uncorrected value formatted for the InfluxDB line protocol, if the
+    /// value was calibrated.
+    pub fn influx_raw_value(&self) -> Option<String> {
+        self.raw_value.map(|raw| self.format_influx(raw))
+    }
+
+    /// The value as JSON number.
+    ///
+    /// The value is converted via its shortest decimal representation, so
+    /// that e.g. 13.14 is not widened to 13.140000343322754.
+    pub fn json_value(&self) -> serde_json::Value {
+        match self.quantity {
+            Quantity::WorkMode => serde_json::Value::from(self.value as i64),
+            _ => self
+                .value
+                .to_string()
+                .parse::<f64>()
+                .map_or(serde_json::Value::Null, serde_json::Value::from),
+        }
+    }
+
+    fn format_influx(&self, value: f32) -> String {
+        match self.quantity {
+            Quantity::BatteryVoltage => format!("{:.3}", value),
+            Quantity::DigitalInput
+            | Quantity::DigitalOutput
+            | Quantity::Alarm
+            | Quantity::Presence => (value != 0.0).to_string(),
+            Quantity::Latitude | Quantity::Longitude => format!("{:.6}", value),
+            Quantity::WorkMode => format!("{}i", value as i64),
+            _ => format!("{:.2}", value),
+        }
+    }
+}
+
+impl fmt::Display for Reading {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} ({:?}", self.quantity, self.position)?;
+        if let Some(channel) = self.channel {
+            write!(f, ", channel {}", channel)?;
+        }
+        if let Some(depth) = self.depth_m {
+            write!(f, ", {} m", depth)?;
+        }
+        write!(f, "): {}", self.value)?;
+        if self.unit != Unit::None {
+            write!(f, " {}", self.unit.symbol())?;
+        }
+        if let Some(raw_value) = self.raw_value {
+            write!(f, " (raw: {})", raw_value)?;
+        }
+        Ok(())
+    }
+}
+
+/// A decoded measurement, consisting of a list of readings.
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct Measurement {
-    /// The water temperature in °C.
-    pub temperature_water: f32,
-    /// The enclosure temperature in °C.
-    pub temperature_enclosure: Option<f32>,
-    /// The enclosure humidity in %RH.
-    pub humidity_enclosure: Option<f32>,
-    /// The battery voltage in millivolts.
-    pub battery_millivolts: u16,
+    pub readings: Vec<Reading>,
+}
+
+impl Measurement {
+    /// Apply the per-sensor channel config (position, depth and primary
+    /// channel) to the readings.
+    pub fn apply_channels(&mut self, channels: &[Channel]) {
+        let channel_config =
+            |reading: &Reading| channels.iter().find(|c| Some(c.channel) == reading.channel);
+
+        // Move the readings first, so that the primary readings are resolved
+        // at their final positions
+        for reading in &mut self.readings {
+            let Some(channel) = channel_config(reading) else {
+                continue;
+            };
+            if let Some(position) = channel.position {
+                reading.position = position;
+            }
+            reading.depth_m = channel.depth_m.or(reading.depth_m);
+        }
+        for i in 0..self.readings.len() {
+            let reading = &self.readings[i];
+            if channel_config(reading).and_then(|c| c.primary) != Some(true) {
+                continue;
+            }
+            // Only one reading per quantity and position can be primary
+            let (quantity, position) = (reading.quantity, reading.position);
+            for (j, other) in self.readings.iter_mut().enumerate() {
+                if other.quantity == quantity && other.position == position {
+                    other.primary = Some(i == j);
+                }
+            }
+        }
+    }
+
+    /// Return the value of the primary reading of the specified quantity at
+    /// the specified position.
+    pub fn get(&self, quantity: Quantity, position: Position) -> Option<f32> {
+        self.readings
+            .iter()
+            .find(|r| r.quantity == quantity && r.position == position && r.is_primary())
+            .map(|r| r.value)
+    }
+
+    /// The (primary) water temperature in °C.
+    pub fn water_temperature(&self) -> Option<f32> {
+        self.get(Quantity::Temperature, Position::Water)
+    }
+
+    /// The battery voltage in V.
+    pub fn battery_voltage(&self) -> Option<f32> {
+        self.get(Quantity::BatteryVoltage, Position::Device)
+    }
+
+    /// The position of the device, if the payload contains latitude and
+    /// longitude (e.g. from a GPS receiver).
+    pub fn location(&self) -> Option<Location> {
+        let first = |quantity| {
+            self.readings
+                .iter()
+                .find(|r| r.quantity == quantity)
+                .map(|r| f64::from(r.value))
+        };
+        Some(Location {
+            latitude: first(Quantity::Latitude)?,
+            longitude: first(Quantity::Longitude)?,
+            altitude: first(Quantity::Altitude),
+        })
+    }
+}
+
+/// A decoder that turns a raw uplink payload into a [`Measurement`].
+pub trait PayloadDecoder: Send + Sync {
+    /// A human readable name of the payload format, used in error messages
+    /// (e.g. "Gfroerli V1").
+    fn label(&self) -> &str;
+
+    /// Decode the raw payload received on the specified FPort.
+    fn decode(&self, frame_port: u16, payload: &[u8]) -> Result<Measurement>;
+}
+
+/// Registry of payload decoders, keyed by decoder name and FPort.
+///
+/// The decoder name corresponds to the `sensor_type` in the sensor config.
+/// A decoder can either be registered for a specific FPort, or for all
+/// FPorts. Decoders registered for a specific FPort take precedence.
+pub struct DecoderRegistry {
+    decoders: HashMap<(String, Option<u16>), Box<dyn PayloadDecoder>>,
+}
+
+impl DecoderRegistry {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Self {
+            decoders: HashMap::new(),
+        }
+    }
+
+    /// Create a registry containing all built-in decoders.
+    pub fn with_builtin_decoders() -> Self {
+        let mut registry = Self::new();
+        registry.register("gfroerli", Some(1), GfroerliV1Decoder);
+        registry.register("gfroerli", Some(2), GfroerliV2Decoder);
+        registry.register("dragino", None, DraginoDecoder);
+        registry.register("cayenne_lpp", None, CayenneLppDecoder);
+        registry
+    }
+
+    /// Register a decoder for the specified FPort (or for all FPorts if
+    /// `frame_port` is `None`), replacing any previously registered decoder.
+    pub fn register(
+        &mut self,
+        name: &str,
+        frame_port: Option<u16>,
+        decoder: impl PayloadDecoder + 'static,
+    ) {
+        self.decoders
+            .insert((name.to_string(), frame_port), Box::new(decoder));
+    }
+
+    /// Return whether at least one decoder with this name is registered.
+    pub fn contains(&self, name: &str) -> bool {
+        self.decoders.keys().any(|(n, _)| n == name)
+    }
+
+    /// Return the sorted list of registered decoder names and FPorts.
+    pub fn entries(&self) -> Vec<(&str, Option<u16>, &str)> {
+        let mut entries = self
+            .decoders
+            .iter()
+            .map(|((name, port), decoder)| (name.as_str(), *port, decoder.label()))
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|(name, port, _)| (*name, *port));
+        entries
+    }
+
+    /// Look up the decoder for the specified name and FPort.
+    pub fn get(&self, name: &str, frame_port: u16) -> Option<&dyn PayloadDecoder> {
+        self.decoders
+            .get(&(name.to_string(), Some(frame_port)))
+            .or_else(|| self.decoders.get(&(name.to_string(), None)))
+            .map(Box::as_ref)
+    }
+
+    /// Decode a payload with the decoder registered for this name and FPort.
+    pub fn decode(&self, name: &str, frame_port: u16, payload: &[u8]) -> Result<Measurement> {
+        let decoder = match self.get(name, frame_port) {
+            Some(decoder) => decoder,
+            None if self.contains(name) => {
+                bail!("Unknown FPort for a {} sensor: {}", name, frame_port)
+            }
+            None => bail!("Unknown sensor type: {}", name),
+        };
+        decoder
+            .decode(frame_port, payload)
+            .with_context(|| format!("Failed to parse {} payload", decoder.label()))
+    }
+}
+
+impl Default for DecoderRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Dragino LSN50 v2-D20
+pub struct DraginoDecoder;
+
+impl PayloadDecoder for DraginoDecoder {
+    fn label(&self) -> &str {
+        "Dragino"
+    }
+
+    fn decode(&self, _frame_port: u16, payload: &[u8]) -> Result<Measurement> {
+        parse_payload_dragino(payload)
+    }
+}
+
+/// Custom Gfrörli firmware, V1 payload format (FPort 1)
+pub struct GfroerliV1Decoder;
+
+impl PayloadDecoder for GfroerliV1Decoder {
+    fn label(&self) -> &str {
+        "Gfroerli V1"
+    }
+
+    fn decode(&self, _frame_port: u16, payload: &[u8]) -> Result<Measurement> {
+        parse_payload_gfroerli_v1(payload)
+    }
+}
+
+/// Custom Gfrörli firmware, V2 payload format (FPort 2)
+pub struct GfroerliV2Decoder;
+
+impl PayloadDecoder for GfroerliV2Decoder {
+    fn label(&self) -> &str {
+        "Gfroerli V2"
+    }
+
+    fn decode(&self, _frame_port: u16, payload: &[u8]) -> Result<Measurement> {
+        parse_payload_gfroerli_v2(payload)
+    }
 }
 
+/// Raw DS18B20 value reported by Dragino sensors if a probe is not connected.
+const DRAGINO_PROBE_DISCONNECTED: i16 = 0x7fff;
+
 /// Parse a Dragino payload.
 ///
 /// Payload format:
 ///
 /// - 2 bytes battery voltage
-/// - 2 bytes temperature
+/// - 2 bytes temperature (red probe)
 /// - 2 bytes reserved
-/// - 1 byte alarm flag
-/// - 4 bytes for other temperature sensors (unused)
+/// - 1 byte alarm flag (bit 0), work mode (bits 2-6) and PA8 level (bit 7)
+/// - 2 bytes temperature (white probe)
+/// - 2 bytes temperature (black probe)
 ///
-/// All multi-byte values are in big endian format.
+/// All multi-byte values are in big endian format. Temperatures are signed
+/// and in 1/10 °C. Disconnected probes report the value `0x7fff`.
 pub fn parse_payload_dragino(payload: &[u8]) -> Result<Measurement> {
     if payload.len() != 11 {
         bail!(
@@ -31,17 +543,48 @@ pub fn parse_payload_dragino(payload: &[u8]) -> Result<Measurement> {
         );
     }
     let battery_millivolts = u16::from_be_bytes([payload[0], payload[1]]);
-    let temperature_raw = u16::from_be_bytes([payload[2], payload[3]]) as f32;
-    let temperature = match payload[2] & 0xfc == 0 {
-        true => temperature_raw / 10.0,
-        false => (temperature_raw - 65536.0) / 10.0,
+    let temperature = |msb: u8, lsb: u8| -> Option<f32> {
+        match i16::from_be_bytes([msb, lsb]) {
+            DRAGINO_PROBE_DISCONNECTED => None,
+            raw => Some(raw as f32 / 10.0),
+        }
     };
-    Ok(Measurement {
-        temperature_water: temperature,
-        temperature_enclosure: None,
-        humidity_enclosure: None,
-        battery_millivolts,
-    })
+    let temperature_water =
+        temperature(payload[2], payload[3]).context("Main temperature probe is not connected")?;
+    let flags = payload[6];
+
+    let main_probe = Reading::new(Quantity::Temperature, Position::Water, temperature_water);
+    let mut readings = vec![main_probe.with_channel(1)];
+    for (channel, (msb, lsb)) in [
+        (2, (payload[7], payload[8])),
+        (3, (payload[9], payload[10])),
+    ] {
+        if let Some(temperature) = temperature(msb, lsb) {
+            readings.push(
+                Reading::new(Quantity::Temperature, Position::Water, temperature)
+                    .with_channel(channel),
+            );
+        }
+    }
+    readings.extend([
+        Reading::new(
+            Quantity::BatteryVoltage,
+            Position::Device,
+            battery_millivolts as f32 / 1000.0,
+        ),
+        Reading::new(Quantity::Alarm, Position::Device, (flags & 0x01) as f32),
+        Reading::new(
+            Quantity::DigitalInput,
+            Position::Device,
+            ((flags & 0x80) >> 7) as f32,
+        ),
+        Reading::new(
+            Quantity::WorkMode,
+            Position::Device,
+            ((flags & 0x7c) >> 2) as f32,
+        ),
+    ]);
+    Ok(Measurement { readings })
 }
 
 /// Parse a Gfroerli V1 payload.
@@ -61,15 +604,21 @@ pub fn parse_payload_gfroerli_v1(payload: &[u8]) -> Result<Measurement> {
         );
     }
     let temperature_water = f32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]);
-    let temperature_enclosure = Some(f32::from_le_bytes([payload[4], payload[5], payload[6], payload[7]]));
-    let humidity_enclosure = Some(f32::from_le_bytes([payload[8], payload[9], payload[10], payload[11]]));
+    let temperature_enclosure =
+        f32::from_le_bytes([payload[4], payload[5], payload[6], payload[7]]);
+    let humidity_enclosure = f32::from_le_bytes([payload[8], payload[9], payload[10], payload[11]]);
     let battery_voltage = f32::from_le_bytes([payload[12], payload[13], payload[14], payload[15]]);
-    let battery_millivolts = (battery_voltage * 1000.0) as u16;
     Ok(Measurement {
-        temperature_water,
-        temperature_enclosure,
-        humidity_enclosure,
-        battery_millivolts,
+        readings: vec![
+            Reading::new(Quantity::Temperature, Position::Water, temperature_water),
+            Reading::new(
+                Quantity::Temperature,
+                Position::Enclosure,
+                temperature_enclosure,
+            ),
+            Reading::new(Quantity::Humidity, Position::Enclosure, humidity_enclosure),
+            Reading::new(Quantity::BatteryVoltage, Position::Device, battery_voltage),
+        ],
     })
 }
 
@@ -81,6 +630,104 @@ pub fn parse_payload_gfroerli_v2(_payload: &[u8]) -> Result<Measurement> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::Sensor;
+
+    /// Return the battery voltage in V.
+    fn battery(measurement: &Measurement) -> Option<f32> {
+        measurement.get(Quantity::BatteryVoltage, Position::Device)
+    }
+
+    /// Return all water temperatures as (channel, value) tuples.
+    fn water_temperatures(measurement: &Measurement) -> Vec<(u8, f32)> {
+        measurement
+            .readings
+            .iter()
+            .filter(|r| r.quantity == Quantity::Temperature && r.position == Position::Water)
+            .map(|r| (r.channel.unwrap(), r.value))
+            .collect()
+    }
+
+    #[test]
+    fn test_apply_channels() {
+        // A Cayenne LPP sensor (as in the example config) that measures the
+        // water temperature on LPP channel 3
+        let sensor = toml::from_str::<Sensor>(
+            r#"
+            sensor_type = "cayenne_lpp"
+            sensor_id = 126
+
+            [[channels]]
+            channel = 3
+            position = "water"
+            primary = true
+            "#,
+        )
+        .unwrap();
+        let payload = [0x03, 0x67, 0x01, 0x10, 0x05, 0x67, 0x00, 0xff];
+        let mut measurement = DecoderRegistry::with_builtin_decoders()
+            .decode(&sensor.sensor_type, 1, &payload)
+            .unwrap();
+        assert_eq!(measurement.water_temperature(), None);
+        measurement.apply_channels(&sensor.channels);
+        assert_eq!(measurement.water_temperature(), Some(27.2));
+        assert_eq!(measurement.readings[0].field_name(), "water_temp");
+        assert_eq!(measurement.readings[0].depth_m, None);
+
+        // Marking another channel as primary demotes channel 1
+        let channels = toml::from_str::<HashMap<String, Vec<Channel>>>(
+            "[[channels]]\nchannel = 2\nprimary = true\ndepth_m = 2.0",
+        )
+        .unwrap();
+        let payload = [0x0c, 0xe4, 0x00, 0xa0, 0, 0, 0x00, 0x00, 0x7d, 0x7f, 0xff];
+        let mut measurement = parse_payload_dragino(&payload).unwrap();
+        assert_eq!(measurement.water_temperature(), Some(16.0));
+        measurement.apply_channels(&channels["channels"]);
+        assert_eq!(measurement.water_temperature(), Some(12.5));
+        let fields = measurement
+            .readings
+            .iter()
+            .filter(|r| r.quantity == Quantity::Temperature)
+            .map(|r| (r.field_name(), r.depth_m))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            vec![
+                ("water_temp_1".to_string(), None),
+                ("water_temp".to_string(), Some(2.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_channels_moved_onto_primary() {
+        // Channel 1 (primary by default) is moved onto the position of the
+        // primary channel 3 after the latter has been marked as primary
+        let channels = toml::from_str::<HashMap<String, Vec<Channel>>>(
+            r#"
+            [[channels]]
+            channel = 3
+            position = "water"
+            primary = true
+
+            [[channels]]
+            channel = 1
+            position = "water"
+            "#,
+        )
+        .unwrap();
+        let payload = [0x03, 0x67, 0x01, 0x10, 0x01, 0x67, 0x00, 0xff];
+        let mut measurement = DecoderRegistry::with_builtin_decoders()
+            .decode("cayenne_lpp", 1, &payload)
+            .unwrap();
+        measurement.apply_channels(&channels["channels"]);
+        assert_eq!(measurement.water_temperature(), Some(27.2));
+        let fields = measurement
+            .readings
+            .iter()
+            .map(|r| r.field_name())
+            .collect::<Vec<_>>();
+        assert_eq!(fields, vec!["water_temp", "water_temp_1"]);
+    }
 
     #[test]
     fn test_parse_dragino_payload() {
@@ -89,10 +736,59 @@ mod tests {
         let payload2 = [0x0b, 0x49, 0xff, 0x3f, 0, 0, 0, 0, 0, 0, 0];
         let measurement1 = parse_payload_dragino(&payload1).unwrap();
         let measurement2 = parse_payload_dragino(&payload2).unwrap();
-        assert_eq!(measurement1.battery_millivolts, 2885);
-        assert_eq!(measurement2.battery_millivolts, 2889);
-        assert_eq!(measurement1.temperature_water, 26.1);
-        assert_eq!(measurement2.temperature_water, -19.3);
+        assert_eq!(battery(&measurement1), Some(2.885));
+        assert_eq!(battery(&measurement2), Some(2.889));
+        assert_eq!(measurement1.water_temperature(), Some(26.1));
+        assert_eq!(measurement2.water_temperature(), Some(-19.3));
+    }
+
+    #[test]
+    fn test_parse_dragino_payload_additional_probes() {
+        // Alarm flag set, PA8 high, work mode 3, white probe 12.5 °C, black probe disconnected
+        let payload = [0x0c, 0xe4, 0x00, 0xa0, 0, 0, 0x8d, 0x00, 0x7d, 0x7f, 0xff];
+        let measurement = parse_payload_dragino(&payload).unwrap();
+        assert_eq!(battery(&measurement), Some(3.3));
+        assert_eq!(measurement.water_temperature(), Some(16.0));
+        assert_eq!(water_temperatures(&measurement), vec![(1, 16.0), (2, 12.5)]);
+        assert_eq!(
+            measurement.get(Quantity::Alarm, Position::Device),
+            Some(1.0)
+        );
+        assert_eq!(
+            measurement.get(Quantity::DigitalInput, Position::Device),
+            Some(1.0)
+        );
+        assert_eq!(
+            measurement.get(Quantity::WorkMode, Position::Device),
+            Some(3.0)
+        );
+
+        // No flags, negative temperature on the black probe
+        let payload = [0x0c, 0xe4, 0x00, 0xa0, 0, 0, 0x00, 0x00, 0x00, 0xff, 0xf6];
+        let measurement = parse_payload_dragino(&payload).unwrap();
+        assert_eq!(
+            water_temperatures(&measurement),
+            vec![(1, 16.0), (2, 0.0), (3, -1.0)]
+        );
+        assert_eq!(
+            measurement.get(Quantity::Alarm, Position::Device),
+            Some(0.0)
+        );
+        assert_eq!(
+            measurement.get(Quantity::DigitalInput, Position::Device),
+            Some(0.0)
+        );
+        assert_eq!(
+            measurement.get(Quantity::WorkMode, Position::Device),
+            Some(0.0)
+        );
+    }
+
+    #[test]
+    fn test_parse_dragino_payload_main_probe_disconnected() {
+        let payload = [0x0c, 0xe4, 0x7f, 0xff, 0, 0, 0, 0, 0, 0, 0];
+        let err = parse_payload_dragino(&payload).unwrap_err();
+        assert_eq!(err.to_string(), "Main temperature probe is not connected");
     }
 
     #[test]
@@ -103,13 +799,103 @@ mod tests {
         let payload2 = [0, 0, 160, 65, 0, 0, 32, 65, 0, 0, 74, 66, 102, 102, 70, 64];
         let measurement1 = parse_payload_gfroerli_v1(&payload1).unwrap();
         let measurement2 = parse_payload_gfroerli_v1(&payload2).unwrap();
-        assert_eq!(measurement1.temperature_water, 13.14);
-        assert_eq!(measurement2.temperature_water, 20.0);
-        assert_eq!(measurement1.temperature_enclosure, Some(8.76));
-        assert_eq!(measurement2.temperature_enclosure, Some(10.0));
-        assert_eq!(measurement1.humidity_enclosure, Some(75.1));
-        assert_eq!(measurement2.humidity_enclosure, Some(50.5));
-        assert_eq!(measurement1.battery_millivolts, 3210);
-        assert_eq!(measurement2.battery_millivolts, 3100);
+        let enclosure_temperature =
+            |m: &Measurement| m.get(Quantity::Temperature, Position::Enclosure);
+        let enclosure_humidity = |m: &Measurement| m.get(Quantity::Humidity, Position::Enclosure);
+        assert_eq!(measurement1.water_temperature(), Some(13.14));
+        assert_eq!(measurement2.water_temperature(), Some(20.0));
+        assert_eq!(enclosure_temperature(&measurement1), Some(8.76));
+        assert_eq!(enclosure_temperature(&measurement2), Some(10.0));
+        assert_eq!(enclosure_humidity(&measurement1), Some(75.1));
+        assert_eq!(enclosure_humidity(&measurement2), Some(50.5));
+        assert_eq!(battery(&measurement1), Some(3.21));
+        assert_eq!(battery(&measurement2), Some(3.1));
+    }
+
+    #[test]
+    fn test_field_names() {
+        let reading = |quantity, position| Reading::new(quantity, position, 1.0);
+        assert_eq!(
+            reading(Quantity::Temperature, Position::Water).field_name(),
+            "water_temp"
+        );
+        assert_eq!(
+            reading(Quantity::Temperature, Position::Water)
+                .with_channel(1)
+                .field_name(),
+            "water_temp"
+        );
+        assert_eq!(
+            reading(Quantity::Temperature, Position::Water)
+                .with_channel(3)
+                .field_name(),
+            "water_temp_3"
+        );
+        assert_eq!(
+            reading(Quantity::Humidity, Position::Enclosure).field_name(),
+            "enclosure_humi"
+        );
+        assert_eq!(
+            reading(Quantity::BatteryVoltage, Position::Device).field_name(),
+            "voltage"
+        );
+        assert_eq!(
+            reading(Quantity::Pressure, Position::Air)
+                .with_channel(0)
+                .field_name(),
+            "air_pressure_0"
+        );
+    }
+
+    #[test]
+    fn test_registry_lookup() {
+        let registry = DecoderRegistry::with_builtin_decoders();
+        assert!(registry.contains("gfroerli"));
+        assert!(registry.contains("dragino"));
+        assert!(!registry.contains("foo"));
+
+        // Specific FPorts
+        assert_eq!(registry.get("gfroerli", 1).unwrap().label(), "Gfroerli V1");
+        assert_eq!(registry.get("gfroerli", 2).unwrap().label(), "Gfroerli V2");
+        assert!(registry.get("gfroerli", 3).is_none());
+
+        // Wildcard FPort
+        assert_eq!(registry.get("dragino", 2).unwrap().label(), "Dragino");
+    }
+
+    #[test]
+    fn test_registry_decode() {
+        let registry = DecoderRegistry::with_builtin_decoders();
+        let payload = [0x0b, 0x45, 0x01, 0x05, 0, 0, 0, 0, 0, 0, 0];
+        let measurement = registry.decode("dragino", 1, &payload).unwrap();
+        assert_eq!(measurement.water_temperature(), Some(26.1));
+
+        let err = registry.decode("gfroerli", 3, &payload).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown FPort for a gfroerli sensor: 3");
+        let err = registry.decode("gfroerli", 1, &payload).unwrap_err();
+        assert_eq!(err.to_string(), "Failed to parse Gfroerli V1 payload");
+        let err = registry.decode("foo", 1, &payload).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown sensor type: foo");
+    }
+
+    #[test]
+    fn test_registry_specific_port_takes_precedence() {
+        struct Fixed(&'static str, f32);
+        impl PayloadDecoder for Fixed {
+            fn label(&self) -> &str {
+                self.0
+            }
+            fn decode(&self, _frame_port: u16, _payload: &[u8]) -> Result<Measurement> {
+                Ok(Measurement {
+                    readings: vec![Reading::new(Quantity::Temperature, Position::Water, self.1)],
+                })
+            }
+        }
+        let mut registry = DecoderRegistry::new();
+        registry.register("custom", None, Fixed("any", 1.0));
+        registry.register("custom", Some(5), Fixed("five", 5.0));
+        let decode = |fport| registry.decode("custom", fport, &[]).unwrap();
+        assert_eq!(decode(4).water_temperature(), Some(1.0));
+        assert_eq!(decode(5).water_temperature(), Some(5.0));
     }
 }
@@ -0,0 +1,446 @@
+//! PostgreSQL / TimescaleDB output.
+//!
+//! Measurements are written to the `measurements` table (one row per uplink,
+//! readings as JSONB object) and to the `reception` table (one row per uplink
+//! and receiving gateway). If `timescaledb` is enabled, both tables are
+//! converted to hypertables.
+//!
+//! Connections are pooled (broken connections are replaced automatically),
+//! and all records that are queued for this output are written in a single
+//! transaction. The schema is versioned in the `ttn_relay_schema` table, and
+//! migrations are applied once the first connection succeeds, so that the
+//! relay starts even if the server is not reachable yet.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use postgres::{Client, NoTls, Transaction};
+use r2d2_postgres::{r2d2, PostgresConnectionManager};
+use serde_json::{Map, Value};
+
+use crate::{
+    config,
+    output::{Output, Record},
+    uplink::DataRate,
+};
+
+/// Default number of pooled connections.
+const DEFAULT_POOL_SIZE: u32 = 2;
+
+/// Default maximum number of records written in one transaction.
+const DEFAULT_BATCH_SIZE: usize = 100;
+
+/// Schema migrations. The schema version is the number of applied
+/// migrations. Never change an existing migration, always append a new one.
+const MIGRATIONS: &[&str] = &[
+    // 1: Initial schema
+    "CREATE TABLE measurements (
+        time TIMESTAMPTZ NOT NULL,
+        dev_eui TEXT NOT NULL,
+        sensor_id INTEGER NOT NULL,
+        sensor_type TEXT NOT NULL,
+        fcnt BIGINT,
+        lost_frames INTEGER,
+        spreading_factor SMALLINT,
+        bandwidth INTEGER,
+        airtime_ms INTEGER NOT NULL,
+        rejected BOOLEAN NOT NULL DEFAULT FALSE,
+        readings JSONB NOT NULL
+    );
+    CREATE INDEX measurements_sensor_time ON measurements (sensor_id, time DESC);
+    CREATE TABLE reception (
+        time TIMESTAMPTZ NOT NULL,
+        dev_eui TEXT NOT NULL,
+        sensor_id INTEGER NOT NULL,
+        fcnt BIGINT,
+        gateway TEXT NOT NULL,
+        rssi DOUBLE PRECISION NOT NULL,
+        snr DOUBLE PRECISION,
+        spreading_factor SMALLINT
+    );
+    CREATE INDEX reception_gateway_time ON reception (gateway, time DESC);",
+    // 2: Detailed reception metadata
+    "ALTER TABLE reception
+        ADD COLUMN gateway_eui TEXT,
+        ADD COLUMN channel_rssi DOUBLE PRECISION,
+        ADD COLUMN gateway_time TEXT,
+        ADD COLUMN gateway_timestamp BIGINT,
+        ADD COLUMN antenna_index INTEGER,
+        ADD COLUMN latitude DOUBLE PRECISION,
+        ADD COLUMN longitude DOUBLE PRECISION,
+        ADD COLUMN altitude DOUBLE PRECISION;",
+    // 3: Radio settings
+    "ALTER TABLE measurements
+        ADD COLUMN data_rate TEXT,
+        ADD COLUMN coding_rate TEXT,
+        ADD COLUMN frequency BIGINT,
+        ADD COLUMN concentrator_timestamp BIGINT;",
+];
+
+/// Statements converting the tables to TimescaleDB hypertables.
+const HYPERTABLES: &str = "
+    CREATE EXTENSION IF NOT EXISTS timescaledb;
+    SELECT create_hypertable('measurements', 'time', if_not_exists => TRUE, migrate_data => TRUE);
+    SELECT create_hypertable('reception', 'time', if_not_exists => TRUE, migrate_data => TRUE);";
+
+/// Apply all pending migrations.
+fn migrate(client: &mut Client, timescaledb: bool) -> Result<()> {
+    let mut tx = client.transaction()?;
+    tx.batch_execute(
+        "CREATE TABLE IF NOT EXISTS ttn_relay_schema (version INTEGER NOT NULL);
+         LOCK TABLE ttn_relay_schema IN EXCLUSIVE MODE;",
+    )?;
+    let version = match tx.query_opt("SELECT version FROM ttn_relay_schema", &[])? {
+        Some(row) => row.get::<_, i32>(0) as usize,
+        None => {
+            tx.execute("INSERT INTO ttn_relay_schema (version) VALUES (0)", &[])?;
+            0
+        }
+    };
+    if version > MIGRATIONS.len() {
+        bail!(
+            "Database schema version {} is newer than supported version {}",
+            version,
+            MIGRATIONS.len()
+        );
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!("Migrating PostgreSQL schema to version {}", i + 1);
+        tx.batch_execute(migration)
+            .with_context(|| format!("Migration {} failed", i + 1))?;
+        tx.execute(
+            "UPDATE ttn_relay_schema SET version = $1",
+            &[&(i as i32 + 1)],
+        )?;
+    }
+    if timescaledb {
+        tx.batch_execute(HYPERTABLES)
+            .context("Could not create hypertables")?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// The readings of a record as JSON object (field name → value).
+fn readings_json(record: &Record) -> Value {
+    Value::Object(
+        record
+            .measurement
+            .readings
+            .iter()
+            .map(|reading| (reading.field_name(), reading.json_value()))
+            .collect::<Map<_, _>>(),
+    )
+}
+
+/// Write a record within a transaction.
+fn insert(tx: &mut Transaction, record: &Record) -> Result<()> {
+    let meta = &record.meta;
+    let sensor_id = record.sensor.sensor_id as i32;
+    let fcnt = meta.frames.as_ref().map(|frames| i64::from(frames.fcnt));
+    let spreading_factor = meta.spreading_factor().map(|sf| sf as i16);
+    tx.execute(
+        "INSERT INTO measurements (time, dev_eui, sensor_id, sensor_type, fcnt, lost_frames,
+            spreading_factor, bandwidth, airtime_ms, rejected, readings, data_rate, coding_rate,
+            frequency, concentrator_timestamp)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)",
+        &[
+            &record.timestamp,
+            &record.dev_eui,
+            &sensor_id,
+            &record.sensor.sensor_type,
+            &fcnt,
+            &meta.frames.as_ref().map(|frames| frames.lost_frames as i32),
+            &spreading_factor,
+            &meta.bandwidth().map(|bw| bw as i32),
+            &(meta.airtime_ms as i32),
+            &record.rejected,
+            &readings_json(record),
+            &meta.data_rate.as_ref().map(ToString::to_string),
+            &meta.data_rate.as_ref().and_then(DataRate::coding_rate),
+            &meta.frequency.map(|f| f as i64),
+            &meta.timestamp.map(i64::from),
+        ],
+    )?;
+    if record.rejected {
+        // The reception was already recorded with the accepted readings
+        return Ok(());
+    }
+    for gateway in &meta.receiving_gateways {
+        let location = gateway.location;
+        tx.execute(
+            "INSERT INTO reception (time, dev_eui, sensor_id, fcnt, gateway, rssi, snr,
+                spreading_factor, gateway_eui, channel_rssi, gateway_time, gateway_timestamp,
+                antenna_index, latitude, longitude, altitude)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16)",
+            &[
+                &record.timestamp,
+                &record.dev_eui,
+                &sensor_id,
+                &fcnt,
+                &gateway.name,
+                &gateway.rssi,
+                &gateway.snr,
+                &spreading_factor,
+                &gateway.eui,
+                &gateway.channel_rssi,
+                &gateway.time,
+                &gateway.timestamp.map(i64::from),
+                &(gateway.antenna_index as i32),
+                &location.map(|l| l.latitude),
+                &location.map(|l| l.longitude),
+                &location.and_then(|l| l.altitude),
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+type Pool = r2d2::Pool<PostgresConnectionManager<NoTls>>;
+type Connection = r2d2::PooledConnection<PostgresConnectionManager<NoTls>>;
+
+/// Writes measurements to PostgreSQL (optionally with TimescaleDB).
+pub struct PostgresOutput {
+    pool: Pool,
+    batch_size: usize,
+    timescaledb: bool,
+    /// Whether pending migrations were applied
+    migrated: AtomicBool,
+}
+
+impl PostgresOutput {
+    /// Create a connection pool. No connection is established until the
+    /// first record is written.
+    pub fn new(config: &config::Postgres) -> Result<Self> {
+        let manager = PostgresConnectionManager::new(
+            config
+                .url
+                .parse()
+                .context("Invalid PostgreSQL connection string")?,
+            NoTls,
+        );
+        let pool = r2d2::Pool::builder()
+            .max_size(config.pool_size.unwrap_or(DEFAULT_POOL_SIZE))
+            .connection_timeout(Duration::from_secs(5))
+            // Connect on demand instead of in the background
+            .min_idle(Some(0))
+            .build_unchecked(manager);
+        Ok(Self {
+            pool,
+            batch_size: config.batch_size.unwrap_or(DEFAULT_BATCH_SIZE),
+            timescaledb: config.timescaledb.unwrap_or(false),
+            migrated: AtomicBool::new(false),
+        })
+    }
+
+    /// Check out a connection, applying pending migrations on the first
+    /// successful checkout.
+    fn connection(&self) -> Result<Connection> {
+        let mut conn = self
+            .pool
+            .get()
+            .context("No PostgreSQL connection available")?;
+        if !self.migrated.load(Ordering::Acquire) {
+            info!("Connected to PostgreSQL");
+            migrate(&mut conn, self.timescaledb).context("PostgreSQL migration failed")?;
+            self.migrated.store(true, Ordering::Release);
+        }
+        Ok(conn)
+    }
+
+    /// Write records in a single transaction.
+    ///
+    /// If the connection was closed (e.g. because the server was restarted),
+    /// the write is retried once with a new connection.
+    fn write(&self, records: &[&Record]) -> Result<()> {
+        let mut retried = false;
+        loop {
+            let mut conn = self.connection()?;
+            let result = (|| {
+                let mut tx = conn.transaction()?;
+                for record in records {
+                    insert(&mut tx, record)?;
+                }
+                tx.commit()?;
+                Ok(())
+            })();
+            // Closed connections are discarded by the pool
+            if result.is_err() && conn.is_closed() && !retried {
+                warn!("PostgreSQL connection was closed, reconnecting");
+                retried = true;
+                continue;
+            }
+            return result;
+        }
+    }
+}
+
+impl Output for PostgresOutput {
+    fn name(&self) -> &str {
+        "postgres"
+    }
+
+    /// Store all measurements, including rejected readings (flagged as such).
+    fn accepts(&self, _record: &Record) -> bool {
+        true
+    }
+
+    fn send(&self, record: &Record) -> Result<()> {
+        self.write(&[record])
+    }
+
+    fn max_batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    fn send_batch(&self, records: &[Arc<Record>]) -> Result<()> {
+        debug!("Writing {} records to PostgreSQL...", records.len());
+        self.write(&records.iter().map(|r| &**r).collect::<Vec<_>>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Except for `test_unreachable_server`, these tests need a PostgreSQL
+    //! server. Run them with
+    //! `TTN_RELAY_TEST_POSTGRES="host=localhost user=postgres" cargo test -- --ignored`.
+    //! All tables are dropped and recreated.
+
+    use super::*;
+    use crate::payload::{Measurement, Position, Quantity, Reading};
+
+    fn test_url() -> String {
+        std::env::var("TTN_RELAY_TEST_POSTGRES")
+            .unwrap_or_else(|_| "host=localhost user=postgres".to_string())
+    }
+
+    fn output() -> PostgresOutput {
+        let url = test_url();
+        let mut client = Client::connect(&url, NoTls).unwrap();
+        client
+            .batch_execute("DROP TABLE IF EXISTS measurements, reception, ttn_relay_schema CASCADE")
+            .unwrap();
+        PostgresOutput::new(&config::Postgres {
+            url,
+            pool_size: Some(1),
+            batch_size: None,
+            timescaledb: None,
+        })
+        .unwrap()
+    }
+
+    fn record(sensor_id: u32) -> Arc<Record> {
+        Arc::new(Record::example(
+            sensor_id,
+            Measurement {
+                readings: vec![Reading::new(Quantity::Temperature, Position::Water, 13.14)],
+            },
+        ))
+    }
+
+    #[test]
+    fn test_unreachable_server() {
+        // The output is created without connecting
+        PostgresOutput::new(&config::Postgres {
+            url: "host=127.0.0.1 port=1 user=postgres".to_string(),
+            pool_size: Some(1),
+            batch_size: None,
+            timescaledb: None,
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn test_write() {
+        let output = output();
+        output.send(&record(1)).unwrap();
+        output.send_batch(&[record(2), record(3)]).unwrap();
+        let mut rejected = Record::example(4, Measurement::default());
+        rejected.rejected = true;
+        output.send(&rejected).unwrap();
+
+        // Migrations are only applied once
+        migrate(&mut output.pool.get().unwrap(), false).unwrap();
+
+        let mut conn = output.pool.get().unwrap();
+        let rows = conn
+            .query(
+                "SELECT sensor_id, fcnt, spreading_factor, rejected, readings, data_rate, frequency
+                 FROM measurements ORDER BY sensor_id",
+                &[],
+            )
+            .unwrap();
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[0].get::<_, i32>(0), 1);
+        assert_eq!(rows[0].get::<_, i64>(1), 7);
+        assert_eq!(rows[0].get::<_, Option<i16>>(2), Some(7));
+        assert!(!rows[0].get::<_, bool>(3));
+        assert_eq!(
+            rows[0].get::<_, Value>(4),
+            serde_json::json!({"water_temp": 13.14})
+        );
+        assert_eq!(
+            rows[0].get::<_, Option<String>>(5).as_deref(),
+            Some("SF7BW125")
+        );
+        assert_eq!(rows[0].get::<_, Option<i64>>(6), Some(868100000));
+        assert!(rows[3].get::<_, bool>(3));
+
+        let rows = conn
+            .query(
+                "SELECT gateway, rssi, snr, gateway_eui, latitude FROM reception
+                 WHERE sensor_id = 1 ORDER BY gateway",
+                &[],
+            )
+            .unwrap();
+        let reception = rows
+            .iter()
+            .map(|row| {
+                (
+                    row.get::<_, String>(0),
+                    row.get::<_, f64>(1),
+                    row.get::<_, Option<f64>>(2),
+                    row.get::<_, Option<String>>(3),
+                    row.get::<_, Option<f64>>(4),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reception,
+            [
+                (
+                    "gw-1".to_string(),
+                    -110.0,
+                    Some(-3.5),
+                    Some("B827EBFFFE000001".to_string()),
+                    Some(47.2)
+                ),
+                ("gw-2".to_string(), -95.0, None, None, None)
+            ]
+        );
+        let count: i64 = conn
+            .query_one("SELECT COUNT(*) FROM reception", &[])
+            .unwrap()
+            .get(0);
+        assert_eq!(count, 6);
+        drop(conn);
+
+        // Connections closed by the server are replaced
+        let mut admin = Client::connect(&test_url(), NoTls).unwrap();
+        admin
+            .batch_execute(
+                "SELECT pg_terminate_backend(pid) FROM pg_stat_activity
+                 WHERE pid <> pg_backend_pid() AND datname = current_database()",
+            )
+            .unwrap();
+        output.send(&record(5)).unwrap();
+    }
+}
@@ -0,0 +1,336 @@
+//! Republishing of decoded measurements to a local MQTT broker.
+//!
+//! Every measurement is published as JSON object to a configurable topic.
+//! Optionally, Home Assistant MQTT discovery messages are published for every
+//! field of a sensor (once per field and run), so that the sensors show up in
+//! Home Assistant automatically.
+
+use std::{
+    collections::HashSet,
+    sync::Mutex,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, info};
+use paho_mqtt as mqtt;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    config::{self, Sensor},
+    output::{MeasurementMeta, Output, Record},
+    payload::{Measurement, Position, Quantity, Reading},
+    uplink::DataRate,
+};
+
+/// Default Home Assistant discovery topic prefix.
+const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+
+/// Publishes measurements to a local MQTT broker.
+pub struct Republisher {
+    client: mqtt::Client,
+    config: config::Republish,
+    /// The (DevEUI, field) pairs for which a discovery message was published
+    announced: Mutex<HashSet<(String, String)>>,
+}
+
+/// Render a topic template.
+///
+/// Supported placeholders: `{sensor_id}`, `{dev_eui}` and `{sensor_type}`.
+fn render_topic(template: &str, dev_eui: &str, sensor: &Sensor) -> String {
+    template
+        .replace("{sensor_id}", &sensor.sensor_id.to_string())
+        .replace("{dev_eui}", dev_eui)
+        .replace("{sensor_type}", &sensor.sensor_type)
+}
+
+/// Build the JSON payload of a measurement.
+fn measurement_payload(
+    dev_eui: &str,
+    sensor: &Sensor,
+    measurement: &Measurement,
+    meta: &MeasurementMeta,
+    timestamp: u64,
+) -> Value {
+    let readings = measurement
+        .readings
+        .iter()
+        .map(|reading| (reading.field_name(), reading.json_value()))
+        .collect::<Map<_, _>>();
+    json!({
+        "sensor_id": sensor.sensor_id,
+        "dev_eui": dev_eui,
+        "sensor_type": sensor.sensor_type,
+        "timestamp": timestamp,
+        "fcnt": meta.frames.as_ref().map(|frames| frames.fcnt),
+        "data_rate": meta.data_rate.as_ref().map(ToString::to_string),
+        "modulation": meta.data_rate.as_ref().map(DataRate::modulation),
+        "coding_rate": meta.data_rate.as_ref().and_then(DataRate::coding_rate),
+        "frequency": meta.frequency,
+        "concentrator_timestamp": meta.timestamp,
+        "readings": readings,
+    })
+}
+
+/// Split a camel case identifier into words, e.g. "BatteryVoltage" becomes
+/// "Battery voltage".
+fn words(identifier: &str) -> String {
+    let mut result = String::new();
+    for (i, c) in identifier.chars().enumerate() {
+        if i > 0 && c.is_uppercase() {
+            result.push(' ');
+            result.extend(c.to_lowercase());
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// The human readable name of a reading, e.g. "Water temperature 2".
+fn reading_name(reading: &Reading) -> String {
+    let mut name = match reading.position {
+        Position::Device => words(&format!("{:?}", reading.quantity)),
+        position => format!(
+            "{} {}",
+            words(&format!("{:?}", position)),
+            words(&format!("{:?}", reading.quantity)).to_lowercase()
+        ),
+    };
+    if let Some(channel) = reading.channel.filter(|_| !reading.is_primary()) {
+        name.push_str(&format!(" {}", channel));
+    }
+    name
+}
+
+/// The Home Assistant device class of a quantity (if any).
+fn device_class(quantity: Quantity) -> Option<&'static str> {
+    match quantity {
+        Quantity::Temperature => Some("temperature"),
+        Quantity::Humidity => Some("humidity"),
+        Quantity::BatteryVoltage => Some("voltage"),
+        Quantity::Pressure => Some("pressure"),
+        Quantity::Illuminance => Some("illuminance"),
+        _ => None,
+    }
+}
+
+/// Build the Home Assistant discovery topic and payload of a reading.
+fn discovery_message(
+    prefix: &str,
+    state_topic: &str,
+    dev_eui: &str,
+    sensor: &Sensor,
+    reading: &Reading,
+) -> (String, Value) {
+    let field = reading.field_name();
+    let object_id = format!("ttn_relay_{}", dev_eui.to_lowercase());
+    let mut payload = json!({
+        "name": reading_name(reading),
+        "unique_id": format!("{}_{}", object_id, field),
+        "state_topic": state_topic,
+        "value_template": format!("{{{{ value_json.readings.{} }}}}", field),
+        "state_class": "measurement",
+        "device": {
+            "identifiers": [object_id],
+            "name": format!("Gfrörli sensor {}", sensor.sensor_id),
+            "model": sensor.sensor_type,
+        },
+    });
+    if reading.unit.symbol() != "" {
+        payload["unit_of_measurement"] = json!(reading.unit.symbol());
+    }
+    if let Some(class) = device_class(reading.quantity) {
+        payload["device_class"] = json!(class);
+    }
+    let topic = format!("{}/sensor/{}/{}/config", prefix, object_id, field);
+    (topic, payload)
+}
+
+impl Republisher {
+    /// Create a client for the configured broker and connect to it.
+    pub fn connect(config: &config::Republish) -> Result<Self> {
+        let mut client = mqtt::Client::new(
+            mqtt::CreateOptionsBuilder::new()
+                .server_uri(&config.host)
+                .client_id(config.client_id.as_deref().unwrap_or("ttn-relay"))
+                .finalize(),
+        )
+        .context("Error creating the republish client")?;
+        client.set_timeout(Duration::from_secs(3));
+        let mut conn_opts = mqtt::ConnectOptionsBuilder::new();
+        conn_opts.keep_alive_interval(Duration::from_secs(20));
+        if let Some(ref user) = config.user {
+            conn_opts.user_name(user);
+        }
+        if let Some(ref pass) = config.pass {
+            conn_opts.password(pass);
+        }
+        info!("Connecting to the republish MQTT broker...");
+        client
+            .connect(conn_opts.finalize())
+            .context("Error connecting to the republish broker")?;
+        Ok(Self {
+            client,
+            config: config.clone(),
+            announced: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Publish a message, reconnecting first if the connection was lost.
+    fn publish(&self, topic: String, payload: &Value, retained: bool) -> Result<()> {
+        if !self.client.is_connected() {
+            info!("Reconnecting to the republish MQTT broker...");
+            self.client
+                .reconnect()
+                .context("Error reconnecting to the republish broker")?;
+        }
+        let payload = payload.to_string();
+        let message = if retained {
+            mqtt::Message::new_retained(topic, payload, 1)
+        } else {
+            mqtt::Message::new(topic, payload, 1)
+        };
+        self.client.publish(message).context("Publishing failed")?;
+        Ok(())
+    }
+}
+
+impl Output for Republisher {
+    fn name(&self) -> &str {
+        "mqtt"
+    }
+
+    /// Publish a measurement (and discovery messages for new fields).
+    fn send(&self, record: &Record) -> Result<()> {
+        let dev_eui = record.dev_eui.as_str();
+        let topic = render_topic(&self.config.topic, dev_eui, &record.sensor);
+        if self.config.home_assistant_discovery.unwrap_or(false) {
+            let prefix = self
+                .config
+                .discovery_prefix
+                .as_deref()
+                .unwrap_or(DEFAULT_DISCOVERY_PREFIX);
+            let mut announced = self.announced.lock().unwrap();
+            for reading in &record.measurement.readings {
+                let key = (dev_eui.to_string(), reading.field_name());
+                if announced.contains(&key) {
+                    continue;
+                }
+                let (discovery_topic, payload) =
+                    discovery_message(prefix, &topic, dev_eui, &record.sensor, reading);
+                debug!("Publishing discovery message to {}", discovery_topic);
+                self.publish(discovery_topic, &payload, true)?;
+                announced.insert(key);
+            }
+        }
+        info!("Republishing measurement to {}...", topic);
+        let timestamp = record
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let payload = measurement_payload(
+            dev_eui,
+            &record.sensor,
+            &record.measurement,
+            &record.meta,
+            timestamp,
+        );
+        self.publish(topic, &payload, self.config.retained.unwrap_or(true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sensor() -> Sensor {
+        toml::from_str("sensor_type = \"dragino\"\nsensor_id = 42").unwrap()
+    }
+
+    fn measurement() -> Measurement {
+        Measurement {
+            readings: vec![
+                Reading::new(Quantity::Temperature, Position::Water, 13.14).with_channel(1),
+                Reading::new(Quantity::Temperature, Position::Water, 15.25).with_channel(2),
+                Reading::new(Quantity::BatteryVoltage, Position::Device, 3.5),
+                Reading::new(Quantity::WorkMode, Position::Device, 1.0),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_render_topic() {
+        assert_eq!(
+            render_topic(
+                "gfroerli/{sensor_id}/{sensor_type}/{dev_eui}",
+                "AABB",
+                &sensor()
+            ),
+            "gfroerli/42/dragino/AABB"
+        );
+    }
+
+    #[test]
+    fn test_measurement_payload() {
+        let meta = Record::example(42, Measurement::default()).meta;
+        let payload = measurement_payload("AABB", &sensor(), &measurement(), &meta, 1700000000);
+        assert_eq!(
+            payload,
+            json!({
+                "sensor_id": 42,
+                "dev_eui": "AABB",
+                "sensor_type": "dragino",
+                "timestamp": 1700000000,
+                "fcnt": 7,
+                "data_rate": "SF7BW125",
+                "modulation": "lora",
+                "coding_rate": "4/5",
+                "frequency": 868100000,
+                "concentrator_timestamp": 1234500,
+                "readings": {
+                    "water_temp": 13.14,
+                    "water_temp_2": 15.25,
+                    "voltage": 3.5,
+                    "work_mode": 1,
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_discovery_message() {
+        let measurement = measurement();
+        let (topic, payload) = discovery_message(
+            "homeassistant",
+            "gfroerli/42/measurement",
+            "AABB",
+            &sensor(),
+            &measurement.readings[1],
+        );
+        assert_eq!(
+            topic,
+            "homeassistant/sensor/ttn_relay_aabb/water_temp_2/config"
+        );
+        assert_eq!(payload["name"], "Water temperature 2");
+        assert_eq!(payload["unique_id"], "ttn_relay_aabb_water_temp_2");
+        assert_eq!(
+            payload["value_template"],
+            "{{ value_json.readings.water_temp_2 }}"
+        );
+        assert_eq!(payload["unit_of_measurement"], "°C");
+        assert_eq!(payload["device_class"], "temperature");
+
+        let (_, payload) = discovery_message(
+            "homeassistant",
+            "gfroerli/42/measurement",
+            "AABB",
+            &sensor(),
+            &measurement.readings[3],
+        );
+        assert_eq!(payload["name"], "Work mode");
+        assert!(payload.get("unit_of_measurement").is_none());
+        assert!(payload.get("device_class").is_none());
+    }
+}
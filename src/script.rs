@@ -0,0 +1,381 @@
+//! Scripted payload decoders, based on the [Rhai](https://rhai.rs/) scripting
+//! language.
+//!
+//! A decoder script must define a function `decode(fport, bytes)` that
+//! receives the FPort as integer and the raw payload as blob, and returns an
+//! object map with the following (optional) fields:
+//!
+//! - `temperature_water`: Water temperature in °C
+//! - `temperature_enclosure`: Enclosure temperature in °C
+//! - `humidity_enclosure`: Enclosure humidity in %RH
+//! - `battery_millivolts`: Battery voltage in millivolts
+//! - `readings`: An array of additional readings, each being an object map
+//!   with the fields `quantity` (e.g. "temperature"), `position` (e.g.
+//!   "water"), `value` and an optional `channel` (0-255)
+//!
+//! Example:
+//!
+//! ```rhai
+//! fn decode(fport, bytes) {
+//!     if bytes.len() != 4 {
+//!         throw `Expected 4 bytes, but got ${bytes.len()}`;
+//!     }
+//!     #{
+//!         temperature_water: ((bytes[0] << 8) | bytes[1]) / 100.0,
+//!         battery_millivolts: (bytes[2] << 8) | bytes[3],
+//!     }
+//! }
+//! ```
+
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use rhai::{Array, Blob, Dynamic, Engine, Map, Scope, AST};
+use serde::{de::IntoDeserializer, Deserialize};
+
+use crate::{
+    config::ScriptDecoder as ScriptDecoderConfig,
+    payload::{Measurement, PayloadDecoder, Position, Quantity, Reading},
+};
+
+/// Default maximum number of operations per decoder invocation
+const DEFAULT_MAX_OPERATIONS: u64 = 100_000;
+/// Default maximum execution time per decoder invocation
+const DEFAULT_TIMEOUT_MS: u64 = 100;
+/// Default maximum size of strings, arrays and maps
+const DEFAULT_MAX_COLLECTION_SIZE: usize = 1024;
+
+/// A payload decoder implemented as Rhai script.
+pub struct ScriptDecoder {
+    label: String,
+    ast: AST,
+    max_operations: u64,
+    timeout: Duration,
+    max_collection_size: usize,
+}
+
+impl ScriptDecoder {
+    /// Load and compile the decoder script referenced by the config.
+    ///
+    /// Relative script paths are resolved relative to `base_dir`.
+    pub fn load(name: &str, config: &ScriptDecoderConfig, base_dir: &Path) -> Result<Self> {
+        let path = base_dir.join(&config.script);
+        let source = fs::read_to_string(&path)
+            .with_context(|| format!("Could not read decoder script {:?}", path))?;
+        Self::compile(name, &source, config)
+            .with_context(|| format!("Could not load decoder script {:?}", path))
+    }
+
+    /// Compile a decoder script from source.
+    pub fn compile(name: &str, source: &str, config: &ScriptDecoderConfig) -> Result<Self> {
+        let mut decoder = Self {
+            label: format!("{} (script)", name),
+            ast: AST::empty(),
+            max_operations: config.max_operations.unwrap_or(DEFAULT_MAX_OPERATIONS),
+            timeout: Duration::from_millis(config.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS)),
+            max_collection_size: config
+                .max_collection_size
+                .unwrap_or(DEFAULT_MAX_COLLECTION_SIZE),
+        };
+        let ast = decoder
+            .engine()
+            .compile(source)
+            .map_err(|e| anyhow!("Syntax error: {}", e))?;
+        if !ast
+            .iter_functions()
+            .any(|f| f.name == "decode" && f.params.len() == 2)
+        {
+            bail!("Script does not define a function `decode(fport, bytes)`");
+        }
+        decoder.ast = ast;
+        Ok(decoder)
+    }
+
+    /// Create a sandboxed script engine with the configured limits.
+    ///
+    /// The execution time limit starts counting when the engine is created.
+    fn engine(&self) -> Engine {
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(self.max_operations)
+            .set_max_string_size(self.max_collection_size)
+            .set_max_array_size(self.max_collection_size)
+            .set_max_map_size(self.max_collection_size)
+            .set_max_call_levels(32)
+            .set_max_expr_depths(64, 32)
+            .disable_symbol("eval");
+        let started = Instant::now();
+        let timeout = self.timeout;
+        engine.on_progress(move |_| {
+            if started.elapsed() > timeout {
+                Some(Dynamic::from("timeout"))
+            } else {
+                None
+            }
+        });
+        engine
+    }
+}
+
+impl PayloadDecoder for ScriptDecoder {
+    fn label(&self) -> &str {
+        &self.label
+    }
+
+    fn decode(&self, frame_port: u16, payload: &[u8]) -> Result<Measurement> {
+        let result: Map = self
+            .engine()
+            .call_fn(
+                &mut Scope::new(),
+                &self.ast,
+                "decode",
+                (i64::from(frame_port), Blob::from(payload)),
+            )
+            .map_err(|e| anyhow!("Script error: {}", e))?;
+
+        let mut readings = vec![];
+        let legacy_fields = [
+            ("temperature_water", Quantity::Temperature, Position::Water),
+            (
+                "temperature_enclosure",
+                Quantity::Temperature,
+                Position::Enclosure,
+            ),
+            (
+                "humidity_enclosure",
+                Quantity::Humidity,
+                Position::Enclosure,
+            ),
+        ];
+        for (key, quantity, position) in legacy_fields {
+            if let Some(value) = get_float(&result, key)? {
+                readings.push(Reading::new(quantity, position, value));
+            }
+        }
+        if let Some(millivolts) = get_float(&result, "battery_millivolts")? {
+            readings.push(Reading::new(
+                Quantity::BatteryVoltage,
+                Position::Device,
+                millivolts / 1000.0,
+            ));
+        }
+        if let Some(value) = result.get("readings") {
+            let array = value
+                .as_array_ref()
+                .map_err(|t| anyhow!("Field `readings` must be an array, but was {}", t))?;
+            readings.extend(parse_readings(&array)?);
+        }
+        if readings.is_empty() {
+            bail!("Script result does not contain any readings");
+        }
+        Ok(Measurement { readings })
+    }
+}
+
+/// Return the numeric value of a map field, if present.
+fn get_float(map: &Map, key: &str) -> Result<Option<f32>> {
+    match map.get(key) {
+        None => Ok(None),
+        Some(value) if value.is_unit() => Ok(None),
+        Some(value) => value
+            .as_float()
+            .map(|v| v as f32)
+            .or_else(|_| value.as_int().map(|v| v as f32))
+            .map(Some)
+            .map_err(|t| anyhow!("Field `{}` must be a number, but was {}", key, t)),
+    }
+}
+
+/// Return the integer value of a map field, if present.
+fn get_int(map: &Map, key: &str) -> Result<Option<i64>> {
+    match map.get(key) {
+        None => Ok(None),
+        Some(value) if value.is_unit() => Ok(None),
+        Some(value) => value
+            .as_int()
+            .map(Some)
+            .map_err(|t| anyhow!("Field `{}` must be an integer, but was {}", key, t)),
+    }
+}
+
+/// Return the string value of a map field.
+fn get_string(map: &Map, key: &str) -> Result<String> {
+    map.get(key)
+        .with_context(|| format!("Field `{}` is missing", key))?
+        .clone()
+        .into_string()
+        .map_err(|t| anyhow!("Field `{}` must be a string, but was {}", key, t))
+}
+
+/// Parse the `readings` array returned by a script.
+fn parse_readings(array: &Array) -> Result<Vec<Reading>> {
+    array
+        .iter()
+        .enumerate()
+        .map(|(i, item)| {
+            let map = item
+                .read_lock::<Map>()
+                .with_context(|| format!("Reading {} must be an object map", i))?;
+            let quantity = get_string(&map, "quantity")?;
+            let quantity = Quantity::deserialize(quantity.as_str().into_deserializer())
+                .map_err(|e: serde::de::value::Error| anyhow!("Reading {}: {}", i, e))?;
+            let position = get_string(&map, "position")?;
+            let position = Position::deserialize(position.as_str().into_deserializer())
+                .map_err(|e: serde::de::value::Error| anyhow!("Reading {}: {}", i, e))?;
+            let value = get_float(&map, "value")?
+                .with_context(|| format!("Reading {} does not contain a value", i))?;
+            let mut reading = Reading::new(quantity, position, value);
+            if let Some(channel) = get_int(&map, "channel")? {
+                let channel = u8::try_from(channel).map_err(|_| {
+                    anyhow!("Reading {}: channel {} is out of range (0-255)", i, channel)
+                })?;
+                reading = reading.with_channel(channel);
+            }
+            Ok(reading)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(source: &str) -> Result<ScriptDecoder> {
+        ScriptDecoder::compile("test", source, &ScriptDecoderConfig::default())
+    }
+
+    #[test]
+    fn test_decode() {
+        let decoder = compile(
+            r#"
+            fn decode(fport, bytes) {
+                if fport != 3 {
+                    throw `Unexpected FPort ${fport}`;
+                }
+                #{
+                    temperature_water: ((bytes[0] << 8) | bytes[1]) / 100.0,
+                    humidity_enclosure: 55,
+                    battery_millivolts: (bytes[2] << 8) | bytes[3],
+                }
+            }
+            "#,
+        )
+        .unwrap();
+        let measurement = decoder.decode(3, &[0x05, 0x14, 0x0b, 0xb8]).unwrap();
+        assert_eq!(measurement.water_temperature(), Some(13.0));
+        assert_eq!(
+            measurement.get(Quantity::Temperature, Position::Enclosure),
+            None
+        );
+        assert_eq!(
+            measurement.get(Quantity::Humidity, Position::Enclosure),
+            Some(55.0)
+        );
+        assert_eq!(
+            measurement.get(Quantity::BatteryVoltage, Position::Device),
+            Some(3.0)
+        );
+
+        let err = decoder.decode(1, &[0x05, 0x14, 0x0b, 0xb8]).unwrap_err();
+        assert!(err.to_string().contains("Unexpected FPort 1"), "{}", err);
+    }
+
+    #[test]
+    fn test_missing_decode_function() {
+        let err = compile("fn parse(bytes) { 42 }").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Script does not define a function `decode(fport, bytes)`"
+        );
+    }
+
+    #[test]
+    fn test_decode_readings() {
+        let decoder = compile(
+            r#"
+            fn decode(fport, bytes) {
+                #{
+                    readings: [
+                        #{ quantity: "temperature", position: "water", channel: 2, value: 11.5 },
+                        #{ quantity: "pressure", position: "air", value: 1013 },
+                    ]
+                }
+            }
+            "#,
+        )
+        .unwrap();
+        let measurement = decoder.decode(1, &[]).unwrap();
+        assert_eq!(
+            measurement.readings,
+            vec![
+                Reading::new(Quantity::Temperature, Position::Water, 11.5).with_channel(2),
+                Reading::new(Quantity::Pressure, Position::Air, 1013.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_reading() {
+        let decoder = compile(
+            r#"fn decode(fport, bytes) { #{ readings: [#{ quantity: "foo", position: "water", value: 1 }] } }"#,
+        )
+        .unwrap();
+        let err = decoder.decode(1, &[]).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Reading 0: unknown variant `foo`"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_channel_out_of_range() {
+        let decoder = compile(
+            r#"fn decode(fport, bytes) { #{ readings: [#{ quantity: "temperature", position: "water", channel: 256, value: 1 }] } }"#,
+        )
+        .unwrap();
+        let err = decoder.decode(1, &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Reading 0: channel 256 is out of range (0-255)"
+        );
+    }
+
+    #[test]
+    fn test_no_readings() {
+        let decoder = compile("fn decode(fport, bytes) { #{} }").unwrap();
+        let err = decoder.decode(1, &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Script result does not contain any readings"
+        );
+    }
+
+    #[test]
+    fn test_operation_limit() {
+        let decoder = compile("fn decode(fport, bytes) { loop {} }").unwrap();
+        let err = decoder.decode(1, &[]).unwrap_err();
+        assert!(err.to_string().contains("operations"), "{}", err);
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let decoder = compile(
+            r#"
+            fn decode(fport, bytes) {
+                let s = "";
+                for i in 0..2000 { s += "x"; }
+                #{ temperature_water: 1.0 }
+            }
+            "#,
+        )
+        .unwrap();
+        let err = decoder.decode(1, &[]).unwrap_err();
+        assert!(err.to_string().contains("too large"), "{}", err);
+    }
+}
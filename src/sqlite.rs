@@ -0,0 +1,480 @@
+//! Local SQLite measurement store.
+//!
+//! Every decoded measurement is stored together with its radio metadata, so
+//! that there is a local source of truth that does not depend on the API or
+//! InfluxDB being reachable. The schema is versioned using the SQLite
+//! `user_version` pragma, and migrations are applied when the store is opened.
+//!
+//! Schema:
+//!
+//! - `measurements`: One row per uplink (sensor, timestamp, frame counter,
+//!   data rate, spreading factor, bandwidth, coding rate, frequency,
+//!   concentrator timestamp, airtime, lost frames, rejected flag)
+//! - `readings`: The readings of a measurement (field name, value, raw value,
+//!   unit, depth)
+//! - `gateways`: The gateways that received the uplink (ID, EUI, RSSI,
+//!   channel RSSI, SNR, time, timestamp, antenna index and location)
+
+use std::{
+    io::Write,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use rusqlite::{params, types::ValueRef, Connection};
+
+use crate::{
+    output::{Output, Record},
+    uplink::DataRate,
+};
+
+/// Schema migrations. The schema version is the number of applied
+/// migrations. Never change an existing migration, always append a new one.
+const MIGRATIONS: &[&str] = &[
+    // 1: Initial schema
+    "CREATE TABLE measurements (
+        id INTEGER PRIMARY KEY,
+        timestamp INTEGER NOT NULL,
+        dev_eui TEXT NOT NULL,
+        sensor_id INTEGER NOT NULL,
+        sensor_type TEXT NOT NULL,
+        fcnt INTEGER,
+        lost_frames INTEGER,
+        spreading_factor INTEGER,
+        bandwidth INTEGER,
+        airtime_ms INTEGER NOT NULL,
+        rejected INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX measurements_sensor_timestamp ON measurements (sensor_id, timestamp);
+    CREATE INDEX measurements_timestamp ON measurements (timestamp);
+    CREATE TABLE readings (
+        measurement_id INTEGER NOT NULL REFERENCES measurements (id) ON DELETE CASCADE,
+        field TEXT NOT NULL,
+        value REAL NOT NULL,
+        raw_value REAL,
+        unit TEXT NOT NULL,
+        depth_m REAL
+    );
+    CREATE INDEX readings_measurement ON readings (measurement_id);
+    CREATE TABLE gateways (
+        measurement_id INTEGER NOT NULL REFERENCES measurements (id) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        rssi REAL NOT NULL,
+        snr REAL
+    );
+    CREATE INDEX gateways_measurement ON gateways (measurement_id);",
+    // 2: Detailed reception metadata
+    "ALTER TABLE gateways ADD COLUMN eui TEXT;
+    ALTER TABLE gateways ADD COLUMN channel_rssi REAL;
+    ALTER TABLE gateways ADD COLUMN time TEXT;
+    ALTER TABLE gateways ADD COLUMN timestamp INTEGER;
+    ALTER TABLE gateways ADD COLUMN antenna_index INTEGER;
+    ALTER TABLE gateways ADD COLUMN latitude REAL;
+    ALTER TABLE gateways ADD COLUMN longitude REAL;
+    ALTER TABLE gateways ADD COLUMN altitude REAL;",
+    // 3: Radio settings
+    "ALTER TABLE measurements ADD COLUMN data_rate TEXT;
+    ALTER TABLE measurements ADD COLUMN coding_rate TEXT;
+    ALTER TABLE measurements ADD COLUMN frequency INTEGER;
+    ALTER TABLE measurements ADD COLUMN concentrator_timestamp INTEGER;",
+];
+
+/// Apply all pending migrations.
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        bail!(
+            "Database schema version {} is newer than supported version {}",
+            version,
+            MIGRATIONS.len()
+        );
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!("Migrating SQLite store to schema version {}", i + 1);
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)
+            .with_context(|| format!("Migration {} failed", i + 1))?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// A filter for stored measurements.
+#[derive(Debug, Default)]
+pub struct Query {
+    /// Only return measurements of this sensor
+    pub sensor_id: Option<u32>,
+    /// Start of the time range (anything SQLite understands, e.g.
+    /// "2024-06-01" or "2024-06-01 12:00:00", in UTC)
+    pub since: Option<String>,
+    /// End of the time range (exclusive)
+    pub until: Option<String>,
+}
+
+/// A measurement read from the store.
+#[derive(Debug, PartialEq)]
+pub struct StoredMeasurement {
+    /// ISO 8601 timestamp (UTC)
+    pub time: String,
+    pub sensor_id: u32,
+    pub sensor_type: String,
+    pub fcnt: Option<u32>,
+    pub spreading_factor: Option<u16>,
+    pub rejected: bool,
+    /// (field, value, unit)
+    pub readings: Vec<(String, f64, String)>,
+}
+
+/// Columns of the CSV export (one row per reading).
+const CSV_HEADER: &str = "time,sensor_id,dev_eui,sensor_type,fcnt,spreading_factor,bandwidth,\
+                          airtime_ms,gateway_count,max_rssi,max_snr,rejected,field,value,\
+                          raw_value,unit,depth_m";
+
+/// Quote a CSV value if necessary.
+fn csv_escape(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Stores measurements in a local SQLite database.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Open (or create) the database and apply pending migrations.
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut conn = Connection::open(path)
+            .with_context(|| format!("Could not open SQLite database {:?}", path))?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        migrate(&mut conn)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Store a record.
+    pub fn insert(&self, record: &Record) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let meta = &record.meta;
+        let timestamp = record
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        tx.execute(
+            "INSERT INTO measurements (timestamp, dev_eui, sensor_id, sensor_type, fcnt,
+                lost_frames, spreading_factor, bandwidth, airtime_ms, rejected, data_rate,
+                coding_rate, frequency, concentrator_timestamp)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+            params![
+                timestamp,
+                record.dev_eui,
+                record.sensor.sensor_id,
+                record.sensor.sensor_type,
+                meta.frames.as_ref().map(|frames| frames.fcnt),
+                meta.frames.as_ref().map(|frames| frames.lost_frames),
+                meta.spreading_factor(),
+                meta.bandwidth(),
+                meta.airtime_ms,
+                record.rejected,
+                meta.data_rate.as_ref().map(ToString::to_string),
+                meta.data_rate.as_ref().and_then(DataRate::coding_rate),
+                meta.frequency,
+                meta.timestamp,
+            ],
+        )?;
+        let id = tx.last_insert_rowid();
+        {
+            let mut insert_reading = tx.prepare(
+                "INSERT INTO readings (measurement_id, field, value, raw_value, unit, depth_m)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for reading in &record.measurement.readings {
+                insert_reading.execute(params![
+                    id,
+                    reading.field_name(),
+                    reading.value,
+                    reading.raw_value,
+                    reading.unit.symbol(),
+                    reading.depth_m,
+                ])?;
+            }
+            let mut insert_gateway = tx.prepare(
+                "INSERT INTO gateways (measurement_id, name, rssi, snr, eui, channel_rssi, time,
+                    timestamp, antenna_index, latitude, longitude, altitude)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            )?;
+            for gateway in &meta.receiving_gateways {
+                let location = gateway.location;
+                insert_gateway.execute(params![
+                    id,
+                    gateway.name,
+                    gateway.rssi,
+                    gateway.snr,
+                    gateway.eui,
+                    gateway.channel_rssi,
+                    gateway.time,
+                    gateway.timestamp,
+                    gateway.antenna_index,
+                    location.map(|l| l.latitude),
+                    location.map(|l| l.longitude),
+                    location.and_then(|l| l.altitude),
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Convert a time string to a UNIX timestamp.
+    fn parse_time(conn: &Connection, time: Option<&str>) -> Result<Option<i64>> {
+        let Some(time) = time else {
+            return Ok(None);
+        };
+        let timestamp: Option<i64> =
+            conn.query_row("SELECT unixepoch(?1)", [time], |row| row.get(0))?;
+        match timestamp {
+            Some(timestamp) => Ok(Some(timestamp)),
+            None => bail!("Invalid time: {}", time),
+        }
+    }
+
+    /// Return the most recent measurements (at most `limit` per sensor),
+    /// ordered by sensor and time.
+    pub fn recent(&self, query: &Query, limit: usize) -> Result<Vec<StoredMeasurement>> {
+        let conn = self.conn.lock().unwrap();
+        let since = Self::parse_time(&conn, query.since.as_deref())?;
+        let until = Self::parse_time(&conn, query.until.as_deref())?;
+        let mut statement = conn.prepare(
+            "SELECT id, strftime('%Y-%m-%dT%H:%M:%SZ', timestamp, 'unixepoch'), sensor_id,
+                sensor_type, fcnt, spreading_factor, rejected
+             FROM (
+                SELECT *, ROW_NUMBER() OVER (
+                    PARTITION BY sensor_id ORDER BY timestamp DESC, id DESC
+                ) AS n
+                FROM measurements
+                WHERE (?1 IS NULL OR sensor_id = ?1)
+                    AND (?2 IS NULL OR timestamp >= ?2)
+                    AND (?3 IS NULL OR timestamp < ?3)
+             )
+             WHERE n <= ?4
+             ORDER BY sensor_id, timestamp, id",
+        )?;
+        let mut readings_statement = conn.prepare(
+            "SELECT field, value, unit FROM readings WHERE measurement_id = ?1 ORDER BY rowid",
+        )?;
+        let rows = statement.query_map(params![query.sensor_id, since, until, limit], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                StoredMeasurement {
+                    time: row.get(1)?,
+                    sensor_id: row.get(2)?,
+                    sensor_type: row.get(3)?,
+                    fcnt: row.get(4)?,
+                    spreading_factor: row.get(5)?,
+                    rejected: row.get(6)?,
+                    readings: Vec::new(),
+                },
+            ))
+        })?;
+        let mut measurements = Vec::new();
+        for row in rows {
+            let (id, mut measurement) = row?;
+            measurement.readings = readings_statement
+                .query_map([id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                .collect::<rusqlite::Result<_>>()?;
+            measurements.push(measurement);
+        }
+        Ok(measurements)
+    }
+
+    /// Export measurements as CSV (one row per reading), ordered by time.
+    ///
+    /// Returns the number of exported measurements.
+    pub fn export_csv(&self, query: &Query, out: &mut dyn Write) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        let since = Self::parse_time(&conn, query.since.as_deref())?;
+        let until = Self::parse_time(&conn, query.until.as_deref())?;
+        let mut statement = conn.prepare(
+            "SELECT m.id, strftime('%Y-%m-%dT%H:%M:%SZ', m.timestamp, 'unixepoch'), m.sensor_id,
+                m.dev_eui, m.sensor_type, m.fcnt, m.spreading_factor, m.bandwidth, m.airtime_ms,
+                (SELECT COUNT(*) FROM gateways g WHERE g.measurement_id = m.id),
+                (SELECT MAX(rssi) FROM gateways g WHERE g.measurement_id = m.id),
+                (SELECT MAX(snr) FROM gateways g WHERE g.measurement_id = m.id),
+                m.rejected, r.field, r.value, r.raw_value, r.unit, r.depth_m
+             FROM measurements m
+             JOIN readings r ON r.measurement_id = m.id
+             WHERE (?1 IS NULL OR m.sensor_id = ?1)
+                AND (?2 IS NULL OR m.timestamp >= ?2)
+                AND (?3 IS NULL OR m.timestamp < ?3)
+             ORDER BY m.timestamp, m.id, r.rowid",
+        )?;
+        writeln!(out, "{}", CSV_HEADER)?;
+        let mut rows = statement.query(params![query.sensor_id, since, until])?;
+        let mut last_id = None;
+        let mut count = 0;
+        while let Some(row) = rows.next()? {
+            let id: i64 = row.get(0)?;
+            if last_id != Some(id) {
+                last_id = Some(id);
+                count += 1;
+            }
+            let columns = (1..18)
+                .map(|i| {
+                    Ok(match row.get_ref(i)? {
+                        ValueRef::Null => String::new(),
+                        ValueRef::Integer(v) => v.to_string(),
+                        ValueRef::Real(v) => v.to_string(),
+                        value => csv_escape(value.as_str()?),
+                    })
+                })
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            writeln!(out, "{}", columns.join(","))?;
+        }
+        Ok(count)
+    }
+}
+
+impl Output for SqliteStore {
+    fn name(&self) -> &str {
+        "sqlite"
+    }
+
+    /// Store all measurements, including rejected readings (flagged as such).
+    fn accepts(&self, _record: &Record) -> bool {
+        true
+    }
+
+    fn send(&self, record: &Record) -> Result<()> {
+        debug!("Storing measurement in SQLite...");
+        self.insert(record).context("SQLite insert failed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::{Measurement, Position, Quantity, Reading};
+
+    fn store() -> SqliteStore {
+        SqliteStore::open(Path::new(":memory:")).unwrap()
+    }
+
+    fn record(sensor_id: u32, minute: u64, temperature: f32) -> Record {
+        let mut reading = Reading::new(Quantity::Temperature, Position::Water, temperature);
+        reading.raw_value = Some(temperature + 0.5);
+        let mut record = Record::example(
+            sensor_id,
+            Measurement {
+                readings: vec![
+                    reading,
+                    Reading::new(Quantity::BatteryVoltage, Position::Device, 3.5),
+                ],
+            },
+        );
+        // 2023-11-14T22:13:20Z + minutes
+        record.timestamp += Duration::from_secs(minute * 60);
+        record
+    }
+
+    #[test]
+    fn test_migrations() {
+        let store = store();
+        store.insert(&record(1, 0, 16.0)).unwrap();
+        let conn = store.conn.lock().unwrap();
+        let version: usize = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        let (data_rate, frequency): (Option<String>, Option<u64>) = conn
+            .query_row("SELECT data_rate, frequency FROM measurements", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(data_rate.as_deref(), Some("SF7BW125"));
+        assert_eq!(frequency, Some(868100000));
+    }
+
+    #[test]
+    fn test_recent() {
+        let store = store();
+        for minute in 0..5 {
+            store
+                .insert(&record(1, minute, 16.0 + minute as f32))
+                .unwrap();
+        }
+        store.insert(&record(2, 2, 12.0)).unwrap();
+
+        let recent = store.recent(&Query::default(), 2).unwrap();
+        assert_eq!(recent.len(), 3);
+        assert_eq!(
+            recent[0],
+            StoredMeasurement {
+                time: "2023-11-14T22:16:20Z".to_string(),
+                sensor_id: 1,
+                sensor_type: "gfroerli".to_string(),
+                fcnt: Some(7),
+                spreading_factor: Some(7),
+                rejected: false,
+                readings: vec![
+                    ("water_temp".to_string(), 19.0, "°C".to_string()),
+                    ("voltage".to_string(), 3.5, "V".to_string()),
+                ],
+            }
+        );
+        assert_eq!(recent[1].readings[0].1, 20.0);
+        assert_eq!(recent[2].sensor_id, 2);
+
+        let query = Query {
+            sensor_id: Some(1),
+            since: Some("2023-11-14 22:14".to_string()),
+            until: Some("2023-11-14 22:16".to_string()),
+        };
+        let times = store
+            .recent(&query, 10)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.time)
+            .collect::<Vec<_>>();
+        assert_eq!(times, ["2023-11-14T22:14:20Z", "2023-11-14T22:15:20Z"]);
+
+        let query = Query {
+            since: Some("yesterday".to_string()),
+            ..Default::default()
+        };
+        assert!(store.recent(&query, 10).is_err());
+    }
+
+    #[test]
+    fn test_export_csv() {
+        let store = store();
+        store.insert(&record(1, 0, 16.25)).unwrap();
+        store.insert(&record(2, 1, 12.5)).unwrap();
+        let mut csv = Vec::new();
+        let query = Query {
+            sensor_id: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(store.export_csv(&query, &mut csv).unwrap(), 1);
+        let csv = String::from_utf8(csv).unwrap();
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], CSV_HEADER);
+        assert_eq!(
+            lines[1],
+            "2023-11-14T22:13:20Z,1,AABB,gfroerli,7,7,125000,61,2,-95,-3.5,0,water_temp,16.25,16.75,°C,"
+        );
+        assert!(lines[2].contains(",voltage,3.5,,V,"));
+        assert_eq!(lines.len(), 3);
+        assert_eq!(csv_escape("a,\"b\""), "\"a,\"\"b\"\"\"");
+    }
+}
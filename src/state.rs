@@ -0,0 +1,150 @@
+//! Persistent relay state, stored as JSON files in the configured state
+//! directory.
+//!
+//! Changes are not written on every update, but at most once per
+//! [`SAVE_INTERVAL`] and when the relay exits.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Minimum interval between two saves of a state file.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Load state from a JSON file. If the file does not exist, the default
+/// value is returned.
+fn load<T: DeserializeOwned + Default>(path: &Path) -> Result<T> {
+    if !path.exists() {
+        return Ok(T::default());
+    }
+    let contents =
+        fs::read(path).with_context(|| format!("Could not read state file {:?}", path))?;
+    serde_json::from_slice(&contents)
+        .with_context(|| format!("Could not deserialize state file {:?}", path))
+}
+
+/// Save state to a JSON file.
+///
+/// The data is written to a temporary file first, which is then renamed, so
+/// that a crash during writing does not corrupt the state.
+fn save<T: Serialize>(path: &Path, state: &T) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Could not create state directory {:?}", parent))?;
+    }
+    let tmp_path = path.with_extension("json.tmp");
+    let contents = serde_json::to_vec(state).context("Could not serialize state")?;
+    fs::write(&tmp_path, contents)
+        .with_context(|| format!("Could not write state file {:?}", tmp_path))?;
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("Could not rename state file to {:?}", path))
+}
+
+/// Whether a state file is up to date.
+struct SaveStatus {
+    /// Whether the state was changed since it was last saved
+    changed: bool,
+    last_save: Instant,
+}
+
+/// State that is persisted to a JSON file, if a state directory is
+/// configured.
+pub struct StateFile<T> {
+    path: Option<PathBuf>,
+    /// Description of the state for log messages, e.g. "frame counters"
+    description: &'static str,
+    state: Mutex<T>,
+    status: Mutex<SaveStatus>,
+}
+
+impl<T: Serialize + DeserializeOwned + Default> StateFile<T> {
+    /// Load the state from the file `name` in the state directory. Without
+    /// a state directory, the state starts empty and is not persisted.
+    pub fn load(dir: Option<&Path>, name: &str, description: &'static str) -> Result<Self> {
+        let path = dir.map(|dir| dir.join(name));
+        let state = match path {
+            Some(ref path) => load(path)?,
+            None => T::default(),
+        };
+        Ok(Self {
+            path,
+            description,
+            state: Mutex::new(state),
+            status: Mutex::new(SaveStatus {
+                changed: false,
+                last_save: Instant::now(),
+            }),
+        })
+    }
+
+    /// Lock the state.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.state.lock().unwrap()
+    }
+
+    /// Mark the (locked) state as changed. It is saved if the last save was
+    /// more than [`SAVE_INTERVAL`] ago.
+    pub fn changed(&self, state: &T) {
+        let mut status = self.status.lock().unwrap();
+        status.changed = true;
+        if status.last_save.elapsed() >= SAVE_INTERVAL {
+            self.save(state, &mut status);
+        }
+    }
+
+    /// Save the state if it was changed since it was last saved.
+    pub fn flush(&self) {
+        let state = self.lock();
+        let mut status = self.status.lock().unwrap();
+        if status.changed {
+            self.save(&state, &mut status);
+        }
+    }
+
+    fn save(&self, state: &T, status: &mut SaveStatus) {
+        let Some(ref path) = self.path else {
+            return;
+        };
+        // After a failure, the save is retried after the interval
+        status.last_save = Instant::now();
+        match save(path, state) {
+            Ok(()) => status.changed = false,
+            Err(e) => warn!("Could not persist {}: {:#}", self.description, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn test_state_file() {
+        let dir = std::env::temp_dir().join(format!("ttn-relay-state-{}", std::process::id()));
+        let path = dir.join("test.json");
+        let file =
+            StateFile::<HashMap<String, u32>>::load(Some(&dir), "test.json", "test state").unwrap();
+        let mut state = file.lock();
+        state.insert("a".to_string(), 1);
+        file.changed(&state);
+        drop(state);
+
+        // Changes are only saved once per interval or when flushed
+        assert!(!path.exists());
+        file.flush();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), r#"{"a":1}"#);
+        let file =
+            StateFile::<HashMap<String, u32>>::load(Some(&dir), "test.json", "test state").unwrap();
+        assert_eq!(file.lock()["a"], 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
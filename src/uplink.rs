@@ -0,0 +1,373 @@
+//! Supplementary parsing of TTN uplink messages.
+//!
+//! `drogue-ttn` does not cover all fields of the TTN v3 uplink message. The
+//! missing fields are parsed from the raw JSON payload here. Parsing is
+//! lenient: missing or malformed fields are simply left empty.
+
+use std::{collections::BTreeMap, fmt};
+
+use serde::{de::DeserializeOwned, Deserialize, Deserializer};
+
+/// A geographic location.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: Option<f64>,
+}
+
+/// Reception metadata of a single gateway.
+#[derive(Debug, Default, Deserialize)]
+pub struct RxMetadata {
+    /// Reception time according to the gateway (RFC 3339), if the gateway
+    /// has a GPS or NTP synchronized clock
+    pub time: Option<String>,
+    /// Concentrator timestamp in microseconds
+    pub timestamp: Option<u32>,
+    #[serde(default)]
+    pub antenna_index: u32,
+    /// The gateway location (as registered or reported by the gateway)
+    #[serde(default, deserialize_with = "lenient")]
+    pub location: Option<Location>,
+}
+
+/// The data rate (modulation) of an uplink.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub enum DataRate {
+    #[serde(rename = "lora")]
+    Lora {
+        #[serde(default)]
+        spreading_factor: u16,
+        /// Bandwidth in Hz
+        #[serde(default)]
+        bandwidth: u64,
+        /// Coding rate, e.g. "4/5"
+        coding_rate: Option<String>,
+    },
+    #[serde(rename = "fsk")]
+    Fsk {
+        /// Bit rate in bit/s
+        #[serde(default)]
+        bit_rate: u32,
+    },
+    #[serde(rename = "lrfhss")]
+    LrFhss {
+        #[serde(default)]
+        modulation_type: u32,
+        /// Operating channel width in Hz
+        #[serde(default)]
+        operating_channel_width: u32,
+        /// Coding rate, e.g. "2/3"
+        coding_rate: Option<String>,
+    },
+}
+
+impl DataRate {
+    /// The name of the modulation.
+    pub fn modulation(&self) -> &'static str {
+        match self {
+            DataRate::Lora { .. } => "lora",
+            DataRate::Fsk { .. } => "fsk",
+            DataRate::LrFhss { .. } => "lr-fhss",
+        }
+    }
+
+    /// The spreading factor (LoRa only).
+    pub fn spreading_factor(&self) -> Option<u16> {
+        match self {
+            DataRate::Lora {
+                spreading_factor, ..
+            } => Some(*spreading_factor),
+            _ => None,
+        }
+    }
+
+    /// The bandwidth in Hz (LoRa only).
+    pub fn bandwidth(&self) -> Option<u64> {
+        match self {
+            DataRate::Lora { bandwidth, .. } => Some(*bandwidth),
+            _ => None,
+        }
+    }
+
+    /// The coding rate (LoRa and LR-FHSS).
+    pub fn coding_rate(&self) -> Option<&str> {
+        match self {
+            DataRate::Lora { coding_rate, .. } | DataRate::LrFhss { coding_rate, .. } => {
+                coding_rate.as_deref()
+            }
+            DataRate::Fsk { .. } => None,
+        }
+    }
+
+    /// The bit rate in bit/s (FSK only).
+    pub fn bit_rate(&self) -> Option<u32> {
+        match self {
+            DataRate::Fsk { bit_rate } => Some(*bit_rate),
+            _ => None,
+        }
+    }
+}
+
+/// Format the data rate in the common short notation, e.g. "SF7BW125".
+impl fmt::Display for DataRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DataRate::Lora {
+                spreading_factor,
+                bandwidth,
+                ..
+            } => write!(f, "SF{}BW{}", spreading_factor, bandwidth / 1000),
+            DataRate::Fsk { bit_rate } => write!(f, "FSK{}", bit_rate),
+            DataRate::LrFhss {
+                operating_channel_width,
+                ..
+            } => write!(f, "LR-FHSS-OCW{}", operating_channel_width / 1000),
+        }
+    }
+}
+
+/// A number that is encoded either as JSON number or as string (64 bit
+/// integers are encoded as strings by TTN).
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Number {
+    Number(u64),
+    String(String),
+}
+
+fn number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    Ok(match Option::<Number>::deserialize(deserializer)? {
+        Some(Number::Number(n)) => Some(n),
+        Some(Number::String(s)) => s.parse().ok(),
+        None => None,
+    })
+}
+
+/// Deserialize a value, falling back to the default if it is malformed (so
+/// that e.g. an unknown data rate does not affect the other fields).
+fn lenient<'de, D: Deserializer<'de>, T: DeserializeOwned + Default>(
+    deserializer: D,
+) -> Result<T, D::Error> {
+    let value = serde_json::Value::deserialize(deserializer)?;
+    Ok(serde_json::from_value(value).unwrap_or_default())
+}
+
+/// Deserialize a list, replacing malformed elements by the default (so that
+/// the remaining elements keep their position).
+fn lenient_elements<'de, D: Deserializer<'de>, T: DeserializeOwned + Default>(
+    deserializer: D,
+) -> Result<Vec<T>, D::Error> {
+    let value = serde_json::Value::deserialize(deserializer)?;
+    Ok(serde_json::from_value::<Vec<serde_json::Value>>(value)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|element| serde_json::from_value(element).unwrap_or_default())
+        .collect())
+}
+
+/// Transmission settings of an uplink.
+#[derive(Debug, Default, Deserialize)]
+struct TxSettings {
+    #[serde(default, deserialize_with = "lenient")]
+    data_rate: Option<DataRate>,
+    /// Coding rate (in older TTN versions, it is not part of the data rate)
+    coding_rate: Option<String>,
+    #[serde(default, deserialize_with = "number")]
+    frequency: Option<u64>,
+    timestamp: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct UplinkMessage {
+    #[serde(default, deserialize_with = "lenient_elements")]
+    rx_metadata: Vec<RxMetadata>,
+    #[serde(default, deserialize_with = "lenient")]
+    settings: TxSettings,
+    #[serde(default, deserialize_with = "lenient")]
+    locations: BTreeMap<String, Location>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Message {
+    #[serde(default)]
+    uplink_message: UplinkMessage,
+}
+
+/// Supplementary fields of an uplink message.
+#[derive(Debug, Default)]
+pub struct UplinkDetails {
+    /// Reception metadata, in the same order as in the message
+    pub rx_metadata: Vec<RxMetadata>,
+    pub data_rate: Option<DataRate>,
+    /// Frequency in Hz
+    pub frequency: Option<u64>,
+    /// Concentrator timestamp of the first receiving gateway in microseconds
+    pub timestamp: Option<u32>,
+    /// Device locations by source, e.g. "user" (the registered location) or
+    /// "frm-payload" (decoded from the payload by TTN)
+    pub locations: BTreeMap<String, Location>,
+}
+
+impl UplinkDetails {
+    /// Parse the supplementary fields of a raw uplink message.
+    pub fn parse(payload: &[u8]) -> Self {
+        let message = serde_json::from_slice::<Message>(payload).unwrap_or_default();
+        let UplinkMessage {
+            rx_metadata,
+            settings,
+            locations,
+        } = message.uplink_message;
+        let mut data_rate = settings.data_rate;
+        if let Some(DataRate::Lora {
+            ref mut coding_rate,
+            ..
+        }) = data_rate
+        {
+            if coding_rate.is_none() {
+                *coding_rate = settings.coding_rate;
+            }
+        }
+        Self {
+            rx_metadata,
+            data_rate,
+            frequency: settings.frequency,
+            timestamp: settings.timestamp,
+            locations,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let uplink = UplinkDetails::parse(
+            br#"{
+                "end_device_ids": {"dev_eui": "AABB"},
+                "uplink_message": {
+                    "rx_metadata": [
+                        {
+                            "gateway_ids": {"gateway_id": "gw-1", "eui": "B827EBFFFE000001"},
+                            "time": "2023-11-14T22:13:20.123Z",
+                            "timestamp": 1234567,
+                            "rssi": -110,
+                            "antenna_index": 1,
+                            "location": {"latitude": 47.2, "longitude": 8.8, "source": "SOURCE_REGISTRY"}
+                        },
+                        {
+                            "gateway_ids": {"gateway_id": "packetbroker"},
+                            "rssi": -95
+                        }
+                    ],
+                    "settings": {
+                        "data_rate": {"lora": {"bandwidth": 125000, "spreading_factor": 9}},
+                        "coding_rate": "4/5",
+                        "frequency": "868300000",
+                        "timestamp": 1234500
+                    },
+                    "locations": {
+                        "user": {"latitude": 47.2, "longitude": 8.8, "altitude": 410, "source": "SOURCE_REGISTRY"}
+                    }
+                }
+            }"#,
+        );
+        assert_eq!(uplink.rx_metadata.len(), 2);
+        let first = &uplink.rx_metadata[0];
+        assert_eq!(first.time.as_deref(), Some("2023-11-14T22:13:20.123Z"));
+        assert_eq!(first.timestamp, Some(1234567));
+        assert_eq!(first.antenna_index, 1);
+        assert_eq!(
+            first.location,
+            Some(Location {
+                latitude: 47.2,
+                longitude: 8.8,
+                altitude: None
+            })
+        );
+        let second = &uplink.rx_metadata[1];
+        assert_eq!(second.timestamp, None);
+        assert_eq!(second.location, None);
+
+        let data_rate = uplink.data_rate.unwrap();
+        assert_eq!(data_rate.to_string(), "SF9BW125");
+        assert_eq!(data_rate.spreading_factor(), Some(9));
+        assert_eq!(data_rate.coding_rate(), Some("4/5"));
+        assert_eq!(uplink.frequency, Some(868300000));
+        assert_eq!(uplink.timestamp, Some(1234500));
+        assert_eq!(
+            uplink.locations["user"],
+            Location {
+                latitude: 47.2,
+                longitude: 8.8,
+                altitude: Some(410.0)
+            }
+        );
+
+        assert!(UplinkDetails::parse(b"invalid").rx_metadata.is_empty());
+    }
+
+    #[test]
+    fn test_parse_data_rates() {
+        let data_rate = |settings: &str| {
+            UplinkDetails::parse(
+                format!(
+                    r#"{{"uplink_message": {{"rx_metadata": [{{}}], "settings": {}}}}}"#,
+                    settings
+                )
+                .as_bytes(),
+            )
+        };
+
+        let uplink =
+            data_rate(r#"{"data_rate": {"fsk": {"bit_rate": 50000}}, "frequency": 868800000}"#);
+        let fsk = uplink.data_rate.unwrap();
+        assert_eq!(fsk, DataRate::Fsk { bit_rate: 50000 });
+        assert_eq!(fsk.to_string(), "FSK50000");
+        assert_eq!(fsk.spreading_factor(), None);
+        assert_eq!(uplink.frequency, Some(868800000));
+
+        let lr_fhss = data_rate(
+            r#"{"data_rate": {"lrfhss": {"modulation_type": 0, "operating_channel_width": 137000, "coding_rate": "2/3"}}}"#,
+        )
+        .data_rate
+        .unwrap();
+        assert_eq!(lr_fhss.to_string(), "LR-FHSS-OCW137");
+        assert_eq!(lr_fhss.modulation(), "lr-fhss");
+        assert_eq!(lr_fhss.coding_rate(), Some("2/3"));
+
+        // Unknown data rates or malformed settings do not affect the other
+        // fields
+        let uplink = data_rate(r#"{"data_rate": {"unknown": {}}, "frequency": 868100000}"#);
+        assert_eq!(uplink.data_rate, None);
+        assert_eq!(uplink.frequency, Some(868100000));
+        let uplink = data_rate(r#"{"timestamp": "invalid"}"#);
+        assert_eq!(uplink.timestamp, None);
+        assert_eq!(uplink.rx_metadata.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_malformed_rx_metadata() {
+        let uplink = UplinkDetails::parse(
+            br#"{
+                "uplink_message": {
+                    "rx_metadata": [
+                        {"timestamp": "invalid"},
+                        {"timestamp": 1234567, "location": {"latitude": "invalid"}},
+                        {"location": {"latitude": 47.2, "longitude": 8.8}}
+                    ],
+                    "settings": {"frequency": 868100000}
+                }
+            }"#,
+        );
+        // Malformed elements keep their position
+        assert_eq!(uplink.rx_metadata.len(), 3);
+        assert_eq!(uplink.rx_metadata[0].timestamp, None);
+        assert_eq!(uplink.rx_metadata[1].timestamp, Some(1234567));
+        assert_eq!(uplink.rx_metadata[1].location, None);
+        assert!(uplink.rx_metadata[2].location.is_some());
+        assert_eq!(uplink.frequency, Some(868100000));
+    }
+}
@@ -0,0 +1,255 @@
+//! Plausibility filtering of decoded readings.
+//!
+//! Every reading is checked against the validation rules that match it.
+//! Non-finite values (NaN or infinity) are always rejected. Additionally, a
+//! rule can specify:
+//!
+//! - `min` / `max`: The valid value range (inclusive)
+//! - `sentinels`: Values that indicate a sensor error (e.g. 85 °C, the
+//!   power-on value of a DS18B20)
+//! - `max_rate_per_hour`: The maximum rate of change compared to the previous
+//!   accepted reading of the same sensor and field. Since the rate is
+//!   calculated relative to the last accepted reading, a real step change
+//!   will be accepted again once enough time has passed. Readings with the
+//!   same time as the previous reading are not checked.
+
+use std::{collections::HashMap, sync::Mutex, time::Instant};
+
+use crate::{
+    config::ValidationRule,
+    payload::{Measurement, Reading},
+};
+
+/// Tolerance when comparing values to sentinels.
+const SENTINEL_TOLERANCE: f32 = 0.001;
+
+/// A reading that was rejected by the validation.
+#[derive(Debug)]
+pub struct Rejection {
+    pub reading: Reading,
+    pub reason: String,
+}
+
+/// Validates readings and keeps track of previous values for rate-of-change
+/// checks.
+#[derive(Default)]
+pub struct Validator {
+    /// The last accepted value per (DevEUI, field name)
+    previous: Mutex<HashMap<(String, String), (Instant, f32)>>,
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check a single reading against a rule, return the rejection reason if
+    /// the reading is not plausible.
+    fn check(
+        rule: &ValidationRule,
+        reading: &Reading,
+        previous: Option<&(Instant, f32)>,
+        now: Instant,
+    ) -> Option<String> {
+        let value = reading.value;
+        if rule
+            .sentinels
+            .iter()
+            .any(|s| (value - s).abs() < SENTINEL_TOLERANCE)
+        {
+            return Some(format!("{} is a sentinel value", value));
+        }
+        if let Some(min) = rule.min {
+            if value < min {
+                return Some(format!("{} is below the minimum of {}", value, min));
+            }
+        }
+        if let Some(max) = rule.max {
+            if value > max {
+                return Some(format!("{} is above the maximum of {}", value, max));
+            }
+        }
+        if let (Some(max_rate), Some((then, previous_value))) = (rule.max_rate_per_hour, previous) {
+            // Without elapsed time, there is no rate to check
+            let hours = now.saturating_duration_since(*then).as_secs_f32() / 3600.0;
+            if hours > 0.0 {
+                let rate = (value - previous_value).abs() / hours;
+                if rate > max_rate {
+                    return Some(format!(
+                        "change from {} to {} exceeds the maximum rate of {}/h",
+                        previous_value, value, max_rate
+                    ));
+                }
+            }
+        }
+        None
+    }
+
+    /// Validate all readings of a measurement against the rules.
+    ///
+    /// Rejected readings are removed from the measurement and returned.
+    pub fn validate<'a>(
+        &self,
+        dev_eui: &str,
+        rules: impl Iterator<Item = &'a ValidationRule> + Clone,
+        measurement: &mut Measurement,
+        now: Instant,
+    ) -> Vec<Rejection> {
+        let mut previous = self.previous.lock().unwrap();
+        let mut rejections = vec![];
+        let mut accepted = vec![];
+        for reading in measurement.readings.drain(..) {
+            let key = (dev_eui.to_string(), reading.field_name());
+            let reason = if reading.value.is_finite() {
+                rules
+                    .clone()
+                    .filter(|rule| reading.matches(rule.quantity, rule.position, rule.channel))
+                    .find_map(|rule| Self::check(rule, &reading, previous.get(&key), now))
+            } else {
+                Some(format!("{} is not a finite number", reading.value))
+            };
+            match reason {
+                Some(reason) => rejections.push(Rejection { reading, reason }),
+                None => {
+                    previous.insert(key, (now, reading.value));
+                    accepted.push(reading);
+                }
+            }
+        }
+        measurement.readings = accepted;
+        rejections
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::payload::{Position, Quantity};
+
+    fn rule() -> ValidationRule {
+        ValidationRule {
+            quantity: Quantity::Temperature,
+            position: Some(Position::Water),
+            channel: None,
+            min: Some(-2.0),
+            max: Some(40.0),
+            max_rate_per_hour: Some(2.0),
+            sentinels: vec![85.0],
+        }
+    }
+
+    fn measurement(values: &[f32]) -> Measurement {
+        Measurement {
+            readings: values
+                .iter()
+                .enumerate()
+                .map(|(i, v)| {
+                    Reading::new(Quantity::Temperature, Position::Water, *v)
+                        .with_channel(i as u8 + 1)
+                })
+                .collect(),
+        }
+    }
+
+    fn values(measurement: &Measurement) -> Vec<f32> {
+        measurement.readings.iter().map(|r| r.value).collect()
+    }
+
+    #[test]
+    fn test_range_sentinel_and_nan() {
+        let validator = Validator::new();
+        let rules = [rule()];
+        let mut m = measurement(&[20.0, 85.0, -5.0, 41.0, f32::NAN, f32::INFINITY]);
+        let rejections = validator.validate("eui", rules.iter(), &mut m, Instant::now());
+        assert_eq!(values(&m), vec![20.0]);
+        let reasons = rejections
+            .iter()
+            .map(|r| r.reason.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reasons,
+            vec![
+                "85 is a sentinel value",
+                "-5 is below the minimum of -2",
+                "41 is above the maximum of 40",
+                "NaN is not a finite number",
+                "inf is not a finite number",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_non_matching_rule() {
+        let validator = Validator::new();
+        let rules = [ValidationRule {
+            position: Some(Position::Enclosure),
+            ..rule()
+        }];
+        let mut m = measurement(&[85.0]);
+        let rejections = validator.validate("eui", rules.iter(), &mut m, Instant::now());
+        assert!(rejections.is_empty());
+        assert_eq!(values(&m), vec![85.0]);
+    }
+
+    #[test]
+    fn test_rate_of_change() {
+        let validator = Validator::new();
+        let rules = [rule()];
+        let t0 = Instant::now();
+        let half_hour = Duration::from_secs(1800);
+
+        let mut m = measurement(&[15.0]);
+        assert!(validator
+            .validate("eui", rules.iter(), &mut m, t0)
+            .is_empty());
+
+        // 0.5 °C in 30 minutes: Ok
+        let mut m = measurement(&[15.5]);
+        assert!(validator
+            .validate("eui", rules.iter(), &mut m, t0 + half_hour)
+            .is_empty());
+
+        // 3 °C in 30 minutes: Rejected
+        let mut m = measurement(&[18.5]);
+        let rejections = validator.validate("eui", rules.iter(), &mut m, t0 + 2 * half_hour);
+        assert_eq!(rejections.len(), 1);
+        assert!(m.readings.is_empty());
+
+        // Other sensors are independent
+        let mut m = measurement(&[18.5]);
+        assert!(validator
+            .validate("other", rules.iter(), &mut m, t0 + 2 * half_hour)
+            .is_empty());
+
+        // 3 °C in 2 hours (relative to the last accepted value): Ok
+        let mut m = measurement(&[18.5]);
+        assert!(validator
+            .validate("eui", rules.iter(), &mut m, t0 + 5 * half_hour)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_rate_of_change_without_elapsed_time() {
+        let validator = Validator::new();
+        let rules = [rule()];
+        let t0 = Instant::now();
+
+        // Readings with the same time are not rate checked, whether the value
+        // changed or not
+        for value in [15.0, 15.0, 18.5] {
+            let mut m = measurement(&[value]);
+            assert!(validator
+                .validate("eui", rules.iter(), &mut m, t0)
+                .is_empty());
+            assert_eq!(values(&m), vec![value]);
+        }
+
+        // Later readings are checked against the last accepted value
+        let mut m = measurement(&[15.0]);
+        let rejections =
+            validator.validate("eui", rules.iter(), &mut m, t0 + Duration::from_secs(1800));
+        assert_eq!(rejections.len(), 1);
+    }
+}
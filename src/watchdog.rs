@@ -0,0 +1,255 @@
+//! Detection of offline sensors.
+//!
+//! The watchdog keeps track of the time at which every sensor was last seen.
+//! If no uplink was received within the expected interval of a sensor, an
+//! alert is raised (once). When the sensor sends an uplink again, a recovery
+//! alert is raised.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use log::debug;
+
+use crate::{
+    alerts::{Alert, AlertKind, Alerts, Severity},
+    config::Config,
+    metrics::Metrics,
+};
+
+/// Default interval between two checks.
+const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The watchdog state of a single sensor.
+#[derive(Debug)]
+struct SensorState {
+    sensor_id: u32,
+    expected_interval: Duration,
+    last_seen: SystemTime,
+    overdue: bool,
+}
+
+/// Keeps track of the last-seen times of all watched sensors.
+pub struct Watchdog {
+    /// The state per DevEUI
+    sensors: Mutex<HashMap<String, SensorState>>,
+    /// Interval between two checks
+    check_interval: Duration,
+}
+
+/// Format a duration as a human readable string (rounded to minutes).
+pub fn format_duration(duration: Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    if minutes < 120 {
+        format!("{} min", minutes)
+    } else {
+        format!("{} h {} min", minutes / 60, minutes % 60)
+    }
+}
+
+impl Watchdog {
+    /// Create a watchdog for all sensors with an expected interval (either
+    /// configured per sensor or globally).
+    ///
+    /// Sensors are treated as if they were last seen at `now`.
+    pub fn new(config: &Config, now: SystemTime) -> Self {
+        let default_interval = config
+            .watchdog
+            .as_ref()
+            .and_then(|w| w.expected_interval_min);
+        let sensors = config
+            .sensors
+            .iter()
+            .filter_map(|(dev_eui, sensor)| {
+                let interval_min = sensor.expected_interval_min.or(default_interval)?;
+                Some((
+                    dev_eui.clone(),
+                    SensorState {
+                        sensor_id: sensor.sensor_id,
+                        expected_interval: Duration::from_secs(interval_min * 60),
+                        last_seen: now,
+                        overdue: false,
+                    },
+                ))
+            })
+            .collect();
+        let check_interval = config
+            .watchdog
+            .as_ref()
+            .and_then(|w| w.check_interval_s)
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_CHECK_INTERVAL);
+        Self {
+            sensors: Mutex::new(sensors),
+            check_interval,
+        }
+    }
+
+    /// Return whether any sensors are watched.
+    pub fn is_active(&self) -> bool {
+        !self.sensors.lock().unwrap().is_empty()
+    }
+
+    /// Register an uplink of a sensor.
+    ///
+    /// Returns a recovery alert if the sensor was overdue.
+    pub fn seen(&self, dev_eui: &str, now: SystemTime) -> Option<Alert> {
+        let mut sensors = self.sensors.lock().unwrap();
+        let sensor = sensors.get_mut(dev_eui)?;
+        let silence = now.duration_since(sensor.last_seen).unwrap_or_default();
+        sensor.last_seen = now;
+        if !sensor.overdue {
+            return None;
+        }
+        sensor.overdue = false;
+        Some(Alert {
+            kind: AlertKind::SensorRecovered,
+            severity: Severity::Info,
+            sensor_id: sensor.sensor_id,
+            dev_eui: dev_eui.to_string(),
+            message: format!(
+                "Sensor is back online after {} of silence",
+                format_duration(silence)
+            ),
+        })
+    }
+
+    /// Check all sensors, return alerts for sensors that just became
+    /// overdue.
+    pub fn check(&self, now: SystemTime) -> Vec<Alert> {
+        let mut sensors = self.sensors.lock().unwrap();
+        let mut alerts = vec![];
+        for (dev_eui, sensor) in sensors.iter_mut() {
+            let silence = now.duration_since(sensor.last_seen).unwrap_or_default();
+            if sensor.overdue || silence <= sensor.expected_interval {
+                continue;
+            }
+            sensor.overdue = true;
+            alerts.push(Alert {
+                kind: AlertKind::SensorOffline,
+                severity: Severity::Warning,
+                sensor_id: sensor.sensor_id,
+                dev_eui: dev_eui.clone(),
+                message: format!(
+                    "No uplink received for {} (expected every {})",
+                    format_duration(silence),
+                    format_duration(sensor.expected_interval)
+                ),
+            });
+        }
+        alerts
+    }
+
+    /// Update the overdue metrics of all sensors.
+    fn update_metrics(&self, metrics: &Metrics) {
+        let sensors = self.sensors.lock().unwrap();
+        for sensor in sensors.values() {
+            metrics.set(
+                "ttn_relay_sensor_overdue",
+                &[("sensor_id", &sensor.sensor_id.to_string())],
+                if sensor.overdue { 1.0 } else { 0.0 },
+            );
+        }
+    }
+
+    /// Periodically check all sensors in a background thread.
+    pub fn spawn(self: Arc<Self>, alerts: Arc<Alerts>, metrics: Arc<Metrics>) {
+        thread::spawn(move || loop {
+            thread::sleep(self.check_interval);
+            debug!("Checking for overdue sensors");
+            for alert in self.check(SystemTime::now()) {
+                alerts.send(&alert);
+            }
+            self.update_metrics(&metrics);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            [ttn]
+            host = "localhost"
+            user = "user"
+            pass = "pass"
+
+            [api]
+            base_url = "http://localhost"
+            api_token = "token"
+
+            [watchdog]
+            expected_interval_min = 60
+
+            [sensors.AAAA]
+            sensor_type = "gfroerli"
+            sensor_id = 1
+
+            [sensors.BBBB]
+            sensor_type = "gfroerli"
+            sensor_id = 2
+            expected_interval_min = 10
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn kinds(alerts: &[Alert]) -> Vec<(u32, AlertKind)> {
+        let mut kinds = alerts
+            .iter()
+            .map(|a| (a.sensor_id, a.kind.clone()))
+            .collect::<Vec<_>>();
+        kinds.sort_by_key(|(id, _)| *id);
+        kinds
+    }
+
+    #[test]
+    fn test_overdue_and_recovery() {
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let min = |m: u64| t0 + Duration::from_secs(m * 60);
+        let watchdog = Watchdog::new(&config(), t0);
+        assert!(watchdog.is_active());
+
+        assert!(watchdog.check(min(10)).is_empty());
+        assert_eq!(
+            kinds(&watchdog.check(min(11))),
+            vec![(2, AlertKind::SensorOffline)]
+        );
+        // Only alert once
+        assert!(watchdog.check(min(12)).is_empty());
+
+        // Sensor 1 is kept alive
+        assert!(watchdog.seen("AAAA", min(50)).is_none());
+        assert!(watchdog.check(min(70)).is_empty());
+
+        // Sensor 2 recovers
+        let alert = watchdog.seen("BBBB", min(80)).unwrap();
+        assert_eq!(alert.kind, AlertKind::SensorRecovered);
+        assert_eq!(
+            alert.message,
+            "Sensor is back online after 80 min of silence"
+        );
+        assert!(watchdog.check(min(85)).is_empty());
+
+        // Unknown sensors are ignored
+        assert!(watchdog.seen("CCCC", min(85)).is_none());
+
+        assert_eq!(
+            kinds(&watchdog.check(min(111))),
+            vec![(1, AlertKind::SensorOffline), (2, AlertKind::SensorOffline)]
+        );
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(59)), "0 min");
+        assert_eq!(format_duration(Duration::from_secs(119 * 60)), "119 min");
+        assert_eq!(format_duration(Duration::from_secs(150 * 60)), "2 h 30 min");
+    }
+}
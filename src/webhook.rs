@@ -0,0 +1,360 @@
+//! Forwarding of measurements to generic HTTP webhooks.
+//!
+//! The request body is rendered from a template, in which placeholders like
+//! `{sensor_id}` or `{water_temp}` are replaced by the corresponding values
+//! of the measurement. Unknown placeholders are replaced by `null`. Without a
+//! template, all values are sent as JSON object.
+//!
+//! If a secret is configured, the body is signed using HMAC-SHA256 and the
+//! hex encoded signature is sent in a header (`X-Signature: sha256=<hex>`).
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    thread,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use hmac::{Hmac, Mac};
+use log::{debug, info, warn};
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::{
+    config,
+    output::{Output, Record},
+};
+
+/// Default number of retries.
+const DEFAULT_RETRIES: u32 = 2;
+
+/// Default delay between two attempts.
+const DEFAULT_RETRY_DELAY_MS: u64 = 1000;
+
+/// Default name of the signature header.
+const DEFAULT_SIGNATURE_HEADER: &str = "X-Signature";
+
+/// The values that can be used in a body template.
+pub type TemplateValues = BTreeMap<String, Value>;
+
+/// Collect the values of a record that can be used in body templates.
+fn template_values(record: &Record) -> TemplateValues {
+    let meta = &record.meta;
+    let mut values = TemplateValues::new();
+    let mut insert = |name: &str, value: Value| {
+        values.insert(name.to_string(), value);
+    };
+    insert("sensor_id", record.sensor.sensor_id.into());
+    insert("dev_eui", record.dev_eui.as_str().into());
+    insert("sensor_type", record.sensor.sensor_type.as_str().into());
+    insert(
+        "timestamp",
+        record
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            .into(),
+    );
+    if let Some(ref frames) = meta.frames {
+        insert("fcnt", frames.fcnt.into());
+    }
+    insert("airtime_ms", meta.airtime_ms.into());
+    if let Some(ref data_rate) = meta.data_rate {
+        insert("data_rate", data_rate.to_string().into());
+        insert("modulation", data_rate.modulation().into());
+        if let Some(coding_rate) = data_rate.coding_rate() {
+            insert("coding_rate", coding_rate.into());
+        }
+        if let Some(bit_rate) = data_rate.bit_rate() {
+            insert("bit_rate", bit_rate.into());
+        }
+    }
+    if let Some(sf) = meta.spreading_factor() {
+        insert("sf", sf.into());
+    }
+    if let Some(bw) = meta.bandwidth() {
+        insert("bw", bw.into());
+    }
+    if let Some(frequency) = meta.frequency {
+        insert("frequency", frequency.into());
+    }
+    if let Some(timestamp) = meta.timestamp {
+        insert("concentrator_timestamp", timestamp.into());
+    }
+    insert(
+        "receiving_gateway_count",
+        meta.receiving_gateways.len().into(),
+    );
+    if let Some(gw) = meta.max_rssi_gateway() {
+        insert("max_rssi", gw.rssi.into());
+    }
+    if let Some((_, snr)) = meta.max_snr_gateway() {
+        insert("max_snr", snr.into());
+    }
+    for reading in &record.measurement.readings {
+        insert(&reading.field_name(), reading.json_value());
+    }
+    values
+}
+
+/// Render a body template.
+fn render(template: &str, values: &TemplateValues) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let name_len = rest[1..]
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len() - 1);
+        if name_len > 0 && rest[1 + name_len..].starts_with('}') {
+            let name = &rest[1..1 + name_len];
+            match values.get(name) {
+                Some(Value::String(s)) => output.push_str(s),
+                Some(value) => output.push_str(&value.to_string()),
+                None => output.push_str("null"),
+            }
+            rest = &rest[name_len + 2..];
+        } else {
+            // Not a placeholder (e.g. a JSON object)
+            output.push('{');
+            rest = &rest[1..];
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Calculate the hex encoded HMAC-SHA256 signature of a body.
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .fold(String::new(), |mut hex, b| {
+            write!(hex, "{:02x}", b).unwrap();
+            hex
+        })
+}
+
+/// A webhook to which measurements are forwarded.
+pub struct Webhook {
+    config: config::Webhook,
+    /// The output name ("webhook:<name>")
+    output_name: String,
+    http_client: ureq::Agent,
+}
+
+impl Webhook {
+    pub fn new(config: &config::Webhook, http_client: &ureq::Agent) -> Self {
+        Self {
+            config: config.clone(),
+            output_name: format!("webhook:{}", config.name),
+            http_client: http_client.clone(),
+        }
+    }
+
+    /// Send a single request.
+    fn request(&self, body: &str, signature: Option<&str>) -> Result<(), Box<ureq::Error>> {
+        let method = self.config.method.as_deref().unwrap_or("POST");
+        let mut request = self
+            .http_client
+            .request(method, &self.config.url)
+            .set("content-type", "application/json");
+        for (name, value) in &self.config.headers {
+            request = request.set(name, value);
+        }
+        if let Some(signature) = signature {
+            let header = self
+                .config
+                .signature_header
+                .as_deref()
+                .unwrap_or(DEFAULT_SIGNATURE_HEADER);
+            request = request.set(header, &format!("sha256={}", signature));
+        }
+        request.send_string(body).map(|_| ()).map_err(Box::new)
+    }
+
+    /// Send the values of a measurement, retrying on connection errors and
+    /// server errors.
+    fn post(&self, values: &TemplateValues) -> Result<()> {
+        let body = match self.config.body {
+            Some(ref template) => render(template, values),
+            None => serde_json::to_string(values).context("Could not serialize body")?,
+        };
+        let signature = self.config.secret.as_deref().map(|s| sign(s, &body));
+        let retries = self.config.retries.unwrap_or(DEFAULT_RETRIES);
+        let delay =
+            Duration::from_millis(self.config.retry_delay_ms.unwrap_or(DEFAULT_RETRY_DELAY_MS));
+        info!("Sending measurement to webhook {}...", self.config.name);
+        let mut attempt = 0;
+        loop {
+            let error = match self.request(&body, signature.as_deref()) {
+                Ok(()) => {
+                    debug!("Webhook {} request succeeded", self.config.name);
+                    return Ok(());
+                }
+                Err(e) => match *e {
+                    ureq::Error::Status(status, response) if status < 500 => bail!(
+                        "Webhook {} request failed: HTTP {} ({})",
+                        self.config.name,
+                        status,
+                        response.status_text()
+                    ),
+                    e => e,
+                },
+            };
+            if attempt >= retries {
+                return Err(error)
+                    .with_context(|| format!("Webhook {} request failed", self.config.name));
+            }
+            attempt += 1;
+            warn!(
+                "Webhook {} request failed ({}), retrying ({}/{})",
+                self.config.name, error, attempt, retries
+            );
+            thread::sleep(delay);
+        }
+    }
+}
+
+impl Output for Webhook {
+    fn name(&self) -> &str {
+        &self.output_name
+    }
+
+    /// Accept measurements of the configured sensors (default: all sensors).
+    fn accepts(&self, record: &Record) -> bool {
+        !record.rejected
+            && (self.config.sensors.is_empty()
+                || self.config.sensors.contains(&record.sensor.sensor_id))
+    }
+
+    fn send(&self, record: &Record) -> Result<()> {
+        self.post(&template_values(record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::{mock_http_server, TIMEOUT};
+
+    fn values() -> TemplateValues {
+        BTreeMap::from([
+            ("sensor_id".to_string(), json!(42)),
+            ("dev_eui".to_string(), json!("AABB")),
+            ("water_temp".to_string(), json!(16.5)),
+        ])
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render(
+                r#"{"id": {sensor_id}, "eui": "{dev_eui}", "t": {water_temp}, "h": {humi}}"#,
+                &values()
+            ),
+            r#"{"id": 42, "eui": "AABB", "t": 16.5, "h": null}"#
+        );
+        assert_eq!(render("{ {} {sensor_id", &values()), "{ {} {sensor_id");
+        assert_eq!(render("temp={water_temp}&x=1", &values()), "temp=16.5&x=1");
+    }
+
+    #[test]
+    fn test_sign() {
+        assert_eq!(
+            sign("key", "The quick brown fox jumps over the lazy dog"),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    fn webhook(config: &str) -> Webhook {
+        Webhook::new(&toml::from_str(config).unwrap(), &ureq::agent())
+    }
+
+    #[test]
+    fn test_send_with_retry_and_signature() {
+        let (url, rx) = mock_http_server(&[503, 200]);
+        let webhook = webhook(&format!(
+            "name = \"test\"\n\
+             url = \"{}\"\n\
+             method = \"PUT\"\n\
+             headers = {{ Authorization = \"Bearer token\" }}\n\
+             body = '{{\"temp\": {{water_temp}}}}'\n\
+             secret = \"key\"\n\
+             retry_delay_ms = 1",
+            url
+        ));
+        webhook.post(&values()).unwrap();
+        let first = rx.recv_timeout(TIMEOUT).unwrap();
+        let second = rx.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(first, second);
+        assert!(first.starts_with("PUT /hook "));
+        let lowercase = first.to_lowercase();
+        assert!(lowercase.contains("authorization: bearer token\r\n"));
+        assert!(lowercase.contains(&format!(
+            "x-signature: sha256={}\r\n",
+            sign("key", r#"{"temp": 16.5}"#)
+        )));
+        assert!(first.ends_with("\r\n\r\n{\"temp\": 16.5}"));
+    }
+
+    #[test]
+    fn test_send_client_error() {
+        let (url, rx) = mock_http_server(&[400]);
+        let webhook = webhook(&format!("name = \"test\"\nurl = \"{}\"", url));
+        let err = webhook.post(&values()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Webhook test request failed: HTTP 400 (Status)"
+        );
+        // The default body contains all values
+        assert!(rx
+            .recv_timeout(TIMEOUT)
+            .unwrap()
+            .ends_with(r#"{"dev_eui":"AABB","sensor_id":42,"water_temp":16.5}"#));
+    }
+
+    #[test]
+    fn test_accepts() {
+        let record = |sensor_id| Record::example(sensor_id, Default::default());
+        let all = webhook("name = \"a\"\nurl = \"http://localhost\"");
+        assert_eq!(all.name(), "webhook:a");
+        assert!(all.accepts(&record(1)));
+        let webhook = webhook("name = \"a\"\nurl = \"http://localhost\"\nsensors = [2, 3]");
+        assert!(!webhook.accepts(&record(1)));
+        assert!(webhook.accepts(&record(3)));
+        let mut rejected = record(3);
+        rejected.rejected = true;
+        assert!(!webhook.accepts(&rejected));
+    }
+
+    #[test]
+    fn test_template_values() {
+        let values = template_values(&Record::example(
+            42,
+            crate::payload::Measurement {
+                readings: vec![crate::payload::Reading::new(
+                    crate::payload::Quantity::Temperature,
+                    crate::payload::Position::Water,
+                    13.14,
+                )],
+            },
+        ));
+        assert_eq!(values["sensor_id"], json!(42));
+        assert_eq!(values["timestamp"], json!(1_700_000_000));
+        assert_eq!(values["max_rssi"], json!(-95.0));
+        assert_eq!(values["max_snr"], json!(-3.5));
+        assert_eq!(values["data_rate"], json!("SF7BW125"));
+        assert_eq!(values["frequency"], json!(868100000));
+        assert_eq!(values["concentrator_timestamp"], json!(1234500));
+        assert_eq!(values["water_temp"], json!(13.14));
+    }
+}